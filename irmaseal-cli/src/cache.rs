@@ -0,0 +1,200 @@
+//! Cache of the public parameters of PKGs, pinning the public key of each PKG on first use.
+//!
+//! The cache lives in `$XDG_CACHE_HOME/irmaseal`, or `~/.cache/irmaseal`, with a file per PKG URL.
+
+use irmaseal_core::api::Parameters;
+use irmaseal_core::stream::FORMAT_VERSION;
+use irmaseal_core::{KeyId, PublicKey};
+use serde::{Deserialize, Serialize};
+
+use std::path::{Path, PathBuf};
+
+/// The timeframe duration assumed when encrypting with a public key for which no parameters are known,
+/// the default of the PKG.
+pub const DEFAULT_MAX_AGE: u64 = 300;
+
+/// The cached parameters of a single PKG.
+#[derive(Serialize, Deserialize)]
+pub struct Entry {
+    /// The key ID of the public key trusted for this PKG, to show to the user.
+    pub pinned: KeyId,
+    /// The fingerprint of the public key trusted for this PKG, absent in entries of earlier versions.
+    #[serde(default)]
+    pub fingerprint: Option<[u8; 32]>,
+    pub parameters: Parameters,
+}
+
+impl Entry {
+    /// Whether the public key is the pinned one, compared by its full fingerprint if known.
+    pub fn is_pinned(&self, public_key: &PublicKey) -> bool {
+        match self.fingerprint {
+            Some(fingerprint) => fingerprint == public_key.fingerprint(),
+            None => self.pinned == public_key.key_id(),
+        }
+    }
+}
+
+/// A public key of a PKG that differs from the pinned one.
+pub struct PinMismatch {
+    pub pinned: KeyId,
+    /// Whether the PKG lists the pinned key as retired, which may indicate a key rotation.
+    pub retired: bool,
+}
+
+pub struct Cache {
+    dir: Option<PathBuf>,
+}
+
+impl Cache {
+    pub fn new() -> Cache {
+        let dir = std::env::var_os("XDG_CACHE_HOME")
+            .map(PathBuf::from)
+            .or_else(|| std::env::var_os("HOME").map(|home| Path::new(&home).join(".cache")))
+            .map(|dir| dir.join("irmaseal"));
+
+        Cache { dir }
+    }
+
+    /// The file for a PKG URL, of which all characters that are not safe in a filename are replaced.
+    fn path(&self, server: &str) -> Option<PathBuf> {
+        let name: String = server
+            .chars()
+            .map(|c| {
+                if c.is_ascii_alphanumeric() || c == '.' || c == '-' {
+                    c
+                } else {
+                    '_'
+                }
+            })
+            .collect();
+
+        self.dir
+            .as_ref()
+            .map(|dir| dir.join(format!("{}.json", name)))
+    }
+
+    pub fn get(&self, server: &str) -> Option<Entry> {
+        let bytes = std::fs::read(self.path(server)?).ok()?;
+        serde_json::from_slice(&bytes).ok()
+    }
+
+    /// Store the entry, ignoring failures as the cache is only an optimisation.
+    pub fn put(&self, server: &str, entry: &Entry) {
+        if let (Some(dir), Some(path)) = (&self.dir, self.path(server)) {
+            let _ = std::fs::create_dir_all(dir);
+            let _ = std::fs::write(path, serde_json::to_vec(entry).unwrap());
+        }
+    }
+
+    /// Check freshly fetched parameters against the public key pinned for the PKG, and cache them.
+    ///
+    /// The public key is pinned on first use. If it differs from the pinned key, the parameters are
+    /// rejected, unless the new key is explicitly trusted.
+    pub fn pin(
+        &self,
+        server: &str,
+        parameters: Parameters,
+        trust: bool,
+    ) -> Result<Parameters, PinMismatch> {
+        if let Some(entry) = self.get(server) {
+            if !entry.is_pinned(&parameters.public_key) && !trust {
+                return Err(PinMismatch {
+                    pinned: entry.pinned,
+                    retired: parameters.generation(&entry.pinned).is_some(),
+                });
+            }
+        }
+
+        let entry = Entry {
+            pinned: parameters.public_key.key_id(),
+            fingerprint: Some(parameters.public_key.fingerprint()),
+            parameters,
+        };
+        self.put(server, &entry);
+
+        Ok(entry.parameters)
+    }
+}
+
+/// Read a public key, either as written by `irmaseal-pkg generate` or in base64 form.
+pub fn read_public_key(path: impl AsRef<Path>) -> Option<PublicKey> {
+    const LENGTH: usize = 25056;
+
+    let bytes = std::fs::read(path).ok()?;
+    if bytes.len() == LENGTH {
+        let mut b = [0u8; LENGTH];
+        b.copy_from_slice(&bytes);
+
+        let pk = ibe::kiltz_vahlis_one::PublicKey::from_bytes(&b);
+        return if bool::from(pk.is_some()) {
+            Some(pk.unwrap().into())
+        } else {
+            None
+        };
+    }
+
+    PublicKey::from_base64(std::str::from_utf8(&bytes).ok()?.trim())
+}
+
+/// The parameters to encrypt offline with the given public key, using the cached parameters of the
+/// PKG for everything but the public key if known.
+///
+/// The public key is checked against the key pinned for the PKG, if any, unless explicitly trusted.
+pub fn offline_parameters(
+    cached: Option<Entry>,
+    public_key: PublicKey,
+    trust: bool,
+) -> Result<Parameters, PinMismatch> {
+    Ok(match cached {
+        Some(entry) if !entry.is_pinned(&public_key) && !trust => {
+            return Err(PinMismatch {
+                pinned: entry.pinned,
+                retired: false,
+            })
+        }
+        Some(entry) => Parameters {
+            public_key,
+            ..entry.parameters
+        },
+        None => Parameters {
+            format_version: FORMAT_VERSION,
+            max_age: DEFAULT_MAX_AGE,
+            max_retention: None,
+            max_skew: 0,
+            public_key,
+            keys: Default::default(),
+        },
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn offline_pin() {
+        let (pinned, _) = ibe::kiltz_vahlis_one::setup(&mut rand::thread_rng());
+        let (other, _) = ibe::kiltz_vahlis_one::setup(&mut rand::thread_rng());
+
+        let entry = || {
+            let parameters = offline_parameters(None, pinned.into(), false).ok().unwrap();
+            Entry {
+                pinned: parameters.public_key.key_id(),
+                fingerprint: Some(parameters.public_key.fingerprint()),
+                parameters,
+            }
+        };
+
+        assert!(offline_parameters(Some(entry()), pinned.into(), false).is_ok());
+        assert!(offline_parameters(Some(entry()), other.into(), false).is_err());
+        assert!(offline_parameters(Some(entry()), other.into(), true).is_ok());
+
+        // Entries of earlier versions only know the key ID.
+        let legacy = Entry {
+            fingerprint: None,
+            ..entry()
+        };
+        assert!(legacy.is_pinned(&pinned.into()));
+        assert!(!legacy.is_pinned(&other.into()));
+    }
+}
@@ -17,14 +17,14 @@ pub struct OwnedKeyChallenge {
 }
 
 impl<'a> Client<'a> {
-    pub fn new(baseurl: &'a str) -> Result<Client, reqwest::Error> {
+    pub fn new(baseurl: &'a str) -> Result<Client<'a>, reqwest::Error> {
         let client = ClientBuilder::new().build().unwrap();
 
         Ok(Client { baseurl, client })
     }
 
     fn create_url(&self, u: &str) -> Url {
-        Url::parse(&self.baseurl).unwrap().join(u).unwrap()
+        Url::parse(self.baseurl).unwrap().join(u).unwrap()
     }
 
     pub async fn parameters(&self) -> Result<Parameters, ClientError> {
@@ -48,13 +48,25 @@ impl<'a> Client<'a> {
             .await
     }
 
-    pub async fn result(&self, token: &str, timestamp: u64) -> Result<KeyResponse, ClientError> {
+    /// Send the code the user received to verify the request, yielding the resulting status.
+    pub async fn verify(
+        &self,
+        token: &str,
+        kv: &KeyVerification,
+    ) -> Result<KeyResponse, ClientError> {
         self.client
-            .get(
-                self.create_url("v1/request/")
-                    .join(&format!("{}/{}", token, timestamp))
-                    .unwrap(),
-            )
+            .post(self.create_url("v1/request/").join(token).unwrap())
+            .json(kv)
+            .send()
+            .await?
+            .error_for_status()?
+            .json::<KeyResponse>()
+            .await
+    }
+
+    pub async fn result(&self, token: &str) -> Result<KeyResponse, ClientError> {
+        self.client
+            .get(self.create_url("v1/request/").join(token).unwrap())
             .send()
             .await?
             .error_for_status()?
@@ -1,8 +1,19 @@
+use arrayvec::ArrayString;
 use clap::ArgMatches;
 use irmaseal_core::api::*;
+use irmaseal_core::armor::{is_armored, ArmorReader};
+use irmaseal_core::io::IoReader;
 use irmaseal_core::stream::OpenerSealed;
+use irmaseal_core::threshold;
+use irmaseal_core::{
+    Attribute, DeliverySecretKey, Identity, Metadata, Readable, UserSecretKey, METADATA_FILENAME,
+    METADATA_MODIFIED,
+};
 
-use std::time::Duration;
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
 use tokio::time::delay_for;
 
 use crate::client::{Client, ClientError, OwnedKeyChallenge};
@@ -18,13 +29,82 @@ fn print_qr(s: &str) {
     println!("\n\n{}", scode);
 }
 
+/// The attributes given on the commandline.
+fn given_attributes(m: &ArgMatches) -> Vec<Attribute> {
+    let email = m
+        .value_of("email")
+        .map(|email| Attribute::new("pbdf.pbdf.email.email", Some(email)).unwrap());
+    let bsn = m
+        .value_of("bsn")
+        .map(|bsn| Attribute::new("pbdf.gemeente.personalData.bsn", Some(bsn)).unwrap());
+
+    email.into_iter().chain(bsn).collect()
+}
+
+/// Select the recipient to decrypt as, either the one of which the policy matches the given attributes,
+/// or the only recipient if there is just one.
+fn select_recipient(given: &[Attribute], recipients: &[Identity]) -> Option<usize> {
+    if given.is_empty() {
+        return if recipients.len() == 1 { Some(0) } else { None };
+    }
+
+    recipients.iter().position(|i| {
+        given.iter().all(|g| {
+            i.attributes
+                .iter()
+                .any(|a| a.atype == g.atype && a.value.map_or(true, |v| Some(v) == g.value))
+        })
+    })
+}
+
+/// Whether a filename from the metadata is a plain, visible filename, such that the file can neither
+/// escape the directory nor pose as a configuration file such as `.bashrc`.
+fn is_plain_filename(name: &str) -> bool {
+    !name.starts_with('.') && Path::new(name).file_name() == Some(name.as_ref())
+}
+
+/// Determine the candidates for the output file, either given on the commandline, or the original
+/// filename next to the input file, followed by the input file without its `.irma` (or `.irma.asc`)
+/// extension.
+fn output_paths(m: &ArgMatches, input: &str, metadata: &Metadata) -> Vec<PathBuf> {
+    if let Some(output) = m.value_of("OUTPUT") {
+        return vec![PathBuf::from(output)];
+    }
+
+    // Armored input is named after the unarmored file, with an additional `.asc` extension.
+    let mut input = PathBuf::from(input);
+    if input.extension() == Some("asc".as_ref()) {
+        input.set_extension("");
+    }
+
+    let original = metadata
+        .get_str(METADATA_FILENAME)
+        .filter(|name| is_plain_filename(name))
+        .map(|name| input.with_file_name(name));
+    let stripped =
+        Some(input.with_extension("")).filter(|_| input.extension() == Some("irma".as_ref()));
+
+    original.into_iter().chain(stripped).collect()
+}
+
+/// Create the first of the candidates that does not exist yet, such that no file is ever overwritten.
+fn create_output(paths: &[PathBuf]) -> Option<(&Path, File)> {
+    paths.iter().find_map(|path| {
+        OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(path)
+            .ok()
+            .map(|f| (path.as_path(), f))
+    })
+}
+
 async fn wait_on_session(
     client: Client<'_>,
     sp: &OwnedKeyChallenge,
-    timestamp: u64,
 ) -> Result<Option<KeyResponse>, ClientError> {
     for _ in 0..120 {
-        let r: KeyResponse = client.result(&sp.token, timestamp).await?;
+        let r: KeyResponse = client.result(&sp.token).await?;
 
         if r.status != KeyStatus::DoneValid {
             delay_for(Duration::new(0, 500_000_000)).await;
@@ -36,43 +116,267 @@ async fn wait_on_session(
     Ok(None)
 }
 
-pub async fn exec(m: &ArgMatches<'_>) {
-    let input = m.value_of("INPUT").unwrap();
-    let output = m.value_of("OUTPUT").unwrap();
-    let server = m.value_of("server").unwrap();
+/// Read a private key as printed by `irmaseal-pkg extract`.
+fn read_usk(path: &str) -> Option<UserSecretKey> {
+    let s = std::fs::read_to_string(path).ok()?;
+    // The key deserializes from a borrowed string only, thus not from a `serde_json::Value`.
+    serde_json::from_str(&serde_json::to_string(s.trim()).ok()?).ok()
+}
 
-    eprintln!("Opening {}", input);
+/// Request the private key of the recipient from the PKG, disclosing the identity in an IRMA session,
+/// or verifying the e-mail address of the identity by a code sent to it.
+///
+/// Yields the key together with the index of the share of the PKG, if it is a node of a threshold PKG.
+async fn request_key<R: Readable>(
+    server: &str,
+    o: &OpenerSealed<R>,
+    recipient: usize,
+    identity: &Identity,
+    method: AuthMethod,
+) -> Option<(UserSecretKey, Option<u8>)> {
+    let client = Client::new(server).unwrap();
 
-    let r = crate::util::FileReader::new(std::fs::File::open(input).unwrap());
+    let parameters = client.parameters().await.unwrap();
+    let timeframe = o.timeframe(recipient, &parameters).unwrap();
+    eprintln!(
+        "Encrypted for the timeframe from {} until {} (UNIX time)",
+        timeframe.start, timeframe.end
+    );
 
-    let (identity, o) = OpenerSealed::new(r).unwrap();
-    let timestamp = identity.timestamp;
+    // Keys must be issued under the master key the file was sealed with, which may have been retired since.
+    let key_id = o.key_ids()[recipient];
+    match parameters.generation(&key_id) {
+        Some(g) => {
+            if let Some(until) = g.valid_until {
+                eprintln!(
+                    "Sealed under the retired key {}, which was used until {} (UNIX time)",
+                    key_id, until
+                );
+            }
+        }
+        None if !parameters.keys.is_empty() => {
+            eprintln!(
+                "Sealed under the key {}, which is not served by {}",
+                key_id, server
+            );
+            return None;
+        }
+        None => {}
+    }
 
-    let client = Client::new(server).unwrap();
+    eprintln!("Requesting private key for {:#?}", identity.attributes);
 
-    eprintln!("Requesting private key for {:#?}", identity.attribute);
+    // The key is wrapped to this secret, such that it is of no use to anyone observing the session token.
+    let delivery = DeliverySecretKey::new(&mut rand::thread_rng());
 
     let sp: OwnedKeyChallenge = client
         .request(&KeyRequest {
-            attribute: identity.attribute,
+            identity: identity.clone(),
+            method,
+            key_id: Some(key_id),
+            delivery_key: Some(delivery.public_key()),
         })
         .await
         .unwrap();
 
-    eprintln!("Please scan the following QR-code with IRMA:");
+    let response = match method {
+        AuthMethod::Irma => {
+            eprintln!("Please scan the following QR-code with IRMA:");
+
+            print_qr(&sp.qr);
+
+            match wait_on_session(client, &sp).await.unwrap() {
+                Some(r) => {
+                    eprintln!("Disclosure successful");
+                    r
+                }
+                None => {
+                    eprintln!("Did not scan the QR code and disclose in time");
+                    return None;
+                }
+            }
+        }
+        AuthMethod::Email => {
+            eprintln!("Please enter the code sent to the e-mail address:");
+
+            let mut code = String::new();
+            std::io::stdin().read_line(&mut code).unwrap();
+            let code = ArrayString::from(code.trim()).ok()?;
+
+            let r = client
+                .verify(&sp.token, &KeyVerification { code })
+                .await
+                .unwrap();
+            if r.status != KeyStatus::DoneValid {
+                eprintln!("The code is wrong");
+                return None;
+            }
+            r
+        }
+    };
+
+    let usk = match response.wrapped_key {
+        Some(wrapped) => delivery.unwrap_key(&wrapped).unwrap(),
+        None => response.key?,
+    };
+
+    Some((usk, response.share))
+}
+
+/// The key of the recipient from the keys issued by the given PKGs, which are combined if issued by the
+/// nodes of a threshold PKG.
+fn combine_keys(keys: Vec<(UserSecretKey, Option<u8>)>) -> Option<UserSecretKey> {
+    let mut partials = Vec::new();
+    for (usk, share) in keys {
+        match share {
+            Some(index) => partials.push((index, usk)),
+            None => return Some(usk),
+        }
+    }
+
+    threshold::combine(&partials).ok()
+}
+
+pub async fn exec(m: &ArgMatches<'_>) {
+    let input = m.value_of("INPUT").unwrap();
+    let servers: Vec<&str> = m.values_of("server").unwrap().collect();
+
+    eprintln!("Opening {}", input);
+
+    let mut f = BufReader::new(File::open(input).unwrap());
+    let armored = is_armored(f.fill_buf().unwrap());
 
-    print_qr(&sp.qr);
+    // Both readers are only declared, such that either can be passed on.
+    let mut plain;
+    let mut armor;
+    let r: &mut dyn Readable = if armored {
+        eprintln!("Detected ASCII-armored input");
+        armor = ArmorReader::new(IoReader::new(f));
+        &mut armor
+    } else {
+        plain = IoReader::new(f);
+        &mut plain
+    };
 
-    if let Some(r) = wait_on_session(client, &sp, timestamp).await.unwrap() {
-        eprintln!("Disclosure successful, decrypting {} to {}", input, output);
+    let (recipients, o) = OpenerSealed::new(r).unwrap();
 
-        let mut o = o.unseal(&r.key.unwrap()).unwrap();
+    let given = given_attributes(m);
+    let recipient = match select_recipient(&given, &recipients) {
+        Some(recipient) => recipient,
+        None => {
+            eprintln!(
+                "Could not determine which recipient to decrypt as, choose one of {:#?}",
+                recipients
+            );
+            std::process::exit(1);
+        }
+    };
 
-        let mut of = crate::util::FileWriter::new(std::fs::File::create(output).unwrap());
-        o.write_to(&mut of).unwrap();
+    // Hidden attribute values are filled in if given, and are disclosed in the IRMA session otherwise.
+    let identity = recipients[recipient]
+        .reconstruct(&given)
+        .unwrap_or_else(|_| recipients[recipient].clone());
 
-        eprintln!("Succesfully decrypted {}", output);
+    let method = if m.is_present("verify-email") {
+        AuthMethod::Email
     } else {
-        eprintln!("Did not scan the QR code and disclose in time");
+        AuthMethod::Irma
+    };
+
+    let usk = match m.value_of("usk") {
+        Some(path) => match read_usk(path) {
+            Some(usk) => usk,
+            None => {
+                eprintln!("Could not read the private key from {}", path);
+                std::process::exit(1);
+            }
+        },
+        None => {
+            // A threshold PKG requires a partial key of each of at least a threshold of its nodes.
+            let mut keys = Vec::new();
+            for server in servers {
+                match request_key(server, &o, recipient, &identity, method).await {
+                    Some(key) => keys.push(key),
+                    None => std::process::exit(1),
+                }
+            }
+
+            match combine_keys(keys) {
+                Some(usk) => usk,
+                None => {
+                    eprintln!("The partial keys of the nodes could not be combined");
+                    std::process::exit(1);
+                }
+            }
+        }
+    };
+
+    // A key for another identity, or for another timeframe, does not unseal the header.
+    let mut o = match o.unseal(recipient, &usk) {
+        Ok(o) => o,
+        Err(_) => {
+            eprintln!(
+                "The private key does not belong to the recipient, or more keys are required"
+            );
+            std::process::exit(1);
+        }
+    };
+
+    let paths = output_paths(m, input, o.metadata());
+    let (output, mut of) = match create_output(&paths) {
+        Some(output) => output,
+        None if paths.is_empty() => {
+            eprintln!("Could not determine the output file, please specify one");
+            std::process::exit(1);
+        }
+        None => {
+            eprintln!(
+                "Could not create {}, please specify another output file",
+                paths[0].display()
+            );
+            std::process::exit(1);
+        }
     };
+
+    eprintln!("Decrypting {} to {}", input, output.display());
+
+    std::io::copy(&mut o, &mut of).unwrap();
+
+    if let Some(t) = o.metadata().get_u64(METADATA_MODIFIED) {
+        of.set_modified(SystemTime::UNIX_EPOCH + Duration::from_secs(t))
+            .unwrap();
+    }
+
+    eprintln!("Succesfully decrypted {}", output.display());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn plain_filename() {
+        assert!(is_plain_filename("report.pdf"));
+        assert!(!is_plain_filename(".bashrc"));
+        assert!(!is_plain_filename("../report.pdf"));
+        assert!(!is_plain_filename("/etc/passwd"));
+    }
+
+    #[test]
+    fn existing_output() {
+        let dir = std::env::temp_dir().join(format!("irmaseal-output-{}", std::process::id()));
+        std::fs::create_dir(&dir).unwrap();
+
+        let paths = [dir.join("report.pdf"), dir.join("report")];
+        std::fs::write(&paths[0], b"existing").unwrap();
+
+        // An existing file is skipped, and left untouched.
+        let (output, _) = create_output(&paths).unwrap();
+        assert_eq!(output, paths[1].as_path());
+        assert_eq!(std::fs::read(&paths[0]).unwrap(), b"existing");
+
+        assert!(create_output(&paths).is_none());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
 }
@@ -1,8 +1,20 @@
 use clap::ArgMatches;
+use irmaseal_core::armor::ArmorWriter;
+use irmaseal_core::io::IoWriter;
 use irmaseal_core::stream::Sealer;
-use irmaseal_core::Identity;
+use irmaseal_core::{
+    Attribute, Identity, Metadata, PublicKey, Recipient, Writable, MAX_CONJUNCTION,
+    METADATA_CREATED, METADATA_FILENAME, METADATA_MODIFIED, METADATA_SIZE,
+};
+use std::fs::File;
+use std::io::BufWriter;
+use std::path::Path;
 use std::time::SystemTime;
 
+use crate::cache::{offline_parameters, read_public_key, Cache, PinMismatch, DEFAULT_MAX_AGE};
+
+const BSN: &str = "pbdf.gemeente.personalData.bsn";
+
 fn now() -> u64 {
     SystemTime::now()
         .duration_since(SystemTime::UNIX_EPOCH)
@@ -10,57 +22,208 @@ fn now() -> u64 {
         .as_secs()
 }
 
-pub async fn exec(m: &ArgMatches<'_>) {
+fn unix_time(t: std::io::Result<SystemTime>) -> Option<u64> {
+    t.ok()?
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .ok()
+        .map(|d| d.as_secs())
+}
+
+/// Parse an attribute given as `TYPE=VALUE` on the commandline.
+fn parse_attribute(s: &str) -> Option<Attribute> {
+    let (atype, value) = s.split_once('=')?;
+    Attribute::new(atype, Some(value)).ok()
+}
+
+/// The metadata of the input file that is restored on decryption.
+fn file_metadata(input: &str) -> Metadata {
+    let mut res = Metadata::new();
+
+    if let Some(name) = Path::new(input).file_name().and_then(|n| n.to_str()) {
+        // Names that do not fit are not restored.
+        let _ = res.push(METADATA_FILENAME, name.as_bytes());
+    }
+
+    let fm = std::fs::metadata(input).unwrap();
+    res.push_u64(METADATA_SIZE, fm.len()).unwrap();
+
+    if let Some(t) = unix_time(fm.modified()) {
+        res.push_u64(METADATA_MODIFIED, t).unwrap();
+    }
+    if let Some(t) = unix_time(fm.created()) {
+        res.push_u64(METADATA_CREATED, t).unwrap();
+    }
+
+    res
+}
+
+/// Seal the input file to the writer, yielding the number of plaintext bytes and the writer.
+fn seal<W: Writable>(is: &[Recipient], pk: &PublicKey, input: &str, w: W) -> (u64, W) {
     let mut rng = rand::thread_rng();
 
+    let mut sealer =
+        Sealer::with_metadata(is, pk, &Metadata::new(), &file_metadata(input), &mut rng, w)
+            .unwrap();
+    let mut src = File::open(input).unwrap();
+
+    let total_len = std::io::copy(&mut src, &mut sealer).unwrap();
+    (total_len, sealer.finish().unwrap())
+}
+
+pub async fn exec(m: &ArgMatches<'_>) {
     let input = m.value_of("INPUT").unwrap();
-    let email = m.value_of("email");
-    let bsn = m.value_of("bsn");
     let server = m.value_of("server").unwrap();
     let timestamp = now();
 
-    let i = match (email, bsn) {
-        (Some(email), None) => {
-            Identity::new(timestamp, "pbdf.pbdf.email.email", Some(email)).unwrap()
-        }
-        (None, Some(bsn)) => {
-            Identity::new(timestamp, "pbdf.gemeente.personalData.bsn", Some(bsn)).unwrap()
-        }
-        _ => {
-            eprintln!("Expected either email or BSN");
-            return;
+    let emails = m.values_of("email").into_iter().flatten();
+    let bsns = m.values_of("bsn").into_iter().flatten();
+
+    let attributes: Option<Vec<Attribute>> = emails
+        .map(|email| Attribute::new("pbdf.pbdf.email.email", Some(email)).ok())
+        .chain(bsns.map(|bsn| Attribute::new(BSN, Some(bsn)).ok()))
+        .chain(
+            m.values_of("attribute")
+                .into_iter()
+                .flatten()
+                .map(parse_attribute),
+        )
+        .collect();
+
+    let attributes = match attributes {
+        Some(attributes) => attributes,
+        None => {
+            eprintln!("Expected attributes as TYPE=VALUE");
+            std::process::exit(1);
         }
     };
 
-    let client = crate::client::Client::new(server).unwrap();
+    if attributes.is_empty() {
+        eprintln!("Expected at least one email, BSN or attribute");
+        std::process::exit(1);
+    }
 
-    let parameters = client.parameters().await.unwrap();
-    eprintln!("Fetched parameters from {}", server);
-    eprintln!("Encrypting for recipient {:#?}", i);
+    let cache = Cache::new();
 
-    let output = format!("{}.irma", input);
-    let mut w = crate::util::FileWriter::new(std::fs::File::create(&output).unwrap());
+    let parameters = if let Some(path) = m.value_of("public-key") {
+        let public_key = match read_public_key(path) {
+            Some(public_key) => public_key,
+            None => {
+                eprintln!("Could not read the public key from {}", path);
+                std::process::exit(1);
+            }
+        };
 
-    let mut sealer = Sealer::new(&i, &parameters.public_key, &mut rng, &mut w).unwrap();
+        let cached = cache.get(server);
+        if cached.is_none() {
+            eprintln!(
+                "No cached parameters for {}, assuming timeframes of {} seconds",
+                server, DEFAULT_MAX_AGE
+            );
+        }
 
-    use std::io::Read;
-    let mut src = std::fs::File::open(input).unwrap();
-    let mut buf = [0u8; 512];
+        let key_id = public_key.key_id();
+        eprintln!("Using public key {} of {}", key_id, path);
 
-    eprintln!("Encrypting {}...", input);
+        match offline_parameters(cached, public_key, m.is_present("trust-new-key")) {
+            Ok(parameters) => parameters,
+            Err(PinMismatch { pinned, .. }) => {
+                eprintln!(
+                    "The public key {} differs from the key {} pinned for {}, refusing to encrypt",
+                    key_id, pinned, server
+                );
+                eprintln!("Use --trust-new-key if the given key is legitimate");
+                std::process::exit(1);
+            }
+        }
+    } else if m.is_present("offline") {
+        match cache.get(server) {
+            Some(entry) => {
+                eprintln!("Using cached parameters of {}", server);
+                entry.parameters
+            }
+            None => {
+                eprintln!(
+                    "No cached parameters for {}, encrypt online once or give a public key",
+                    server
+                );
+                std::process::exit(1);
+            }
+        }
+    } else {
+        let client = crate::client::Client::new(server).unwrap();
+        let parameters = client.parameters().await.unwrap();
+        eprintln!("Fetched parameters from {}", server);
 
-    let mut total_len = 0;
-    loop {
-        let len = src.read(&mut buf).unwrap();
-        total_len += len;
+        // The public key is trusted on first use, a changed key is likely an impersonation of the PKG.
+        let key_id = parameters.public_key.key_id();
+        match cache.pin(server, parameters, m.is_present("trust-new-key")) {
+            Ok(parameters) => parameters,
+            Err(PinMismatch { pinned, retired }) => {
+                eprintln!(
+                    "The public key of {} changed from {} to {}, refusing to encrypt",
+                    server, pinned, key_id
+                );
+                if retired {
+                    eprintln!("The PKG lists the previous key as retired, which may indicate a key rotation");
+                }
+                eprintln!("Use --trust-new-key if the new key is legitimate");
+                std::process::exit(1);
+            }
+        }
+    };
 
-        if len == 0 {
-            break;
+    // BSNs are not disclosed in the header.
+    let recipient = |i: Identity| {
+        if i.attributes.iter().any(|a| a.atype.as_str() == BSN) {
+            Recipient::hidden(i)
+        } else {
+            Recipient::new(i)
         }
+    };
 
-        use irmaseal_core::Writable;
-        sealer.write(&buf[0..len]).unwrap();
-    }
+    // Aligned to the timeframe, such that recipients can reuse their keys for other files.
+    let is: Vec<Recipient> = if m.is_present("conjunction") {
+        match Identity::aligned(&parameters, timestamp, &attributes) {
+            Ok(i) => vec![recipient(i)],
+            Err(_) => {
+                eprintln!(
+                    "Expected at most {} attributes of distinct types",
+                    MAX_CONJUNCTION
+                );
+                std::process::exit(1);
+            }
+        }
+    } else {
+        attributes
+            .into_iter()
+            .map(|a| recipient(Identity::aligned(&parameters, timestamp, &[a]).unwrap()))
+            .collect()
+    };
+
+    eprintln!("Encrypting for recipients {:#?}", is);
+
+    let armor = m.is_present("armor");
+    let output = if armor {
+        format!("{}.irma.asc", input)
+    } else {
+        format!("{}.irma", input)
+    };
+    let w = IoWriter::new(BufWriter::new(File::create(&output).unwrap()));
+
+    eprintln!("Encrypting {}...", input);
+
+    let total_len = if armor {
+        let (total_len, w) = seal(
+            &is,
+            &parameters.public_key,
+            input,
+            ArmorWriter::new(w).unwrap(),
+        );
+        w.finish().unwrap();
+        total_len
+    } else {
+        seal(&is, &parameters.public_key, input, w).0
+    };
 
     eprintln!(
         "Encrypted {} bytes, written result to {}",
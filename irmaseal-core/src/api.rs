@@ -1,20 +1,82 @@
 //! Structs that define the IRMAseal REST API protocol.
 
 use crate::*;
+use arrayvec::{ArrayString, ArrayVec};
 use serde::{Deserialize, Serialize};
 
+/// The maximum number of master key generations listed in the `Parameters`.
+pub const MAX_GENERATIONS: usize = 16;
+
+/// A generation of the master key of the PKG, and the period in which it is used for sealing.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct KeyGeneration {
+    pub key_id: KeyId,
+    /// The UNIX timestamp from which the key is used for sealing.
+    pub valid_from: u64,
+    /// The UNIX timestamp from which the key is retired, or `None` for the active key.
+    /// The PKG still issues user secret keys under retired keys, such that existing streams remain readable.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub valid_until: Option<u64>,
+}
+
+/// The master key generations served by a PKG.
+pub type KeyGenerations = ArrayVec<[KeyGeneration; MAX_GENERATIONS]>;
+
 /// Set of public parameters for the Private Key Generator (PKG).
 #[derive(Serialize, Deserialize)]
 pub struct Parameters {
     pub format_version: u8,
+    /// The duration of a timeframe in seconds. The PKG only issues keys for identities of which
+    /// the timestamp is the start of a timeframe.
     pub max_age: u64,
+    /// The maximum age in seconds of a timeframe for which the PKG still issues keys, if limited.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_retention: Option<u64>,
+    /// The number of seconds a timeframe may start in the future, to allow for clock skew.
+    #[serde(default)]
+    pub max_skew: u64,
+    /// The public key of the active master key, with which streams should be sealed.
     pub public_key: PublicKey,
+    /// The master key generations of which the PKG issues user secret keys, both active and retired.
+    #[serde(default)]
+    pub keys: KeyGenerations,
+}
+
+impl Parameters {
+    /// The generation of the master key with the given key ID, if the PKG serves it.
+    pub fn generation(&self, key_id: &KeyId) -> Option<&KeyGeneration> {
+        self.keys.iter().find(|g| g.key_id == *key_id)
+    }
 }
 
-/// A request for the user secret key for an identity.
+/// The method with which the PKG verifies that the requester has the attributes of an identity.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum AuthMethod {
+    /// Disclosure of the attributes in an IRMA session.
+    #[default]
+    Irma,
+    /// A code sent to the e-mail address of an identity consisting of the `pbdf.pbdf.email.email`
+    /// attribute only, which the requester must send back using `KeyVerification`.
+    Email,
+}
+
+/// A request for the user secret key for an identity, including its timestamp.
+///
+/// All attributes of the policy are requested in a single IRMA session. Attributes of which the value
+/// is omitted are filled in with the disclosed values.
 #[derive(Serialize, Deserialize, Debug)]
 pub struct KeyRequest {
-    pub attribute: Attribute,
+    pub identity: Identity,
+    /// The method with which the identity is verified, IRMA if omitted.
+    #[serde(default)]
+    pub method: AuthMethod,
+    /// The key ID of the master key under which the identity was sealed, or the active key if omitted.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub key_id: Option<KeyId>,
+    /// The public key to which the issued key is wrapped, such that only the requesting client can use it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub delivery_key: Option<DeliveryPublicKey>,
 }
 
 /// The challenge to verify the key request.
@@ -22,12 +84,24 @@ pub struct KeyRequest {
 pub struct KeyChallenge<'a> {
     /// The QR code that should be shown to the user,
     /// such that it can be scanned using the IRMA app.
+    ///
+    /// Empty if the identity is verified using another method than IRMA.
     pub qr: &'a str,
 
     /// The token that should be used to retrieve the status of the earlier request.
+    ///
+    /// The token binds the session to the requested identity, and is only valid for a limited time.
     pub token: &'a str,
 }
 
+/// The code the user received to verify the key request, if verified using e-mail.
+///
+/// It is posted to the same URL as used to retrieve the status of the request.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct KeyVerification {
+    pub code: ArrayString<[u8; 32]>,
+}
+
 /// The status of a key request.
 #[derive(Serialize, Deserialize, Debug, PartialEq)]
 #[serde(rename_all = "SCREAMING_SNAKE_CASE")]
@@ -38,9 +112,11 @@ pub enum KeyStatus {
     Connected,
     /// The IRMA session was cancelled.
     Cancelled,
-    /// The IRMA session was completed succesfully, but it did not contain a valid attribute disclosure proof.
+    /// The IRMA session was completed succesfully, but it did not contain a valid attribute disclosure proof,
+    /// or the code of the verification was wrong.
     DoneInvalid,
-    /// The IRMA session was completed succesfully, and it contains a valid attribute disclosure proof.
+    /// The IRMA session was completed succesfully, and it contains a valid attribute disclosure proof,
+    /// or the code of the verification was right.
     DoneValid,
     /// The IRMA session has timed out.
     Timeout,
@@ -51,7 +127,14 @@ pub enum KeyStatus {
 pub struct KeyResponse {
     /// The current status of the key request.
     pub status: KeyStatus,
-    /// The key will remain `None` until the status is `DoneValid`.
+    /// The key will remain `None` until the status is `DoneValid`, or if a `delivery_key` was requested.
     #[serde(skip_serializing_if = "Option::is_none")]
     pub key: Option<UserSecretKey>,
+    /// The key wrapped to the `delivery_key` of the request, which will remain `None` until the status is `DoneValid`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub wrapped_key: Option<WrappedUserSecretKey>,
+    /// The index of the share of the master secret key with which the key was issued, if the PKG is a node
+    /// of a threshold PKG. Such a partial key must be combined with those of other nodes using `threshold::combine`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub share: Option<u8>,
 }
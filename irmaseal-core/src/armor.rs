@@ -0,0 +1,377 @@
+//! ASCII-armored encoding of IRMAseal bytestreams, suitable for pasting into e-mail bodies and chat.
+//!
+//! The armored bytestream is enclosed in `BEGIN IRMASEAL` and `END IRMASEAL` lines, and consists of
+//! base64 encoded lines of 64 characters. The final line before the `END IRMASEAL` line starts
+//! with a `=` and contains the base64 encoded CRC-24 checksum of the bytestream, as in OpenPGP.
+
+use crate::*;
+use arrayvec::ArrayVec;
+
+const BEGIN: &[u8] = b"-----BEGIN IRMASEAL-----";
+const END: &[u8] = b"-----END IRMASEAL-----";
+
+/// The number of bytes encoded on a single line of 64 characters.
+const LINESIZE: usize = 48;
+
+/// The maximum number of bytes yielded by a single read of an `ArmorReader`.
+/// Exactly this many bytes can be read at once with `read_bytes_strict`.
+pub const READSIZE: usize = 4096;
+
+/// The maximum length of the BEGIN and END lines that is accepted, excluding trailing whitespace.
+const MAXMARKERSIZE: usize = 64;
+
+const CRC24_INIT: u32 = 0x00B7_04CE;
+const CRC24_POLY: u32 = 0x0186_4CFB;
+
+/// Whether the argument bytes, for example the first bytes of a file, start an armored bytestream.
+pub fn is_armored(prefix: &[u8]) -> bool {
+    let start = prefix
+        .iter()
+        .position(|c| !c.is_ascii_whitespace())
+        .unwrap_or(prefix.len());
+
+    prefix[start..].starts_with(BEGIN)
+}
+
+fn crc24(mut crc: u32, buf: &[u8]) -> u32 {
+    for b in buf {
+        crc ^= u32::from(*b) << 16;
+        for _ in 0..8 {
+            crc <<= 1;
+            if crc & 0x0100_0000 != 0 {
+                crc ^= CRC24_POLY;
+            }
+        }
+    }
+
+    crc & 0x00FF_FFFF
+}
+
+fn crc24_bytes(crc: u32) -> [u8; 3] {
+    let b = crc.to_be_bytes();
+    [b[1], b[2], b[3]]
+}
+
+/// Writable that armors everything written to it, for example the output of a `Sealer`.
+///
+/// The armor must be completed with `finish`, which writes the checksum and the END line.
+pub struct ArmorWriter<W: Writable> {
+    buf: [u8; LINESIZE],
+    len: usize,
+    crc: u32,
+    w: W,
+}
+
+impl<W: Writable> ArmorWriter<W> {
+    /// Start an armored bytestream, immediately writing the BEGIN line.
+    pub fn new(mut w: W) -> Result<ArmorWriter<W>, Error> {
+        w.write(BEGIN)?;
+        w.write(b"\n")?;
+
+        Ok(ArmorWriter {
+            buf: [0u8; LINESIZE],
+            len: 0,
+            crc: CRC24_INIT,
+            w,
+        })
+    }
+
+    /// Write the final line, the checksum and the END line, and yield the underlying writer.
+    pub fn finish(mut self) -> Result<W, Error> {
+        if self.len > 0 {
+            self.flush_line()?;
+        }
+
+        let mut checksum = [0u8; 4];
+        base64::encode_config_slice(crc24_bytes(self.crc), base64::STANDARD, &mut checksum);
+
+        self.w.write(b"=")?;
+        self.w.write(&checksum)?;
+        self.w.write(b"\n")?;
+        self.w.write(END)?;
+        self.w.write(b"\n")?;
+
+        Ok(self.w)
+    }
+
+    fn flush_line(&mut self) -> Result<(), Error> {
+        let mut line = [0u8; LINESIZE / 3 * 4];
+        let n = base64::encode_config_slice(&self.buf[..self.len], base64::STANDARD, &mut line);
+
+        self.w.write(&line[..n])?;
+        self.w.write(b"\n")?;
+        self.len = 0;
+
+        Ok(())
+    }
+}
+
+impl<W: Writable> Writable for ArmorWriter<W> {
+    fn write(&mut self, mut buf: &[u8]) -> Result<(), Error> {
+        self.crc = crc24(self.crc, buf);
+
+        while !buf.is_empty() {
+            let n = core::cmp::min(LINESIZE - self.len, buf.len());
+            self.buf[self.len..self.len + n].copy_from_slice(&buf[..n]);
+            self.len += n;
+            buf = &buf[n..];
+
+            if self.len == LINESIZE {
+                self.flush_line()?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Readable that strips the armor off an armored bytestream, for example to pass on to `OpenerSealed`.
+///
+/// Line breaks and other whitespace within the base64 encoded lines are ignored, such that
+/// rewrapped armored bytestreams are accepted. Will throw an `Error::IntegrityViolation` if the checksum
+/// does not match.
+pub struct ArmorReader<R: Readable> {
+    begun: bool,
+    finished: bool,
+    line_start: bool,
+    quad: [u8; 4],
+    qlen: usize,
+    // A decoded quad may exceed the requested amount by 2 bytes.
+    buf: [u8; READSIZE + 2],
+    pos: usize,
+    len: usize,
+    crc: u32,
+    r: R,
+}
+
+impl<R: Readable> ArmorReader<R> {
+    pub fn new(r: R) -> ArmorReader<R> {
+        ArmorReader {
+            begun: false,
+            finished: false,
+            line_start: true,
+            quad: [0u8; 4],
+            qlen: 0,
+            buf: [0u8; READSIZE + 2],
+            pos: 0,
+            len: 0,
+            crc: CRC24_INIT,
+            r,
+        }
+    }
+
+    /// Yield the underlying reader.
+    pub fn into_inner(self) -> R {
+        self.r
+    }
+
+    /// Read a byte from the armored bytestream, which must not end before the END line.
+    fn next(&mut self) -> Result<u8, Error> {
+        match self.r.read_byte() {
+            Err(Error::EndOfStream) => Err(Error::PrematureEndError),
+            res => res,
+        }
+    }
+
+    /// Read the next non-empty line without surrounding whitespace, which should be one of the markers.
+    fn read_marker(&mut self) -> Result<ArrayVec<[u8; MAXMARKERSIZE]>, Error> {
+        let mut line = ArrayVec::new();
+
+        loop {
+            let c = self.next()?;
+            if c == b'\n' {
+                while line.last().is_some_and(u8::is_ascii_whitespace) {
+                    line.pop();
+                }
+                if !line.is_empty() {
+                    return Ok(line);
+                }
+            } else if !line.is_empty() || !c.is_ascii_whitespace() {
+                line.try_push(c).or(Err(Error::FormatViolation))?;
+            }
+        }
+    }
+
+    /// Read the checksum line, of which the leading `=` has been read, and the END line.
+    fn read_trailer(&mut self) -> Result<(), Error> {
+        if self.qlen != 0 {
+            return Err(Error::FormatViolation);
+        }
+
+        let mut checksum = ArrayVec::<[u8; 4]>::new();
+        loop {
+            let c = self.next()?;
+            if c == b'\n' {
+                break;
+            } else if !c.is_ascii_whitespace() {
+                checksum.try_push(c).or(Err(Error::FormatViolation))?;
+            }
+        }
+
+        let mut expected = [0u8; 3];
+        match base64::decode_config_slice(&checksum, base64::STANDARD, &mut expected) {
+            Ok(3) => {}
+            _ => return Err(Error::FormatViolation),
+        };
+
+        if expected != crc24_bytes(self.crc) {
+            return Err(Error::IntegrityViolation);
+        }
+
+        if self.read_marker()?.as_slice() != END {
+            return Err(Error::FormatViolation);
+        }
+
+        self.finished = true;
+        Ok(())
+    }
+
+    /// Decode until at least `want` bytes are buffered, or the trailer has been read.
+    fn fill_buf(&mut self, want: usize) -> Result<(), Error> {
+        if !self.begun {
+            if self.read_marker().or(Err(Error::NotIRMASEAL))?.as_slice() != BEGIN {
+                return Err(Error::NotIRMASEAL);
+            }
+            self.begun = true;
+        }
+
+        // Move the remaining bytes to the front.
+        self.buf.copy_within(self.pos..self.len, 0);
+        self.len -= self.pos;
+        self.pos = 0;
+
+        while !self.finished && self.len < want {
+            let c = self.next()?;
+
+            match c {
+                b'\n' => self.line_start = true,
+                b'=' if self.line_start => self.read_trailer()?,
+                b'-' if self.line_start => return Err(Error::FormatViolation),
+                c if c.is_ascii_whitespace() => {}
+                c => {
+                    self.line_start = false;
+                    self.quad[self.qlen] = c;
+                    self.qlen += 1;
+
+                    if self.qlen == 4 {
+                        let out = &mut self.buf[self.len..self.len + 3];
+                        let n = base64::decode_config_slice(self.quad, base64::STANDARD, out)
+                            .or(Err(Error::FormatViolation))?;
+
+                        self.crc = crc24(self.crc, &out[..n]);
+                        self.len += n;
+                        self.qlen = 0;
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl<R: Readable> Readable for ArmorReader<R> {
+    fn read_byte(&mut self) -> Result<u8, Error> {
+        match self.read_bytes(1)? {
+            [b] => Ok(*b),
+            _ => Err(Error::EndOfStream),
+        }
+    }
+
+    /// Read up to `n` bytes, but at most `READSIZE` at once.
+    fn read_bytes(&mut self, n: usize) -> Result<&[u8], Error> {
+        let n = core::cmp::min(n, READSIZE);
+        if self.len - self.pos < n {
+            self.fill_buf(n)?;
+        }
+
+        if self.pos == self.len && n > 0 {
+            return Err(Error::EndOfStream);
+        }
+
+        let end = core::cmp::min(self.pos + n, self.len);
+        let res = &self.buf[self.pos..end];
+        self.pos = end;
+
+        Ok(res)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::SliceReader;
+
+    type BigBuf = ArrayVec<[u8; 65536]>;
+
+    fn armor(content: &[u8]) -> BigBuf {
+        let mut w = ArmorWriter::new(BigBuf::new()).unwrap();
+        w.write(content).unwrap();
+        w.finish().unwrap()
+    }
+
+    fn dearmor(buf: &[u8]) -> Result<BigBuf, Error> {
+        let mut r = ArmorReader::new(SliceReader::new(buf));
+        let mut res = BigBuf::new();
+
+        loop {
+            match r.read_bytes(1000) {
+                Ok(b) => res.write(b)?,
+                Err(Error::EndOfStream) => return Ok(res),
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    #[test]
+    fn reflection() {
+        for &len in &[0, 1, 2, 3, 47, 48, 49, 1000, 10000] {
+            let content: BigBuf = (0..len).map(|i| i as u8).collect();
+
+            let buf = armor(&content);
+            assert!(is_armored(&buf));
+            assert!(buf.iter().all(|c| c.is_ascii()));
+            assert!(buf.split(|c| *c == b'\n').all(|l| l.len() <= 64));
+
+            assert_eq!(dearmor(&buf).unwrap(), content);
+        }
+    }
+
+    #[test]
+    fn rewrapped() {
+        let content: BigBuf = (0..1000).map(|i| i as u8).collect();
+        let buf = armor(&content);
+
+        // Indent the lines and use CRLF line endings, as some mail clients do.
+        let mut rewrapped = BigBuf::new();
+        rewrapped.write(b"\r\n").unwrap();
+        for line in buf.split(|c| *c == b'\n').filter(|l| !l.is_empty()) {
+            rewrapped.write(b"  ").unwrap();
+            rewrapped.write(line).unwrap();
+            rewrapped.write(b"\r\n").unwrap();
+        }
+
+        assert!(is_armored(&rewrapped));
+        assert_eq!(dearmor(&rewrapped).unwrap(), content);
+    }
+
+    #[test]
+    fn corrupt() {
+        let buf = armor(&[0xAAu8; 1000]);
+
+        // Flip a character in the second line.
+        let mut corrupted = buf.clone();
+        corrupted[BEGIN.len() + 10] = b'A';
+        assert!(matches!(
+            dearmor(&corrupted),
+            Err(Error::IntegrityViolation)
+        ));
+
+        // Without the END line.
+        let truncated = &buf[..buf.len() - END.len() - 1];
+        assert!(matches!(dearmor(truncated), Err(Error::PrematureEndError)));
+
+        assert!(!is_armored(b"not armored"));
+        assert!(matches!(dearmor(b"not armored\n"), Err(Error::NotIRMASEAL)));
+    }
+}
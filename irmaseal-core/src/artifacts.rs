@@ -7,6 +7,27 @@ pub struct PublicKey(pub(crate) ibe::kiltz_vahlis_one::PublicKey);
 /// An IRMAseal user private key, as generated by the Private Key Generator (PKG).
 pub struct UserSecretKey(pub(crate) ibe::kiltz_vahlis_one::UserSecretKey);
 
+/// The size of a `KeyId`.
+pub const KEYIDSIZE: usize = 8;
+
+/// The fingerprint of a `PublicKey`, identifying the PKG under which a recipient of a stream was sealed.
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Hash)]
+pub struct KeyId(pub [u8; KEYIDSIZE]);
+
+/// The size of the byte representation of a `DeliveryPublicKey`.
+pub const DELIVERY_KEYSIZE: usize = 32;
+
+/// The size of the byte representation of a `WrappedUserSecretKey`, consisting of the ephemeral
+/// public key of the PKG, the encrypted user secret key and its authentication tag.
+pub const WRAPPED_USKSIZE: usize = DELIVERY_KEYSIZE + 192 + 16;
+
+/// The ephemeral X25519 public key of a client, to which the PKG wraps the user secret key it issues.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct DeliveryPublicKey(pub(crate) [u8; DELIVERY_KEYSIZE]);
+
+/// A user secret key wrapped to a `DeliveryPublicKey`, which only the client holding the corresponding secret can unwrap.
+pub struct WrappedUserSecretKey(pub(crate) [u8; WRAPPED_USKSIZE]);
+
 /// Serialize to a base64 encoded waters byte representation.
 impl Serialize for PublicKey {
     fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
@@ -23,13 +44,37 @@ impl PublicKey {
     }
 }
 
+#[cfg(feature = "stream")]
+impl PublicKey {
+    /// The key ID of this public key, a truncated SHA3-256 hash of its byte representation.
+    ///
+    /// Being short, it identifies a key, but does not suffice to verify one; use `fingerprint` instead.
+    pub fn key_id(&self) -> KeyId {
+        let mut res = [0u8; KEYIDSIZE];
+        res.copy_from_slice(&self.fingerprint()[..KEYIDSIZE]);
+        KeyId(res)
+    }
+
+    /// The full SHA3-256 hash of the byte representation of this public key, of which the key ID is the start.
+    pub fn fingerprint(&self) -> [u8; 32] {
+        use sha3::{Digest, Sha3_256};
+
+        let mut h = Sha3_256::new();
+        h.input(b"irmaseal key id");
+        h.input(self.0.to_bytes().as_ref());
+
+        let mut res = [0u8; 32];
+        res.copy_from_slice(&h.result());
+        res
+    }
+}
+
 /// Deserialize from a base64 encoded waters byte representation.
 impl<'de> Deserialize<'de> for PublicKey {
     fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
         let s = <&'de str>::deserialize(deserializer)?;
 
-        PublicKey::from_base64(s)
-            .ok_or_else(|| serde::de::Error::custom("Not a waters public key"))
+        PublicKey::from_base64(s).ok_or_else(|| serde::de::Error::custom("Not a waters public key"))
     }
 }
 
@@ -64,3 +109,77 @@ impl From<ibe::kiltz_vahlis_one::UserSecretKey> for UserSecretKey {
         Self(usk)
     }
 }
+
+/// Decode a base64 encoded string of exactly `out.len()` bytes, which is at most 256 bytes.
+fn decode_exact<E: serde::de::Error>(s: &str, out: &mut [u8]) -> Result<(), E> {
+    if s.len() > out.len().div_ceil(3) * 4 {
+        return Err(E::custom("Incorrect length"));
+    }
+
+    let mut b = [0u8; 258];
+    match base64::decode_config_slice(s, base64::STANDARD, &mut b) {
+        Ok(n) if n == out.len() => {
+            out.copy_from_slice(&b[..n]);
+            Ok(())
+        }
+        _ => Err(E::custom("Incorrect length")),
+    }
+}
+
+/// Format as hexadecimal, for display to administrators and users.
+impl core::fmt::Display for KeyId {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        for b in self.0.iter() {
+            write!(f, "{:02x}", b)?;
+        }
+        Ok(())
+    }
+}
+
+/// Serialize to a base64 encoded byte representation.
+impl Serialize for KeyId {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&base64::encode(self.0))
+    }
+}
+
+/// Deserialize from a base64 encoded byte representation.
+impl<'de> Deserialize<'de> for KeyId {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let mut b = [0u8; KEYIDSIZE];
+        decode_exact(<&'de str>::deserialize(deserializer)?, &mut b)?;
+        Ok(KeyId(b))
+    }
+}
+
+/// Serialize to a base64 encoded byte representation.
+impl Serialize for DeliveryPublicKey {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&base64::encode(self.0))
+    }
+}
+
+/// Deserialize from a base64 encoded byte representation.
+impl<'de> Deserialize<'de> for DeliveryPublicKey {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let mut b = [0u8; DELIVERY_KEYSIZE];
+        decode_exact(<&'de str>::deserialize(deserializer)?, &mut b)?;
+        Ok(DeliveryPublicKey(b))
+    }
+}
+
+/// Serialize to a base64 encoded byte representation.
+impl Serialize for WrappedUserSecretKey {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&base64::encode(self.0.as_ref()))
+    }
+}
+
+/// Deserialize from a base64 encoded byte representation.
+impl<'de> Deserialize<'de> for WrappedUserSecretKey {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let mut b = [0u8; WRAPPED_USKSIZE];
+        decode_exact(<&'de str>::deserialize(deserializer)?, &mut b)?;
+        Ok(WrappedUserSecretKey(b))
+    }
+}
@@ -0,0 +1,180 @@
+//! Delivery of user secret keys to the client that requested them.
+//!
+//! The client sends an ephemeral X25519 public key along with its key request. The PKG wraps the user
+//! secret key using AES-256-GCM, with a key derived from the X25519 shared secret of that public key
+//! and a fresh ephemeral key of its own. Thus only the requesting client can use the issued key,
+//! even if the session token is disclosed.
+
+use aes_gcm::aead::generic_array::GenericArray;
+use aes_gcm::aead::{Aead, NewAead};
+use aes_gcm::Aes256Gcm;
+use arrayref::{array_mut_ref, array_refs};
+use rand::{CryptoRng, Rng};
+use sha3::{Digest, Sha3_256};
+use x25519_dalek::{EphemeralSecret, PublicKey as X25519PublicKey};
+
+use crate::util::open_ct;
+use crate::*;
+
+const USKSIZE: usize = 192;
+
+/// The ephemeral X25519 secret of a client requesting a user secret key.
+pub struct DeliverySecretKey {
+    secret: EphemeralSecret,
+    public: DeliveryPublicKey,
+}
+
+/// Derive the AES key from the shared secret and both public keys.
+fn derive_key(shared: &[u8; 32], ephemeral: &[u8; 32], client: &[u8; 32]) -> [u8; 32] {
+    let mut h = Sha3_256::new();
+    h.input(b"irmaseal key delivery");
+    h.input(shared);
+    h.input(ephemeral);
+    h.input(client);
+
+    let mut res = [0u8; 32];
+    res.copy_from_slice(&h.result());
+    res
+}
+
+impl DeliverySecretKey {
+    /// Generate a fresh secret, to be used for a single key request.
+    pub fn new<R: Rng + CryptoRng>(rng: &mut R) -> DeliverySecretKey {
+        let secret = EphemeralSecret::new(rng);
+        let public = DeliveryPublicKey(X25519PublicKey::from(&secret).to_bytes());
+
+        DeliverySecretKey { secret, public }
+    }
+
+    /// The public key to send along with the key request.
+    pub fn public_key(&self) -> DeliveryPublicKey {
+        self.public
+    }
+
+    /// Unwrap the user secret key as issued by the PKG.
+    ///
+    /// Throws an IntegrityViolation if the key was not wrapped to this secret, or has been tampered with.
+    pub fn unwrap_key(self, wrapped: &WrappedUserSecretKey) -> Result<UserSecretKey, Error> {
+        let (ephemeral, ciphertext, tag) = array_refs![&wrapped.0, DELIVERY_KEYSIZE, USKSIZE, 16];
+
+        let shared = self
+            .secret
+            .diffie_hellman(&X25519PublicKey::from(*ephemeral));
+        if !shared.was_contributory() {
+            return Err(Error::IntegrityViolation);
+        }
+
+        let key = derive_key(shared.as_bytes(), ephemeral, &self.public.0);
+
+        let mut usk = *ciphertext;
+        Aes256Gcm::new(key.into())
+            .decrypt_in_place_detached(
+                GenericArray::from_slice(&[0u8; 12]),
+                &[],
+                &mut usk,
+                GenericArray::from_slice(tag),
+            )
+            .or(Err(Error::IntegrityViolation))?;
+
+        open_ct(ibe::kiltz_vahlis_one::UserSecretKey::from_bytes(&usk))
+            .map(UserSecretKey)
+            .ok_or(Error::FormatViolation)
+    }
+}
+
+impl UserSecretKey {
+    /// Wrap this key to the public key of the requesting client.
+    ///
+    /// Throws a ConstraintViolation if the public key is not a valid X25519 public key.
+    pub fn wrap<R: Rng + CryptoRng>(
+        &self,
+        client: &DeliveryPublicKey,
+        rng: &mut R,
+    ) -> Result<WrappedUserSecretKey, Error> {
+        let secret = EphemeralSecret::new(rng);
+        let ephemeral = X25519PublicKey::from(&secret).to_bytes();
+
+        let shared = secret.diffie_hellman(&X25519PublicKey::from(client.0));
+        if !shared.was_contributory() {
+            return Err(Error::ConstraintViolation);
+        }
+
+        // The key is used only once, thus the nonce can be fixed.
+        let key = derive_key(shared.as_bytes(), &ephemeral, &client.0);
+
+        let mut res = [0u8; WRAPPED_USKSIZE];
+        res[..DELIVERY_KEYSIZE].copy_from_slice(&ephemeral);
+
+        let usk = array_mut_ref![res, DELIVERY_KEYSIZE, USKSIZE];
+        usk.copy_from_slice(&self.0.to_bytes());
+
+        let tag = Aes256Gcm::new(key.into())
+            .encrypt_in_place_detached(GenericArray::from_slice(&[0u8; 12]), &[], usk)
+            .or(Err(Error::ConstraintViolation))?;
+        res[DELIVERY_KEYSIZE + USKSIZE..].copy_from_slice(&tag);
+
+        Ok(WrappedUserSecretKey(res))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn usk() -> UserSecretKey {
+        let mut rng = rand::thread_rng();
+        let (pk, sk) = ibe::kiltz_vahlis_one::setup(&mut rng);
+        let i = Identity::new(
+            1566722100,
+            "pbdf.pbdf.email.email",
+            Some("w.geraedts@sarif.nl"),
+        )
+        .unwrap();
+
+        UserSecretKey(ibe::kiltz_vahlis_one::extract_usk(
+            &pk,
+            &sk,
+            &i.derive(),
+            &mut rng,
+        ))
+    }
+
+    #[test]
+    fn reflection() {
+        let mut rng = rand::thread_rng();
+        let usk = usk();
+
+        let secret = DeliverySecretKey::new(&mut rng);
+        let wrapped = usk.wrap(&secret.public_key(), &mut rng).unwrap();
+        let unwrapped = secret.unwrap_key(&wrapped).unwrap();
+
+        assert_eq!(unwrapped.0.to_bytes()[..], usk.0.to_bytes()[..]);
+    }
+
+    #[test]
+    fn wrong_recipient() {
+        let mut rng = rand::thread_rng();
+        let usk = usk();
+
+        let secret = DeliverySecretKey::new(&mut rng);
+        let mut wrapped = usk.wrap(&secret.public_key(), &mut rng).unwrap();
+
+        let other = DeliverySecretKey::new(&mut rng);
+        assert!(matches!(
+            other.unwrap_key(&wrapped),
+            Err(Error::IntegrityViolation)
+        ));
+
+        wrapped.0[DELIVERY_KEYSIZE] ^= 0x01;
+        assert!(matches!(
+            secret.unwrap_key(&wrapped),
+            Err(Error::IntegrityViolation)
+        ));
+
+        // A low order point as public key.
+        assert!(matches!(
+            usk.wrap(&DeliveryPublicKey([0u8; DELIVERY_KEYSIZE]), &mut rng),
+            Err(Error::ConstraintViolation)
+        ));
+    }
+}
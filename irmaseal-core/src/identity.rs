@@ -1,16 +1,23 @@
 use super::{Error, Readable, Writable};
+use crate::api::Parameters;
 use arrayref::array_ref;
 use arrayvec::{ArrayString, ArrayVec};
+use core::ops::Range;
 use serde::{Deserialize, Serialize};
 
 const IDENTITY_UNSET: u8 = 0xFF;
 
-// Must be at least 8+1+255+1+254 = 519
-#[allow(dead_code)]
-type IdentityBuf = ArrayVec<[u8; 1024]>;
+/// The maximum number of attributes in the conjunction of a single identity.
+pub const MAX_CONJUNCTION: usize = 4;
 
-/// An IRMAseal Attribute, which is a simple case of an IRMA ConDisCon.
-#[derive(Serialize, Deserialize, Debug, PartialEq)]
+// Must be at least 8+1+MAX_CONJUNCTION*(1+255+1+254) = 2053
+type IdentityBuf = ArrayVec<[u8; 4096]>;
+
+/// A conjunction of attributes, all of which must be disclosed to obtain the key for an identity.
+pub type Conjunction = ArrayVec<[Attribute; MAX_CONJUNCTION]>;
+
+/// An IRMAseal Attribute, which is the simplest case of an IRMA ConDisCon.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
 pub struct Attribute {
     #[serde(rename = "type")]
     pub atype: ArrayString<[u8; 255]>,
@@ -18,10 +25,29 @@ pub struct Attribute {
 }
 
 /// An IRMAseal identity, from which internally a Waters identity can be derived.
-#[derive(Serialize, Deserialize, Debug, PartialEq)]
+///
+/// The identity is a policy consisting of a conjunction of attributes. These attributes are ordered
+/// canonically by their type, which is unique within the conjunction. Use `Identity::conjunction`
+/// to construct such an ordering.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
 pub struct Identity {
     pub timestamp: u64,
-    pub attribute: Attribute,
+    pub attributes: Conjunction,
+}
+
+/// A recipient of an IRMAseal bytestream.
+///
+/// The bytestream is always sealed for the full identity, including its attribute values.
+/// The header however only contains a hint, from which the attribute values are omitted if `hidden` is set.
+/// Such a recipient reconstructs the full identity by supplying or disclosing its attribute values.
+///
+/// Omitting the values does not hide them. The ciphertexts of the Kiltz-Vahlis IBE1 scheme are not
+/// anonymous, thus anyone with the public key can test candidate values against the header offline.
+/// Values with little entropy, such as a BSN or an e-mail address, are therefore easily recovered.
+#[derive(Debug, PartialEq, Clone)]
+pub struct Recipient {
+    pub identity: Identity,
+    pub hidden: bool,
 }
 
 impl Attribute {
@@ -31,7 +57,7 @@ impl Attribute {
     pub fn new(atype: &str, value: Option<&str>) -> Result<Self, Error> {
         let atype = ArrayString::<[u8; 255]>::from(atype).or(Err(Error::ConstraintViolation))?;
         let value = value
-            .map(|v| Ok(ArrayString::<[u8; 254]>::from(v).or(Err(Error::ConstraintViolation))?))
+            .map(|v| ArrayString::<[u8; 254]>::from(v).or(Err(Error::ConstraintViolation)))
             .transpose()?;
 
         Ok(Attribute { atype, value })
@@ -70,7 +96,8 @@ impl Attribute {
     pub fn read_from<R: Readable>(r: &mut R) -> Result<Self, Error> {
         let at_len = u8::from_be(r.read_byte()?);
         let at_len = usize::from(at_len);
-        let atype = core::str::from_utf8(r.read_bytes(at_len)?).or(Err(Error::FormatViolation))?;
+        let atype =
+            core::str::from_utf8(r.read_bytes_strict(at_len)?).or(Err(Error::FormatViolation))?;
 
         // Unwrap is valid because it impossible to not fit given u8.
         let atype = ArrayString::<[u8; 255]>::from(atype).unwrap();
@@ -80,8 +107,8 @@ impl Attribute {
             None
         } else {
             let i_len = usize::from(i_len);
-            let value =
-                core::str::from_utf8(r.read_bytes(i_len)?).or(Err(Error::FormatViolation))?;
+            let value = core::str::from_utf8(r.read_bytes_strict(i_len)?)
+                .or(Err(Error::FormatViolation))?;
 
             // Unwrap is valid because it impossible to not fit given u8.
             let value = ArrayString::<[u8; 254]>::from(value).unwrap();
@@ -93,33 +120,124 @@ impl Attribute {
 }
 
 impl Identity {
-    /// Conveniently construct a new identity. It is also possible to directly construct this object.
+    /// Conveniently construct a new identity for a single attribute. It is also possible to directly construct this object.
     ///
     /// Throws a ConstraintViolation when the attribute or identity strings are too long.
     pub fn new(timestamp: u64, atype: &str, value: Option<&str>) -> Result<Identity, Error> {
+        Identity::conjunction(timestamp, &[Attribute::new(atype, value)?])
+    }
+
+    /// Construct a new identity for a conjunction of attributes, in canonical order.
+    ///
+    /// Throws a ConstraintViolation when no attributes, more than `MAX_CONJUNCTION` attributes,
+    /// or multiple attributes of the same type are given.
+    pub fn conjunction(timestamp: u64, attributes: &[Attribute]) -> Result<Identity, Error> {
+        if attributes.is_empty() || attributes.len() > MAX_CONJUNCTION {
+            return Err(Error::ConstraintViolation);
+        }
+
+        let mut attributes: Conjunction = attributes.iter().cloned().collect();
+        attributes.sort_unstable_by_key(|a| a.atype);
+
+        if !is_canonical(&attributes) {
+            return Err(Error::ConstraintViolation);
+        }
+
         Ok(Identity {
             timestamp,
-            attribute: Attribute::new(atype, value)?,
+            attributes,
         })
     }
 
+    /// Construct a new identity for a conjunction of attributes, of which the timestamp is the start
+    /// of the timeframe of `max_age` seconds, as advertised by the PKG in its `parameters`, that contains `timestamp`.
+    ///
+    /// All identities for the same attributes within a timeframe are equal, such that their user secret key can be reused.
+    /// Throws a ConstraintViolation when `max_age` is zero, or as `Identity::conjunction`.
+    pub fn aligned(
+        parameters: &Parameters,
+        timestamp: u64,
+        attributes: &[Attribute],
+    ) -> Result<Identity, Error> {
+        let start = timeframe(timestamp, parameters.max_age)?.start;
+        Identity::conjunction(start, attributes)
+    }
+
+    /// The window of UNIX timestamps, in seconds, in which this identity is valid.
+    ///
+    /// Throws a ConstraintViolation when the `max_age` in `parameters` is zero.
+    pub fn timeframe(&self, parameters: &Parameters) -> Result<Range<u64>, Error> {
+        timeframe(self.timestamp, parameters.max_age)
+    }
+
     /// Write the byte representation of this identity as a bytestream.
     pub fn write_to<W: Writable>(&self, w: &mut W) -> Result<(), Error> {
+        if self.attributes.is_empty() {
+            return Err(Error::ConstraintViolation);
+        }
+
         w.write(&self.timestamp.to_be_bytes())?;
-        self.attribute.write_to(w)
+
+        // Bounded by MAX_CONJUNCTION, thus always fits.
+        w.write(&[self.attributes.len() as u8])?;
+        for a in self.attributes.iter() {
+            a.write_to(w)?;
+        }
+
+        Ok(())
     }
 
     /// Construct an identity from a bytestream.
+    ///
+    /// Throws a FormatViolation if the attributes are not in canonical order.
     pub fn read_from<R: Readable>(r: &mut R) -> Result<Identity, Error> {
-        let timestamp = r.read_bytes(8)?;
+        let timestamp = r.read_bytes_strict(8)?;
         let timestamp = u64::from_be_bytes(*array_ref![timestamp, 0, 8]);
 
+        let count = usize::from(r.read_byte()?);
+        if count == 0 || count > MAX_CONJUNCTION {
+            return Err(Error::FormatViolation);
+        }
+
+        let mut attributes = Conjunction::new();
+        for _ in 0..count {
+            attributes.push(Attribute::read_from(r)?);
+        }
+
+        if !is_canonical(&attributes) {
+            return Err(Error::FormatViolation);
+        }
+
         Ok(Identity {
             timestamp,
-            attribute: Attribute::read_from(r)?,
+            attributes,
         })
     }
 
+    /// Reconstruct the full identity from a hint as read from the header, using the argument
+    /// attributes for the values that were omitted from the hint.
+    ///
+    /// Throws a ConstraintViolation if a value is omitted from both, or if they differ.
+    pub fn reconstruct(&self, values: &[Attribute]) -> Result<Identity, Error> {
+        let mut res = self.clone();
+
+        for a in res.attributes.iter_mut() {
+            let given = values
+                .iter()
+                .find(|v| v.atype == a.atype)
+                .and_then(|v| v.value);
+
+            match (a.value, given) {
+                (Some(h), Some(v)) if h != v => return Err(Error::ConstraintViolation),
+                (Some(_), _) => {}
+                (None, Some(v)) => a.value = Some(v),
+                (None, None) => return Err(Error::ConstraintViolation),
+            }
+        }
+
+        Ok(res)
+    }
+
     /// Derive the corresponding Waters identity in a deterministic way.
     /// Uses `self.write_to` and `ibe::kiltz_vahlis_one::Identity:derive` internally.
     pub fn derive(&self) -> ibe::kiltz_vahlis_one::Identity {
@@ -129,6 +247,57 @@ impl Identity {
     }
 }
 
+impl Recipient {
+    /// A recipient of which the full identity is written in the header.
+    pub fn new(identity: Identity) -> Recipient {
+        Recipient {
+            identity,
+            hidden: false,
+        }
+    }
+
+    /// A recipient of which the attribute values are omitted from the header, though not hidden from
+    /// anyone guessing them, see `Recipient`.
+    pub fn hidden(identity: Identity) -> Recipient {
+        Recipient {
+            identity,
+            hidden: true,
+        }
+    }
+
+    /// The identity as written in the header.
+    pub fn hint(&self) -> Identity {
+        let mut res = self.identity.clone();
+        if self.hidden {
+            for a in res.attributes.iter_mut() {
+                a.value = None;
+            }
+        }
+        res
+    }
+}
+
+impl From<Identity> for Recipient {
+    fn from(identity: Identity) -> Recipient {
+        Recipient::new(identity)
+    }
+}
+
+/// The timeframe of `max_age` seconds that contains `timestamp`.
+pub(crate) fn timeframe(timestamp: u64, max_age: u64) -> Result<Range<u64>, Error> {
+    if max_age == 0 {
+        return Err(Error::ConstraintViolation);
+    }
+
+    let start = timestamp - timestamp % max_age;
+    Ok(start..start.saturating_add(max_age))
+}
+
+/// Whether the attributes are strictly ordered by type, such that every type is unique.
+fn is_canonical(attributes: &[Attribute]) -> bool {
+    attributes.windows(2).all(|w| w[0].atype < w[1].atype)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -151,4 +320,72 @@ mod tests {
 
         assert_eq!(i, i2);
     }
+
+    #[test]
+    fn conjunction() {
+        let email = Attribute::new("pbdf.pbdf.email.email", Some("w.geraedts@sarif.nl")).unwrap();
+        let big = Attribute::new("pbdf.pbdf.big.profession", Some("Arts")).unwrap();
+
+        // Canonically ordered, regardless of the order given.
+        let i = Identity::conjunction(1566722350, &[email.clone(), big.clone()]).unwrap();
+        let j = Identity::conjunction(1566722350, &[big.clone(), email.clone()]).unwrap();
+        assert_eq!(i, j);
+        assert_eq!(i.attributes.as_slice(), &[big.clone(), email.clone()]);
+
+        let mut buf = IdentityBuf::new();
+        i.write_to(&mut buf).unwrap();
+        assert_eq!(Identity::read_from(&mut SliceReader::new(&buf)).unwrap(), i);
+
+        // A subset of the policy is a different identity.
+        let mut other = IdentityBuf::new();
+        Identity::conjunction(1566722350, core::slice::from_ref(&email))
+            .unwrap()
+            .write_to(&mut other)
+            .unwrap();
+        assert_ne!(buf, other);
+
+        assert!(matches!(
+            Identity::conjunction(1566722350, &[]),
+            Err(Error::ConstraintViolation)
+        ));
+        assert!(matches!(
+            Identity::conjunction(1566722350, &[email.clone(), email.clone()]),
+            Err(Error::ConstraintViolation)
+        ));
+
+        // Not in canonical order.
+        let mut unordered = i.clone();
+        unordered.attributes.swap(0, 1);
+        let mut buf = IdentityBuf::new();
+        unordered.write_to(&mut buf).unwrap();
+        assert!(matches!(
+            Identity::read_from(&mut SliceReader::new(&buf)),
+            Err(Error::FormatViolation)
+        ));
+    }
+
+    #[test]
+    fn hidden_value() {
+        let email = Attribute::new("pbdf.pbdf.email.email", Some("w.geraedts@sarif.nl")).unwrap();
+        let bsn = Attribute::new("pbdf.gemeente.personalData.bsn", Some("123456789")).unwrap();
+        let i = Identity::conjunction(1566722350, &[email.clone(), bsn.clone()]).unwrap();
+
+        let hint = Recipient::hidden(i.clone()).hint();
+        assert!(hint.attributes.iter().all(|a| a.value.is_none()));
+        assert_eq!(hint.timestamp, i.timestamp);
+        assert_eq!(Recipient::new(i.clone()).hint(), i);
+
+        assert_eq!(hint.reconstruct(&[bsn.clone(), email.clone()]).unwrap(), i);
+        assert_eq!(i.reconstruct(&[]).unwrap(), i);
+        assert!(matches!(
+            i.reconstruct(&[
+                Attribute::new("pbdf.gemeente.personalData.bsn", Some("987654321")).unwrap()
+            ]),
+            Err(Error::ConstraintViolation)
+        ));
+        assert!(matches!(
+            hint.reconstruct(&[bsn]),
+            Err(Error::ConstraintViolation)
+        ));
+    }
 }
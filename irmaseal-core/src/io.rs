@@ -0,0 +1,147 @@
+//! Adapters between `std::io` and the IRMAseal `Readable` and `Writable` traits.
+//!
+//! Also enables the `Sealer` to be used as a `std::io::Write`, and the `OpenerUnsealed` as a `std::io::Read`.
+
+use crate::*;
+use std::fmt::{Display, Formatter};
+use std::io::{ErrorKind, Read, Seek, SeekFrom, Write};
+use std::vec::Vec;
+
+/// Readable resource for any `std::io::Read`, for example a `std::fs::File`.
+pub struct IoReader<R: Read> {
+    r: R,
+    buf: Vec<u8>,
+}
+
+/// Writable resource for any `std::io::Write`, for example a `std::fs::File`.
+pub struct IoWriter<W: Write> {
+    w: W,
+}
+
+impl<R: Read> IoReader<R> {
+    pub fn new(r: R) -> IoReader<R> {
+        IoReader { r, buf: Vec::new() }
+    }
+
+    /// Yield the underlying reader.
+    pub fn into_inner(self) -> R {
+        self.r
+    }
+}
+
+impl<R: Read> Readable for IoReader<R> {
+    fn read_byte(&mut self) -> Result<u8, Error> {
+        match self.read_bytes(1)? {
+            [b] => Ok(*b),
+            _ => Err(Error::EndOfStream),
+        }
+    }
+
+    fn read_bytes(&mut self, n: usize) -> Result<&[u8], Error> {
+        if self.buf.len() < n {
+            self.buf.resize(n, 0u8);
+        }
+
+        // A single read may yield fewer bytes than available, thus read until `n` bytes or the end.
+        let dst = &mut self.buf[0..n];
+        let mut len = 0;
+        while len < n {
+            match self.r.read(&mut dst[len..]) {
+                Ok(0) => break,
+                Ok(read) => len += read,
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e.into()),
+            }
+        }
+
+        Ok(&dst[0..len])
+    }
+}
+
+impl<R: Read + Seek> Seekable for IoReader<R> {
+    fn seek(&mut self, pos: u64) -> Result<(), Error> {
+        self.r.seek(SeekFrom::Start(pos))?;
+        Ok(())
+    }
+
+    fn stream_len(&mut self) -> Result<u64, Error> {
+        let pos = self.r.stream_position()?;
+        let len = self.r.seek(SeekFrom::End(0))?;
+        self.r.seek(SeekFrom::Start(pos))?;
+        Ok(len)
+    }
+}
+
+impl<W: Write> IoWriter<W> {
+    pub fn new(w: W) -> IoWriter<W> {
+        IoWriter { w }
+    }
+
+    /// Yield the underlying writer.
+    pub fn into_inner(self) -> W {
+        self.w
+    }
+}
+
+impl<W: Write> Writable for IoWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> Result<(), Error> {
+        self.w.write_all(buf)?;
+        Ok(())
+    }
+}
+
+impl Writable for Vec<u8> {
+    fn write(&mut self, buf: &[u8]) -> Result<(), Error> {
+        self.extend_from_slice(buf);
+        Ok(())
+    }
+}
+
+impl From<std::io::Error> for Error {
+    fn from(e: std::io::Error) -> Self {
+        Error::Io(e)
+    }
+}
+
+/// Convert into an I/O error, yielding the original error if it came from the underlying resource.
+impl From<Error> for std::io::Error {
+    fn from(e: Error) -> Self {
+        let kind = match e {
+            Error::Io(e) => return e,
+            Error::EndOfStream | Error::PrematureEndError => ErrorKind::UnexpectedEof,
+            Error::NotIRMASEAL
+            | Error::IncorrectVersion
+            | Error::FormatViolation
+            | Error::IntegrityViolation => ErrorKind::InvalidData,
+            _ => ErrorKind::Other,
+        };
+
+        std::io::Error::new(kind, e)
+    }
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        match self {
+            Error::NotIRMASEAL => write!(f, "not an IRMAseal bytestream"),
+            Error::IncorrectVersion => write!(f, "incorrect format version"),
+            Error::ConstraintViolation => write!(f, "constraint violation"),
+            Error::FormatViolation => write!(f, "format violation"),
+            Error::UpstreamWritableError => write!(f, "upstream writable error"),
+            Error::EndOfStream => write!(f, "end of stream"),
+            Error::PrematureEndError => write!(f, "premature end of stream"),
+            Error::UnknownRecipient => write!(f, "unknown recipient"),
+            Error::IntegrityViolation => write!(f, "integrity violation"),
+            Error::Io(e) => write!(f, "I/O error: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
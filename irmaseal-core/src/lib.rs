@@ -1,16 +1,37 @@
 #![no_std]
 
+#[cfg(feature = "std")]
+extern crate std;
+
 mod artifacts;
 mod identity;
+mod metadata;
 
 pub mod util;
 pub mod api;
+pub mod armor;
 
 #[cfg(feature = "stream")]
 pub mod stream;
 
+#[cfg(feature = "stream")]
+mod delivery;
+
+#[cfg(feature = "stream")]
+pub mod shamir;
+
+#[cfg(feature = "stream")]
+pub mod threshold;
+
+#[cfg(feature = "std")]
+pub mod io;
+
 pub use artifacts::*;
 pub use identity::*;
+pub use metadata::*;
+
+#[cfg(feature = "stream")]
+pub use delivery::*;
 
 #[derive(Debug)]
 pub enum Error {
@@ -21,6 +42,10 @@ pub enum Error {
     UpstreamWritableError,
     EndOfStream,
     PrematureEndError,
+    UnknownRecipient,
+    IntegrityViolation,
+    #[cfg(feature = "std")]
+    Io(std::io::Error),
 }
 
 /// A writable resource that accepts chunks of a bytestream.
@@ -29,6 +54,12 @@ pub trait Writable {
     fn write(&mut self, buf: &[u8]) -> Result<(), Error>;
 }
 
+impl<W: Writable + ?Sized> Writable for &mut W {
+    fn write(&mut self, buf: &[u8]) -> Result<(), Error> {
+        (**self).write(buf)
+    }
+}
+
 /// A readable resource that yields chunks of a bytestream.
 pub trait Readable {
     /// Read exactly one byte. Will throw `Error::EndOfStream` if that byte
@@ -49,3 +80,22 @@ pub trait Readable {
         }
     }
 }
+
+impl<R: Readable + ?Sized> Readable for &mut R {
+    fn read_byte(&mut self) -> Result<u8, Error> {
+        (**self).read_byte()
+    }
+
+    fn read_bytes(&mut self, n: usize) -> Result<&[u8], Error> {
+        (**self).read_bytes(n)
+    }
+}
+
+/// A readable resource that can be repositioned, enabling random access.
+pub trait Seekable: Readable {
+    /// Move to the absolute position `pos`, counted in bytes from the start of the bytestream.
+    fn seek(&mut self, pos: u64) -> Result<(), Error>;
+
+    /// The total length of the bytestream in bytes.
+    fn stream_len(&mut self) -> Result<u64, Error>;
+}
@@ -0,0 +1,216 @@
+use super::{Error, Readable, Writable};
+use arrayvec::ArrayVec;
+
+/// The maximum size in bytes of a single metadata section, including the TLV headers of all entries.
+pub const MAX_METADATASIZE: usize = 1024;
+
+/// The original name of the file, as UTF-8 without any directory components.
+pub const METADATA_FILENAME: u8 = 0x01;
+
+/// The MIME content type of the plaintext, as UTF-8.
+pub const METADATA_CONTENT_TYPE: u8 = 0x02;
+
+/// The size of the plaintext in bytes, as 64-bit big endian.
+pub const METADATA_SIZE: u8 = 0x03;
+
+/// The creation time of the plaintext in seconds since the UNIX epoch, as 64-bit big endian.
+pub const METADATA_CREATED: u8 = 0x04;
+
+/// The modification time of the plaintext in seconds since the UNIX epoch, as 64-bit big endian.
+pub const METADATA_MODIFIED: u8 = 0x05;
+
+// Tag (1) and length (2) of a single entry.
+const ENTRY_HEADERSIZE: usize = 3;
+
+type MetadataBuf = ArrayVec<[u8; MAX_METADATASIZE]>;
+
+/// An extensible list of metadata entries, each consisting of a tag and a value of at most `MAX_METADATASIZE` bytes.
+///
+/// The well-known tags are the `METADATA_*` constants. Entries with unknown tags are retained as is.
+#[derive(Debug, Default, PartialEq, Clone)]
+pub struct Metadata {
+    buf: MetadataBuf,
+}
+
+impl Metadata {
+    pub fn new() -> Metadata {
+        Metadata::default()
+    }
+
+    /// Append an entry.
+    ///
+    /// Throws a ConstraintViolation if the section would exceed `MAX_METADATASIZE` bytes.
+    pub fn push(&mut self, tag: u8, value: &[u8]) -> Result<(), Error> {
+        use core::convert::TryFrom;
+
+        if self.buf.len() + ENTRY_HEADERSIZE + value.len() > MAX_METADATASIZE {
+            return Err(Error::ConstraintViolation);
+        }
+
+        // Bounded by MAX_METADATASIZE, thus always fits.
+        let len = u16::try_from(value.len()).unwrap();
+
+        self.buf.write(&[tag])?;
+        self.buf.write(&len.to_be_bytes())?;
+        self.buf.write(value)
+    }
+
+    /// Append an entry with a 64-bit big endian value.
+    pub fn push_u64(&mut self, tag: u8, value: u64) -> Result<(), Error> {
+        self.push(tag, &value.to_be_bytes())
+    }
+
+    /// The value of the first entry with the argument tag.
+    pub fn get(&self, tag: u8) -> Option<&[u8]> {
+        self.iter().find(|(t, _)| *t == tag).map(|(_, v)| v)
+    }
+
+    /// The value of the first entry with the argument tag, interpreted as UTF-8.
+    pub fn get_str(&self, tag: u8) -> Option<&str> {
+        self.get(tag).and_then(|v| core::str::from_utf8(v).ok())
+    }
+
+    /// The value of the first entry with the argument tag, interpreted as 64-bit big endian.
+    pub fn get_u64(&self, tag: u8) -> Option<u64> {
+        use core::convert::TryInto;
+
+        self.get(tag)
+            .and_then(|v| v.try_into().ok())
+            .map(u64::from_be_bytes)
+    }
+
+    /// Iterate over all entries as `(tag, value)`, in the order in which they were pushed.
+    pub fn iter(&self) -> MetadataIter<'_> {
+        MetadataIter { buf: &self.buf }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.buf.is_empty()
+    }
+
+    /// Write the byte representation of this section as a bytestream.
+    pub fn write_to<W: Writable>(&self, w: &mut W) -> Result<(), Error> {
+        // Bounded by MAX_METADATASIZE, thus always fits.
+        w.write(&(self.buf.len() as u16).to_be_bytes())?;
+        w.write(&self.buf)
+    }
+
+    /// Construct a section from a bytestream.
+    ///
+    /// Throws a FormatViolation if the section is too large or its entries are malformed.
+    pub fn read_from<R: Readable>(r: &mut R) -> Result<Metadata, Error> {
+        let res = Metadata::read_raw(r)?;
+        res.validate()?;
+        Ok(res)
+    }
+
+    /// Read a section as written by `write_to`, without interpreting its entries.
+    pub(crate) fn read_raw<R: Readable>(r: &mut R) -> Result<Metadata, Error> {
+        let len = r.read_bytes_strict(2)?;
+        let len = usize::from(u16::from_be_bytes([len[0], len[1]]));
+
+        if len > MAX_METADATASIZE {
+            return Err(Error::FormatViolation);
+        }
+
+        let mut res = Metadata::new();
+        res.buf.write(r.read_bytes_strict(len)?)?;
+        Ok(res)
+    }
+
+    /// Check that the section consists of well-formed entries.
+    pub(crate) fn validate(&self) -> Result<(), Error> {
+        let mut rest = self.buf.as_slice();
+        while !rest.is_empty() {
+            if rest.len() < ENTRY_HEADERSIZE {
+                return Err(Error::FormatViolation);
+            }
+
+            let len = usize::from(u16::from_be_bytes([rest[1], rest[2]]));
+            rest = rest
+                .get(ENTRY_HEADERSIZE + len..)
+                .ok_or(Error::FormatViolation)?;
+        }
+
+        Ok(())
+    }
+
+    /// The concatenation of the entries, which may be encrypted in place.
+    #[cfg(feature = "stream")]
+    pub(crate) fn as_bytes_mut(&mut self) -> &mut [u8] {
+        &mut self.buf
+    }
+}
+
+/// Iterator over the entries of a `Metadata` section.
+pub struct MetadataIter<'a> {
+    buf: &'a [u8],
+}
+
+impl<'a> Iterator for MetadataIter<'a> {
+    type Item = (u8, &'a [u8]);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        // The entries have been validated upon construction.
+        if self.buf.is_empty() {
+            return None;
+        }
+
+        let tag = self.buf[0];
+        let len = usize::from(u16::from_be_bytes([self.buf[1], self.buf[2]]));
+        let (value, rest) = self.buf[ENTRY_HEADERSIZE..].split_at(len);
+        self.buf = rest;
+
+        Some((tag, value))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::util::SliceReader;
+    use crate::*;
+
+    #[test]
+    fn reflection() {
+        let mut m = Metadata::new();
+        m.push(METADATA_FILENAME, b"report.pdf").unwrap();
+        m.push_u64(METADATA_SIZE, 1234).unwrap();
+        m.push(0xEE, b"").unwrap();
+
+        let mut buf = arrayvec::ArrayVec::<[u8; 2048]>::new();
+        m.write_to(&mut buf).unwrap();
+
+        let m2 = Metadata::read_from(&mut SliceReader::new(&buf)).unwrap();
+        assert_eq!(m, m2);
+        assert_eq!(m2.get_str(METADATA_FILENAME), Some("report.pdf"));
+        assert_eq!(m2.get_u64(METADATA_SIZE), Some(1234));
+        assert_eq!(m2.get(0xEE), Some(&b""[..]));
+        assert_eq!(m2.get(METADATA_CONTENT_TYPE), None);
+        assert_eq!(m2.iter().count(), 3);
+    }
+
+    #[test]
+    fn bounds() {
+        let mut m = Metadata::new();
+        m.push(METADATA_FILENAME, &[0u8; MAX_METADATASIZE - 3])
+            .unwrap();
+        assert!(matches!(
+            m.push(METADATA_SIZE, &[]),
+            Err(Error::ConstraintViolation)
+        ));
+
+        // Entry exceeding the section.
+        let buf = [0x00, 0x04, METADATA_FILENAME, 0x00, 0x02, b'a'];
+        assert!(matches!(
+            Metadata::read_from(&mut SliceReader::new(&buf)),
+            Err(Error::FormatViolation)
+        ));
+
+        // Section exceeding the maximum size.
+        let buf = [0x04, 0x01];
+        assert!(matches!(
+            Metadata::read_from(&mut SliceReader::new(&buf)),
+            Err(Error::FormatViolation)
+        ));
+    }
+}
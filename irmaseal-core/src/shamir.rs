@@ -0,0 +1,184 @@
+//! Shamir secret sharing of byte strings over GF(256).
+//!
+//! Every byte of the secret is shared separately, using a random polynomial of degree `threshold - 1`
+//! of which the constant term is that byte. A share is the evaluation of these polynomials at the
+//! index of the share, starting at 1, and is thus as long as the secret.
+//!
+//! With a threshold of 1 every share equals the secret.
+
+use rand::{CryptoRng, Rng};
+
+use crate::*;
+
+/// The maximum number of shares, as the index of a share is a non-zero element of GF(256).
+pub const MAX_SHARES: usize = 255;
+
+/// Multiply in GF(256), modulo the AES polynomial x^8 + x^4 + x^3 + x + 1, without branching on secrets.
+fn mul(mut a: u8, mut b: u8) -> u8 {
+    let mut res = 0u8;
+    for _ in 0..8 {
+        res ^= a & 0u8.wrapping_sub(b & 1);
+        a = (a << 1) ^ (0x1b & 0u8.wrapping_sub(a >> 7));
+        b >>= 1;
+    }
+    res
+}
+
+/// Invert a non-zero element of GF(256), as a^254.
+fn inv(a: u8) -> u8 {
+    let mut res = 1u8;
+    let mut base = a;
+    let mut e = 254u8;
+    while e > 0 {
+        if e & 1 == 1 {
+            res = mul(res, base);
+        }
+        base = mul(base, base);
+        e >>= 1;
+    }
+    res
+}
+
+/// Split `secret` into `shares.len()` shares, of which any `threshold` reconstruct the secret.
+/// The share at position `i` has index `i + 1`.
+///
+/// Throws a ConstraintViolation if the threshold is zero or exceeds the number of shares, if more than
+/// `MAX_SHARES` shares are requested, or if a share is not as long as the secret.
+pub fn split<R: Rng + CryptoRng>(
+    secret: &[u8],
+    threshold: u8,
+    shares: &mut [&mut [u8]],
+    rng: &mut R,
+) -> Result<(), Error> {
+    let threshold = usize::from(threshold);
+    if threshold == 0
+        || threshold > shares.len()
+        || shares.len() > MAX_SHARES
+        || shares.iter().any(|s| s.len() != secret.len())
+    {
+        return Err(Error::ConstraintViolation);
+    }
+
+    let mut coefficients = [0u8; MAX_SHARES - 1];
+    let coefficients = &mut coefficients[..threshold - 1];
+
+    for (j, byte) in secret.iter().enumerate() {
+        rng.fill_bytes(coefficients);
+
+        for (i, share) in shares.iter_mut().enumerate() {
+            // Bounded by MAX_SHARES, thus always fits.
+            let x = (i + 1) as u8;
+            let y = coefficients
+                .iter()
+                .rev()
+                .fold(0u8, |acc, c| mul(acc, x) ^ c);
+
+            share[j] = mul(y, x) ^ byte;
+        }
+    }
+
+    Ok(())
+}
+
+/// Reconstruct the secret from shares, given with their index, into `secret`.
+///
+/// Yields the secret if at least the threshold of shares is given, and garbage otherwise.
+/// Throws a ConstraintViolation if no shares are given, if an index is zero or occurs more than once,
+/// or if a share is not as long as the secret.
+pub fn combine(shares: &[(u8, &[u8])], secret: &mut [u8]) -> Result<(), Error> {
+    if shares.is_empty() || shares.iter().any(|(_, s)| s.len() != secret.len()) {
+        return Err(Error::ConstraintViolation);
+    }
+
+    for b in secret.iter_mut() {
+        *b = 0;
+    }
+
+    for (i, (xi, share)) in shares.iter().enumerate() {
+        if *xi == 0 || shares[..i].iter().any(|(xj, _)| xj == xi) {
+            return Err(Error::ConstraintViolation);
+        }
+
+        // The Lagrange coefficient to interpolate at zero, where subtraction is addition.
+        let l = shares
+            .iter()
+            .filter(|(xj, _)| xj != xi)
+            .fold(1u8, |acc, (xj, _)| mul(acc, mul(*xj, inv(xj ^ xi))));
+
+        for (b, y) in secret.iter_mut().zip(share.iter()) {
+            *b ^= mul(l, *y);
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::RngCore;
+
+    #[test]
+    fn field() {
+        assert_eq!(mul(0x57, 0x83), 0xc1);
+        for a in 1..=255u8 {
+            assert_eq!(mul(a, inv(a)), 1);
+        }
+    }
+
+    #[test]
+    fn reflection() {
+        let mut rng = rand::thread_rng();
+        let mut secret = [0u8; 32];
+        rng.fill_bytes(&mut secret);
+
+        let mut shares = [[0u8; 32]; 5];
+        {
+            let mut refs: [&mut [u8]; 5] = {
+                let [a, b, c, d, e] = &mut shares;
+                [a, b, c, d, e]
+            };
+            split(&secret, 3, &mut refs, &mut rng).unwrap();
+        }
+
+        let mut res = [0u8; 32];
+        for &(a, b, c) in [(1u8, 2u8, 3u8), (5, 1, 3), (2, 4, 5)].iter() {
+            let given = [
+                (a, &shares[a as usize - 1][..]),
+                (b, &shares[b as usize - 1][..]),
+                (c, &shares[c as usize - 1][..]),
+            ];
+            combine(&given, &mut res).unwrap();
+            assert_eq!(res, secret);
+        }
+
+        // Fewer shares than the threshold yield something else.
+        combine(&[(1, &shares[0][..]), (2, &shares[1][..])], &mut res).unwrap();
+        assert_ne!(res, secret);
+
+        assert!(matches!(
+            combine(&[(1, &shares[0][..]), (1, &shares[0][..])], &mut res),
+            Err(Error::ConstraintViolation)
+        ));
+        assert!(matches!(
+            combine(&[(0, &shares[0][..])], &mut res),
+            Err(Error::ConstraintViolation)
+        ));
+    }
+
+    #[test]
+    fn single_threshold() {
+        let mut rng = rand::thread_rng();
+        let secret = [0xAAu8; 16];
+
+        let mut a = [0u8; 16];
+        let mut b = [0u8; 16];
+        split(&secret, 1, &mut [&mut a, &mut b], &mut rng).unwrap();
+        assert_eq!(a, secret);
+        assert_eq!(b, secret);
+
+        assert!(split(&secret, 0, &mut [&mut a, &mut b], &mut rng).is_err());
+        assert!(split(&secret, 3, &mut [&mut a, &mut b], &mut rng).is_err());
+        assert!(split(&secret, 1, &mut [&mut a[..15]], &mut rng).is_err());
+    }
+}
@@ -0,0 +1,332 @@
+//! Sealer and opener for `futures::io::AsyncWrite` and `futures::io::AsyncRead` resources.
+//!
+//! Produces and accepts exactly the same bytestreams as `Sealer` and `OpenerSealed`.
+//! For tokio resources, use the compatibility layer of `tokio-util`.
+
+use arrayref::array_ref;
+use arrayvec::ArrayVec;
+use core::ops::Range;
+use futures::future::Future;
+use futures::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ErrorKind};
+use rand::{CryptoRng, Rng};
+use std::vec::Vec;
+
+use crate::api::Parameters;
+use crate::stream::opener::{read_header, unseal_header, Header, HeaderBuf, SegmentOpener};
+use crate::stream::sealer::{seal_header, single_pkg, SegmentSealer};
+use crate::stream::util::ArchiveReader;
+use crate::stream::*;
+use crate::util::SliceReader;
+use crate::*;
+
+/// The number of bytes requested at once while reading the header.
+const HEADERCHUNKSIZE: usize = 1024;
+
+/// Sealer for an asynchronous bytestream, which converts it into an IRMAseal encrypted bytestream.
+///
+/// The stream must be completed with `finish`, which writes the final segment.
+pub struct AsyncSealer<W: AsyncWrite + Unpin> {
+    segments: SegmentSealer,
+    buf: [u8; SEGMENTSIZE],
+    len: usize,
+    w: W,
+}
+
+/// First stage opener of an asynchronous IRMAseal encrypted bytestream.
+/// See `OpenerSealed`.
+pub struct AsyncOpenerSealed<R: AsyncRead + Unpin> {
+    headerbuf: ArrayVec<HeaderBuf>,
+    mac: [u8; MACSIZE],
+    header: Header,
+    leftover: Vec<u8>,
+    r: R,
+}
+
+/// Second stage opener of an asynchronous IRMAseal encrypted bytestream.
+/// See `OpenerUnsealed`.
+pub struct AsyncOpenerUnsealed<R: AsyncRead + Unpin> {
+    segments: SegmentOpener,
+    metadata: Metadata,
+    finished: bool,
+    leftover: Vec<u8>,
+    r: R,
+    buf: [u8; SEGMENTSIZE + TAGSIZE],
+}
+
+impl<W: AsyncWrite + Unpin> AsyncSealer<W> {
+    /// Start sealing a bytestream for one or more recipients, see `Sealer::new`.
+    ///
+    /// The header is sealed before this function returns, such that the yielded future does not
+    /// hold on to `rng`. The future writes the header.
+    pub fn new<R: Rng + CryptoRng>(
+        is: &[Recipient],
+        pk: &PublicKey,
+        rng: &mut R,
+        w: W,
+    ) -> impl Future<Output = Result<AsyncSealer<W>, Error>> {
+        AsyncSealer::with_metadata(is, pk, &Metadata::new(), &Metadata::new(), rng, w)
+    }
+
+    /// Start sealing a bytestream including metadata in the header, see `Sealer::with_metadata`.
+    pub fn with_metadata<R: Rng + CryptoRng>(
+        is: &[Recipient],
+        pk: &PublicKey,
+        public: &Metadata,
+        private: &Metadata,
+        rng: &mut R,
+        w: W,
+    ) -> impl Future<Output = Result<AsyncSealer<W>, Error>> {
+        let mut header = Vec::new();
+        let segments = single_pkg(is, pk)
+            .and_then(|is| seal_header(1, &is, public, private, rng, &mut header));
+
+        AsyncSealer::start(segments, header, w)
+    }
+
+    /// Start sealing a bytestream for recipients under possibly distinct PKGs, see `Sealer::with_pkgs`.
+    pub fn with_pkgs<R: Rng + CryptoRng>(
+        threshold: u8,
+        is: &[(&PublicKey, &Recipient)],
+        public: &Metadata,
+        private: &Metadata,
+        rng: &mut R,
+        w: W,
+    ) -> impl Future<Output = Result<AsyncSealer<W>, Error>> {
+        let mut header = Vec::new();
+        let segments = seal_header(threshold, is, public, private, rng, &mut header);
+
+        AsyncSealer::start(segments, header, w)
+    }
+
+    /// Write the sealed header, and yield the sealer for the body.
+    async fn start(
+        segments: Result<SegmentSealer, Error>,
+        header: Vec<u8>,
+        mut w: W,
+    ) -> Result<AsyncSealer<W>, Error> {
+        let segments = segments?;
+        w.write_all(&header).await?;
+
+        Ok(AsyncSealer {
+            segments,
+            buf: [0u8; SEGMENTSIZE],
+            len: 0,
+            w,
+        })
+    }
+
+    /// Seal the argument slice. Needs to consume the entire slice.
+    pub async fn write(&mut self, mut buf: &[u8]) -> Result<(), Error> {
+        while !buf.is_empty() {
+            let n = core::cmp::min(SEGMENTSIZE - self.len, buf.len());
+            self.buf[self.len..self.len + n].copy_from_slice(&buf[..n]);
+            self.len += n;
+            buf = &buf[n..];
+
+            if self.len == SEGMENTSIZE {
+                self.flush_segment(false).await?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Write the final segment, flush and yield the underlying writer.
+    pub async fn finish(mut self) -> Result<W, Error> {
+        self.flush_segment(true).await?;
+        self.w.flush().await?;
+
+        Ok(self.w)
+    }
+
+    async fn flush_segment(&mut self, last: bool) -> Result<(), Error> {
+        let segment = &mut self.buf[..self.len];
+        let tag = self.segments.seal(segment, last)?;
+
+        self.w.write_all(segment).await?;
+        self.w.write_all(&tag).await?;
+        self.len = 0;
+
+        Ok(())
+    }
+}
+
+impl<R: AsyncRead + Unpin> AsyncOpenerSealed<R> {
+    /// Starts interpreting an asynchronous bytestream as an IRMAseal stream, see `OpenerSealed::new`.
+    pub async fn new(mut r: R) -> Result<(Recipients, AsyncOpenerSealed<R>), Error> {
+        let mut buf = Vec::new();
+
+        // Retry parsing the header until enough of the bytestream has been read.
+        let (recipients, header, headerbuf) = loop {
+            let res = {
+                let mut ar = ArchiveReader::<_, HeaderBuf>::new(SliceReader::new(&buf));
+                read_header(&mut ar)
+                    .map(|(recipients, header)| (recipients, header, ar.disclose().0))
+            };
+
+            match res {
+                Ok(res) => break res,
+                Err(e @ Error::EndOfStream) | Err(e @ Error::PrematureEndError) => {
+                    if read_more(&mut r, &mut buf, HEADERCHUNKSIZE).await? == 0 {
+                        return Err(e);
+                    }
+                }
+                Err(e) => return Err(e),
+            }
+        };
+
+        let len = headerbuf.len() + MACSIZE;
+        while buf.len() < len {
+            let n = len - buf.len();
+            if read_more(&mut r, &mut buf, n).await? == 0 {
+                return Err(Error::PrematureEndError);
+            }
+        }
+
+        let mac = *array_ref![buf, headerbuf.len(), MACSIZE];
+        buf.drain(..len);
+
+        Ok((
+            recipients,
+            AsyncOpenerSealed {
+                headerbuf,
+                mac,
+                header,
+                leftover: buf,
+                r,
+            },
+        ))
+    }
+
+    /// The public metadata of the stream, see `OpenerSealed::public_metadata`.
+    pub fn public_metadata(&self) -> &Metadata {
+        self.header.public_metadata()
+    }
+
+    /// The window in which the identity of `recipient` is valid, see `OpenerSealed::timeframe`.
+    pub fn timeframe(
+        &self,
+        recipient: usize,
+        parameters: &Parameters,
+    ) -> Result<Range<u64>, Error> {
+        self.header.timeframe(recipient, parameters)
+    }
+
+    /// The number of recipients of which the user secret keys are required, see `OpenerSealed::threshold`.
+    pub fn threshold(&self) -> usize {
+        self.header.threshold()
+    }
+
+    /// The key IDs of the PKGs of the recipients, see `OpenerSealed::key_ids`.
+    pub fn key_ids(&self) -> KeyIds {
+        self.header.key_ids()
+    }
+
+    /// Will unseal the stream continuation and yield a plaintext bytestream, see `OpenerSealed::unseal`.
+    pub fn unseal(
+        self,
+        recipient: usize,
+        usk: &UserSecretKey,
+    ) -> Result<AsyncOpenerUnsealed<R>, Error> {
+        self.unseal_with(&[(recipient, usk)])
+    }
+
+    /// Will unseal the stream continuation with the user secret keys of several recipients,
+    /// see `OpenerSealed::unseal_with`.
+    pub fn unseal_with(
+        self,
+        usks: &[(usize, &UserSecretKey)],
+    ) -> Result<AsyncOpenerUnsealed<R>, Error> {
+        let (segments, metadata) = unseal_header(&self.header, usks, &self.headerbuf, &self.mac)?;
+
+        Ok(AsyncOpenerUnsealed {
+            segments,
+            metadata,
+            finished: false,
+            leftover: self.leftover,
+            r: self.r,
+            buf: [0u8; SEGMENTSIZE + TAGSIZE],
+        })
+    }
+}
+
+impl<R: AsyncRead + Unpin> AsyncOpenerUnsealed<R> {
+    /// The private metadata of the stream, which has been decrypted and authenticated.
+    pub fn metadata(&self) -> &Metadata {
+        &self.metadata
+    }
+
+    /// Read the next segment, and yield its authenticated plaintext of up to `SEGMENTSIZE` bytes.
+    ///
+    /// Will yield `Error::EndOfStream` once the final segment has been read.
+    pub async fn read(&mut self) -> Result<&[u8], Error> {
+        if self.finished {
+            return Err(Error::EndOfStream);
+        }
+
+        let len = self.fill_buf().await?;
+
+        // Only the final segment is shorter than a full segment.
+        let last = len < SEGMENTSIZE + TAGSIZE;
+        let len = self.segments.open(&mut self.buf[..len], last)?;
+        self.finished = last;
+
+        Ok(&self.buf[..len])
+    }
+
+    /// Write the entire stream to the argument writer, and flush it.
+    pub async fn write_to<W: AsyncWrite + Unpin>(&mut self, w: &mut W) -> Result<(), Error> {
+        loop {
+            match self.read().await {
+                Ok(buf) => w.write_all(buf).await?,
+                Err(Error::EndOfStream) => break,
+                Err(e) => return Err(e),
+            };
+        }
+
+        w.flush().await?;
+        Ok(())
+    }
+
+    /// Fill the buffer with the next segment, yielding the number of bytes read.
+    /// Only yields less than a full segment if the underlying stream has ended.
+    async fn fill_buf(&mut self) -> Result<usize, Error> {
+        let mut len = core::cmp::min(self.leftover.len(), self.buf.len());
+        self.buf[..len].copy_from_slice(&self.leftover[..len]);
+        self.leftover.drain(..len);
+
+        while len < self.buf.len() {
+            let n = match self.r.read(&mut self.buf[len..]).await {
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                res => res?,
+            };
+
+            if n == 0 {
+                break;
+            }
+            len += n;
+        }
+
+        Ok(len)
+    }
+}
+
+/// Append up to `n` bytes read from `r` to `buf`, yielding the number of bytes read.
+async fn read_more<R: AsyncRead + Unpin>(
+    r: &mut R,
+    buf: &mut Vec<u8>,
+    n: usize,
+) -> Result<usize, Error> {
+    let start = buf.len();
+    buf.resize(start + n, 0u8);
+
+    let res = loop {
+        match r.read(&mut buf[start..]).await {
+            Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+            res => break res,
+        }
+    };
+
+    buf.truncate(start + *res.as_ref().unwrap_or(&0));
+    Ok(res?)
+}
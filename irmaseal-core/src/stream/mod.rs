@@ -1,25 +1,50 @@
 //! Implementation of the IRMAseal stream format. Includes zero-allocation streaming encryption and decryption.
+//!
+//! The body of a stream is split into segments of `SEGMENTSIZE` bytes, which are each sealed with AES-GCM.
+//! Every chunk of plaintext yielded by the opener is thus authenticated before it is released.
 
 mod opener;
 mod sealer;
 pub(crate) mod util;
 
+#[cfg(feature = "async")]
+mod asynchronous;
+
 #[cfg(test)]
 mod tests;
 
 pub use opener::*;
 pub use sealer::*;
 
-pub(crate) type SymCrypt = cfb_mode::Cfb<aes::Aes256>;
+#[cfg(feature = "async")]
+pub use asynchronous::*;
+
+pub(crate) type SymCrypt = aes_gcm::Aes256Gcm;
 pub(crate) type Verifier = hmac::Hmac<sha3::Sha3_256>;
 
 /// The tag 'IRMASEAL' with which all IRMAseal bytestreams start.
 pub(crate) const PRELUDE: [u8; 4] = [0x14, 0x8A, 0x8E, 0xA7];
-pub(crate) const FORMAT_VERSION: u8 = 0x00;
+
+/// The version of the IRMAseal stream format produced by `sealer` and accepted by `opener`.
+pub const FORMAT_VERSION: u8 = 0x05;
 
 pub(crate) const KEYSIZE: usize = 32;
-pub(crate) const IVSIZE: usize = 16;
 pub(crate) const MACSIZE: usize = 32;
+pub(crate) const TAGSIZE: usize = 16;
+
+/// The random prefix of the AES-GCM nonce, which is completed with the segment counter and the final-segment flag.
+pub(crate) const NONCEPREFIXSIZE: usize = 7;
+pub(crate) const CIPHERTEXTSIZE: usize = 144;
+
+/// The size of the session key (AES key and HMAC key), and of the share of it that is wrapped for each recipient.
+pub(crate) const WRAPPEDKEYSIZE: usize = 2 * KEYSIZE;
+
+/// The maximum number of recipients a single IRMAseal bytestream can be sealed for, which is also the maximum threshold.
+pub const MAX_RECIPIENTS: usize = 8;
 
-/// The stack buffer size that `opener` and `sealer` will use to yield chunks of plaintext and ciphertext.
-pub const BLOCKSIZE: usize = 512;
+/// The size of the plaintext of a single segment.
+/// This is also the stack buffer size that `opener` and `sealer` will use to yield chunks of plaintext and ciphertext.
+///
+/// All segments except the final segment contain exactly this amount of plaintext,
+/// the final segment contains less (possibly none).
+pub const SEGMENTSIZE: usize = 4096;
@@ -1,137 +1,350 @@
-use crate::stream::util::ArchiveReader;
+use crate::api::Parameters;
+use crate::stream::util::{metadata_nonce, segment_nonce, ArchiveReader};
 use crate::stream::*;
 use crate::*;
 
+use aes_gcm::aead::generic_array::GenericArray;
+use aes_gcm::aead::{Aead, NewAead};
 use arrayref::array_ref;
-use cfb_mode::stream_cipher::{NewStreamCipher, StreamCipher};
+use arrayvec::ArrayVec;
+use core::ops::Range;
 use hmac::Mac;
 
+/// The identities for which an IRMAseal bytestream was sealed.
+pub type Recipients = ArrayVec<[Identity; MAX_RECIPIENTS]>;
+
+/// The key IDs of the PKGs under which the recipients of an IRMAseal bytestream were sealed.
+pub type KeyIds = ArrayVec<[KeyId; MAX_RECIPIENTS]>;
+
+// Must be at least 4+1+1+1+MAX_RECIPIENTS*(8+2053+144+64)+7+2*(2+MAX_METADATASIZE)+16 = 20234
+pub(crate) type HeaderBuf = [u8; 32768];
+
+/// The sealed session key for a single recipient, as read from the header.
+pub(crate) struct RecipientKey {
+    key_id: KeyId,
+    timestamp: u64,
+    ciphertext: [u8; CIPHERTEXTSIZE],
+    wrapped: [u8; WRAPPEDKEYSIZE],
+}
+
+pub(crate) type RecipientKeys = ArrayVec<[RecipientKey; MAX_RECIPIENTS]>;
+
 /// First stage opener of an IRMAseal encrypted bytestream.
-/// It reads the IRMAseal header, and yields the recipient Identity for which the content is intended.
+/// It reads the IRMAseal header, and yields the recipient Identities for which the content is intended.
 ///
-/// Enables the library user to lookup the UserSecretKey corresponding to this Identity before continuing.
+/// Enables the library user to lookup the UserSecretKey corresponding to one of these Identities before continuing.
 pub struct OpenerSealed<R: Readable> {
-    ar: ArchiveReader<R, [u8; 2048]>,
+    ar: ArchiveReader<R, HeaderBuf>,
+    header: Header,
+}
+
+/// The parts of the header needed to unseal a stream.
+pub(crate) struct Header {
+    threshold: usize,
+    keys: RecipientKeys,
+    nonce: [u8; NONCEPREFIXSIZE],
+    public: Metadata,
+    private: Metadata,
+    tag: [u8; TAGSIZE],
 }
 
 /// Second stage opener of an IRMAseal encrypted bytestream.
 ///
-/// **Warning**: will only validate the authenticity of the plaintext when calling `validate`.
+/// Every chunk of plaintext yielded by this opener has been authenticated.
+/// Truncation of the stream, or reordering of its segments, results in an `Error::IntegrityViolation`.
 pub struct OpenerUnsealed<R: Readable> {
-    aes: SymCrypt,
-    hmac: Verifier,
+    segments: SegmentOpener,
+    metadata: Metadata,
+    finished: bool,
+    offset: u64,
+    len: Option<u64>,
     r: R,
-    resultbuf: Option<[u8; BLOCKSIZE]>,
+    buf: [u8; SEGMENTSIZE + TAGSIZE],
+    // Range of plaintext in buf that has not yet been consumed through `std::io::Read`.
+    #[cfg(feature = "std")]
+    pending: core::ops::Range<usize>,
 }
 
-impl<R: Readable> OpenerSealed<R> {
-    /// Starts interpreting a bytestream as an IRMAseal stream.
-    /// Will immediately detect whether the bytestream actually is such a stream, and will yield
-    /// the identity for which the stream is intended, as well as the stream continuation.
-    pub fn new(r: R) -> Result<(Identity, OpenerSealed<R>), Error> {
-        let mut ar = ArchiveReader::<R, [u8; 2048]>::new(r);
+/// Opens the consecutive segments of the body of a stream.
+pub(crate) struct SegmentOpener {
+    aead: SymCrypt,
+    nonce: [u8; NONCEPREFIXSIZE],
+    counter: u32,
+}
 
-        let prelude = ar.read_bytes_strict(PRELUDE.len())?;
-        if prelude != PRELUDE {
-            return Err(Error::NotIRMASEAL);
-        }
+/// Read the IRMAseal header up to and including the metadata, but excluding its HMAC.
+///
+/// The header is read through an `ArchiveReader`, such that it can be authenticated once unsealed.
+pub(crate) fn read_header<R: Readable>(
+    ar: &mut ArchiveReader<R, HeaderBuf>,
+) -> Result<(Recipients, Header), Error> {
+    let prelude = ar.read_bytes_strict(PRELUDE.len())?;
+    if prelude != PRELUDE {
+        return Err(Error::NotIRMASEAL);
+    }
 
-        let format_version = ar.read_byte()?;
-        if format_version != FORMAT_VERSION {
-            return Err(Error::IncorrectVersion);
-        }
+    let format_version = ar.read_byte()?;
+    if format_version != FORMAT_VERSION {
+        return Err(Error::IncorrectVersion);
+    }
+
+    let count = usize::from(ar.read_byte()?);
+    let threshold = usize::from(ar.read_byte()?);
+    if count == 0 || count > MAX_RECIPIENTS || threshold == 0 || threshold > count {
+        return Err(Error::FormatViolation);
+    }
+
+    let mut recipients = Recipients::new();
+    let mut keys = RecipientKeys::new();
 
-        let i = Identity::read_from(&mut ar)?;
+    for _ in 0..count {
+        let key_id = KeyId(*array_ref![ar.read_bytes_strict(KEYIDSIZE)?, 0, KEYIDSIZE]);
+        let identity = Identity::read_from(ar)?;
+        let timestamp = identity.timestamp;
+        recipients.push(identity);
 
-        Ok((i, OpenerSealed { ar }))
+        let ciphertext = *array_ref![ar.read_bytes_strict(CIPHERTEXTSIZE)?, 0, CIPHERTEXTSIZE];
+        let wrapped = *array_ref![ar.read_bytes_strict(WRAPPEDKEYSIZE)?, 0, WRAPPEDKEYSIZE];
+
+        keys.push(RecipientKey {
+            key_id,
+            timestamp,
+            ciphertext,
+            wrapped,
+        });
     }
 
-    /// Will unseal the stream continuation and yield a plaintext bytestream.
-    pub fn unseal(mut self, usk: &UserSecretKey) -> Result<OpenerUnsealed<R>, Error> {
-        const CIPHERTEXT_SIZE: usize = 144;
-
-        let cbuf = self.ar.read_bytes_strict(CIPHERTEXT_SIZE)?;
-        let c = crate::util::open_ct(ibe::kiltz_vahlis_one::CipherText::from_bytes(array_ref![
-            cbuf,
-            0,
-            CIPHERTEXT_SIZE
-        ]))
+    let nonce = *array_ref![ar.read_bytes_strict(NONCEPREFIXSIZE)?, 0, NONCEPREFIXSIZE];
+    let public = Metadata::read_from(ar)?;
+    let private = Metadata::read_raw(ar)?;
+    let tag = *array_ref![ar.read_bytes_strict(TAGSIZE)?, 0, TAGSIZE];
+
+    Ok((
+        recipients,
+        Header {
+            threshold,
+            keys,
+            nonce,
+            public,
+            private,
+            tag,
+        },
+    ))
+}
+
+/// Unseal the shares of the session key of the given recipients with their user secret keys,
+/// authenticate the header with the HMAC `mac` and decrypt the private metadata.
+///
+/// The `headerbuf` contains all bytes read by `read_header`.
+pub(crate) fn unseal_header(
+    header: &Header,
+    usks: &[(usize, &UserSecretKey)],
+    headerbuf: &[u8],
+    mac: &[u8],
+) -> Result<(SegmentOpener, Metadata), Error> {
+    let mut shares = ArrayVec::<[(u8, [u8; WRAPPEDKEYSIZE]); MAX_RECIPIENTS]>::new();
+
+    for (recipient, usk) in usks {
+        let key = header.keys.get(*recipient).ok_or(Error::UnknownRecipient)?;
+        if shares.is_full() {
+            return Err(Error::ConstraintViolation);
+        }
+
+        let c = crate::util::open_ct(ibe::kiltz_vahlis_one::CipherText::from_bytes(
+            &key.ciphertext,
+        ))
         .ok_or(Error::FormatViolation)?;
 
         let m = ibe::kiltz_vahlis_one::decrypt(&usk.0, &c);
-        let (skey, mackey) = crate::stream::util::derive_keys(&m);
 
-        let mut hmac = Verifier::new_varkey(&mackey).unwrap();
+        // Bounded by MAX_RECIPIENTS, thus always fits.
+        let index = (*recipient + 1) as u8;
+        shares.push((index, crate::stream::util::unwrap_keys(&m, &key.wrapped)));
+    }
 
-        let (headerbuf, mut r) = self.ar.disclose();
-        hmac.input(&headerbuf);
+    if shares.len() < header.threshold {
+        return Err(Error::ConstraintViolation);
+    }
 
-        let iv = r.read_bytes_strict(IVSIZE)?;
-        let iv: &[u8; IVSIZE] = array_ref![&iv, 0, IVSIZE];
-        hmac.input(iv);
+    let shares: ArrayVec<[(u8, &[u8]); MAX_RECIPIENTS]> =
+        shares.iter().map(|(i, s)| (*i, &s[..])).collect();
+    let mut keys = [0u8; WRAPPEDKEYSIZE];
+    crate::shamir::combine(&shares, &mut keys)?;
+    let (skey, mackey) = crate::stream::util::split_keys(&keys);
 
-        let aes = SymCrypt::new(&skey.into(), &(*iv).into());
+    let mut hmac = Verifier::new_varkey(&mackey).unwrap();
+    hmac.input(headerbuf);
+    hmac.verify(mac).or(Err(Error::IntegrityViolation))?;
 
-        Ok(OpenerUnsealed {
-            aes,
-            hmac,
-            r,
-            resultbuf: None,
-        })
+    let aead = SymCrypt::new(skey.into());
+
+    let mut private = header.private.clone();
+    aead.decrypt_in_place_detached(
+        &metadata_nonce(&header.nonce),
+        &[],
+        private.as_bytes_mut(),
+        GenericArray::from_slice(&header.tag),
+    )
+    .or(Err(Error::IntegrityViolation))?;
+    private.validate()?;
+
+    let segments = SegmentOpener {
+        aead,
+        nonce: header.nonce,
+        counter: 0,
+    };
+
+    Ok((segments, private))
+}
+
+impl Header {
+    pub(crate) fn public_metadata(&self) -> &Metadata {
+        &self.public
+    }
+
+    pub(crate) fn timeframe(
+        &self,
+        recipient: usize,
+        parameters: &Parameters,
+    ) -> Result<Range<u64>, Error> {
+        let key = self.keys.get(recipient).ok_or(Error::UnknownRecipient)?;
+        crate::identity::timeframe(key.timestamp, parameters.max_age)
+    }
+
+    pub(crate) fn threshold(&self) -> usize {
+        self.threshold
+    }
+
+    pub(crate) fn key_ids(&self) -> KeyIds {
+        self.keys.iter().map(|k| k.key_id).collect()
     }
 }
 
-impl<R: Readable> OpenerUnsealed<R> {
-    /// Read up to `BLOCKSIZE` bytes at a time.
-    pub fn read(&mut self) -> Result<&[u8], Error> {
-        let (resultsize, macbuf) = match self.resultbuf.as_mut() {
-            None => (BLOCKSIZE, None),
-            Some(dst) => {
-                let mut macbuf = [0u8; MACSIZE];
-                macbuf.copy_from_slice(&dst[BLOCKSIZE - MACSIZE..BLOCKSIZE]);
-                (BLOCKSIZE - MACSIZE, Some(macbuf))
-            }
-        };
+impl SegmentOpener {
+    /// Authenticate and decrypt the next segment in place, consisting of the ciphertext followed by its tag.
+    ///
+    /// Yields the plaintext length of the segment.
+    pub(crate) fn open(&mut self, segment: &mut [u8], last: bool) -> Result<usize, Error> {
+        if segment.len() < TAGSIZE {
+            return Err(Error::IntegrityViolation);
+        }
 
-        // TODO eliminate extra check.
-        let dst = self.resultbuf.get_or_insert_with(|| [0u8; BLOCKSIZE]);
-        let src = self.r.read_bytes(resultsize)?;
-        let srcsize = src.len();
+        let (content, tag) = segment.split_at_mut(segment.len() - TAGSIZE);
+        let nonce = segment_nonce(&self.nonce, self.counter, last);
 
-        if srcsize == 0 {
-            return Err(Error::EndOfStream);
+        self.aead
+            .decrypt_in_place_detached(&nonce, &[], content, GenericArray::from_slice(tag))
+            .or(Err(Error::IntegrityViolation))?;
+
+        self.counter = self
+            .counter
+            .checked_add(1)
+            .ok_or(Error::IntegrityViolation)?;
+
+        Ok(content.len())
+    }
+}
+
+impl<R: Readable> OpenerSealed<R> {
+    /// Starts interpreting a bytestream as an IRMAseal stream.
+    /// Will immediately detect whether the bytestream actually is such a stream, and will yield
+    /// the identities for which the stream is intended, as well as the stream continuation.
+    pub fn new(r: R) -> Result<(Recipients, OpenerSealed<R>), Error> {
+        let mut ar = ArchiveReader::<R, HeaderBuf>::new(r);
+        let (recipients, header) = read_header(&mut ar)?;
+
+        Ok((recipients, OpenerSealed { ar, header }))
+    }
+
+    /// The public metadata of the stream.
+    ///
+    /// Note that this metadata is only authenticated once the stream is unsealed.
+    pub fn public_metadata(&self) -> &Metadata {
+        self.header.public_metadata()
+    }
+
+    /// The window of UNIX timestamps, in seconds, in which the identity of `recipient` is valid,
+    /// given the `max_age` in the `parameters` of the PKG.
+    ///
+    /// A user secret key obtained for an identity within the same window can be reused.
+    pub fn timeframe(
+        &self,
+        recipient: usize,
+        parameters: &Parameters,
+    ) -> Result<Range<u64>, Error> {
+        self.header.timeframe(recipient, parameters)
+    }
+
+    /// The number of recipients of which the user secret keys are required to unseal the stream.
+    ///
+    /// This is 1 unless the stream was sealed using `Sealer::with_pkgs`.
+    pub fn threshold(&self) -> usize {
+        self.header.threshold()
+    }
+
+    /// The key IDs of the PKGs from which the user secret keys of the recipients must be obtained,
+    /// in the order of the identities yielded by `new`.
+    pub fn key_ids(&self) -> KeyIds {
+        self.header.key_ids()
+    }
+
+    /// Will unseal the stream continuation and yield a plaintext bytestream.
+    ///
+    /// The `recipient` is the index in the list of identities yielded by `new`,
+    /// for which `usk` is the corresponding user secret key.
+    pub fn unseal(self, recipient: usize, usk: &UserSecretKey) -> Result<OpenerUnsealed<R>, Error> {
+        self.unseal_with(&[(recipient, usk)])
+    }
+
+    /// Will unseal the stream continuation with the user secret keys of several recipients,
+    /// given by their index in the list of identities yielded by `new`.
+    ///
+    /// Throws a ConstraintViolation when fewer than `threshold` recipients, or a recipient more than once, are given.
+    pub fn unseal_with(self, usks: &[(usize, &UserSecretKey)]) -> Result<OpenerUnsealed<R>, Error> {
+        if usks
+            .iter()
+            .any(|(recipient, _)| *recipient >= self.header.keys.len())
+        {
+            return Err(Error::UnknownRecipient);
         }
 
-        let dstmid = BLOCKSIZE - srcsize;
-        dst[dstmid..BLOCKSIZE].copy_from_slice(src);
+        let (headerbuf, mut r) = self.ar.disclose();
+        let mac = r.read_bytes_strict(MACSIZE)?;
+        let (segments, metadata) = unseal_header(&self.header, usks, &headerbuf, mac)?;
 
-        let dststart = match macbuf {
-            None => dstmid,
-            Some(macbuf) => {
-                let dststart = dstmid - MACSIZE;
-                dst[dststart..dstmid].copy_from_slice(&macbuf);
-                dststart
-            }
-        };
+        // The body starts right after the header and its HMAC.
+        let offset = (headerbuf.len() + MACSIZE) as u64;
 
-        let mut content = &mut dst[dststart..BLOCKSIZE - MACSIZE];
-        self.hmac.input(content);
-        self.aes.decrypt(&mut content);
+        Ok(OpenerUnsealed {
+            segments,
+            metadata,
+            finished: false,
+            offset,
+            len: None,
+            r,
+            buf: [0u8; SEGMENTSIZE + TAGSIZE],
+            #[cfg(feature = "std")]
+            pending: 0..0,
+        })
+    }
+}
 
-        Ok(content)
+impl<R: Readable> OpenerUnsealed<R> {
+    /// The private metadata of the stream, which has been decrypted and authenticated.
+    pub fn metadata(&self) -> &Metadata {
+        &self.metadata
     }
 
-    /// Will check the HMAC once the entire stream is exhausted.
-    /// Will only yield the correct value once the **entire** stream is read
-    /// using `write_to`, or by manually calling `write` until `Error::EndOfStream` is yielded.
-    pub fn validate(self) -> bool {
-        match self.resultbuf {
-            None => false,
-            Some(resultbuf) => {
-                let macbuf = &resultbuf[BLOCKSIZE - MACSIZE..BLOCKSIZE];
-                self.hmac.verify(macbuf).is_ok()
-            }
+    /// Read the next segment, and yield its authenticated plaintext of up to `SEGMENTSIZE` bytes.
+    ///
+    /// Will yield `Error::EndOfStream` once the final segment has been read.
+    pub fn read(&mut self) -> Result<&[u8], Error> {
+        if self.finished {
+            return Err(Error::EndOfStream);
         }
+
+        let len = self.fill_buf()?;
+        self.open_segment(len)
     }
 
     /// Will block and write the entire stream to the argument writer.
@@ -144,4 +357,139 @@ impl<R: Readable> OpenerUnsealed<R> {
             };
         }
     }
+
+    /// Authenticate and decrypt the segment of `len` bytes in the buffer.
+    fn open_segment(&mut self, len: usize) -> Result<&[u8], Error> {
+        // Only the final segment is shorter than a full segment.
+        let last = len < SEGMENTSIZE + TAGSIZE;
+
+        #[cfg(feature = "std")]
+        {
+            self.pending = 0..0;
+        }
+
+        let len = self.segments.open(&mut self.buf[..len], last)?;
+        self.finished = last;
+
+        Ok(&self.buf[..len])
+    }
+
+    /// Fill the buffer with the next segment, yielding the number of bytes read.
+    /// Only yields less than a full segment if the underlying stream has ended.
+    fn fill_buf(&mut self) -> Result<usize, Error> {
+        let mut len = 0;
+
+        while len < self.buf.len() {
+            match self.r.read_bytes(self.buf.len() - len) {
+                Ok([]) | Err(Error::EndOfStream) => break,
+                Ok(src) => {
+                    self.buf[len..len + src.len()].copy_from_slice(src);
+                    len += src.len();
+                }
+                Err(e) => return Err(e),
+            }
+        }
+
+        Ok(len)
+    }
+}
+
+/// Read the authenticated plaintext through a `std::io::Read`.
+#[cfg(feature = "std")]
+impl<R: Readable> std::io::Read for OpenerUnsealed<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if self.pending.is_empty() {
+            let len = match OpenerUnsealed::read(self) {
+                Ok(content) => content.len(),
+                Err(Error::EndOfStream) => return Ok(0),
+                Err(e) => return Err(e.into()),
+            };
+            self.pending = 0..len;
+        }
+
+        let n = core::cmp::min(buf.len(), self.pending.len());
+        let start = self.pending.start;
+        buf[..n].copy_from_slice(&self.buf[start..start + n]);
+        self.pending.start += n;
+
+        Ok(n)
+    }
+}
+
+impl<R: Seekable> OpenerUnsealed<R> {
+    /// The length of the plaintext of the entire stream.
+    ///
+    /// Reads and authenticates the final segment to ensure that the stream was not truncated.
+    pub fn plaintext_len(&mut self) -> Result<u64, Error> {
+        if let Some(len) = self.len {
+            return Ok(len);
+        }
+
+        const FULL: u64 = (SEGMENTSIZE + TAGSIZE) as u64;
+
+        let body = self
+            .r
+            .stream_len()?
+            .checked_sub(self.offset)
+            .ok_or(Error::IntegrityViolation)?;
+
+        // The final segment is the only one shorter than a full segment, and contains at least a tag.
+        let segments = body / FULL;
+        let last = body % FULL;
+        if last < TAGSIZE as u64 {
+            return Err(Error::IntegrityViolation);
+        }
+
+        self.seek_segment(segments)?;
+        self.read()?;
+
+        let len = segments * SEGMENTSIZE as u64 + last - TAGSIZE as u64;
+        self.len = Some(len);
+
+        Ok(len)
+    }
+
+    /// Write `len` bytes of plaintext starting at plaintext position `offset` to the argument writer.
+    /// Only the segments covering this range (and the final segment) are read, decrypted and authenticated.
+    ///
+    /// Yields the number of bytes written, which is less than `len` if the range extends beyond
+    /// the end of the stream. Afterwards `read` continues with the segment following the range.
+    pub fn read_range<W: Writable>(
+        &mut self,
+        offset: u64,
+        len: u64,
+        w: &mut W,
+    ) -> Result<u64, Error> {
+        let end = self.plaintext_len()?;
+        if offset >= end {
+            return Ok(0);
+        }
+        let len = core::cmp::min(len, end - offset);
+
+        self.seek_segment(offset / SEGMENTSIZE as u64)?;
+        let mut skip = (offset % SEGMENTSIZE as u64) as usize;
+        let mut written = 0;
+
+        while written < len {
+            let content = &self.read()?[skip..];
+            skip = 0;
+
+            let n = core::cmp::min(len - written, content.len() as u64);
+            w.write(&content[..n as usize])?;
+            written += n;
+        }
+
+        Ok(written)
+    }
+
+    /// Position the underlying stream at the start of segment `segment`.
+    fn seek_segment(&mut self, segment: u64) -> Result<(), Error> {
+        use core::convert::TryFrom;
+
+        self.segments.counter = u32::try_from(segment).or(Err(Error::IntegrityViolation))?;
+        self.finished = false;
+
+        self.r
+            .seek(self.offset + segment * (SEGMENTSIZE + TAGSIZE) as u64)
+    }
 }
@@ -1,50 +1,241 @@
-use cfb_mode::stream_cipher::{NewStreamCipher, StreamCipher};
+use aes_gcm::aead::{Aead, NewAead};
+use arrayvec::ArrayVec;
 use hmac::Mac;
 use rand::{CryptoRng, Rng};
 
+use crate::stream::util::{metadata_nonce, segment_nonce};
 use crate::stream::*;
 use crate::*;
 
 /// Sealer for an bytestream, which converts it into an IRMAseal encrypted bytestream.
-pub struct Sealer<'a, W: Writable> {
-    aes: SymCrypt,
-    hmac: Verifier,
-    w: &'a mut W,
+///
+/// The stream must be completed with `finish`. A sealer that is dropped, or explicitly aborted,
+/// does not write the final segment, thus the resulting stream will not be accepted by the opener.
+pub struct Sealer<W: Writable> {
+    segments: SegmentSealer,
+    buf: [u8; SEGMENTSIZE],
+    len: usize,
+    w: W,
 }
 
-impl<'a, W: Writable> Sealer<'a, W> {
-    pub fn new<R: Rng + CryptoRng>(
-        i: &Identity,
-        pk: &PublicKey,
-        rng: &mut R,
-        w: &'a mut W,
-    ) -> Result<Sealer<'a, W>, Error> {
-        let (c, k) = ibe::kiltz_vahlis_one::encrypt(&pk.0, &i.derive(), rng);
+/// Seals the consecutive segments of the body of a stream.
+pub(crate) struct SegmentSealer {
+    aead: SymCrypt,
+    nonce: [u8; NONCEPREFIXSIZE],
+    counter: u32,
+}
 
-        let (aeskey, mackey) = crate::stream::util::derive_keys(&k);
-        let iv = crate::stream::util::generate_iv(rng);
+/// Recipients paired with the public key of the PKG under which they are sealed.
+pub(crate) type PkgRecipients<'a> = ArrayVec<[(&'a PublicKey, &'a Recipient); MAX_RECIPIENTS]>;
 
-        let aes = SymCrypt::new(&aeskey.into(), &iv.into());
-        let mut hmac = Verifier::new_varkey(&mackey).unwrap();
+/// Pair all recipients with the same public key.
+///
+/// Throws a ConstraintViolation when more than `MAX_RECIPIENTS` recipients are given.
+pub(crate) fn single_pkg<'a>(
+    is: &'a [Recipient],
+    pk: &'a PublicKey,
+) -> Result<PkgRecipients<'a>, Error> {
+    if is.len() > MAX_RECIPIENTS {
+        return Err(Error::ConstraintViolation);
+    }
 
-        let ciphertext = c.to_bytes();
+    Ok(is.iter().map(|r| (pk, r)).collect())
+}
+
+/// Write the IRMAseal header for the recipients, and yield the sealer for the body segments.
+///
+/// The session key is split such that the keys of `threshold` recipients are required to recover it.
+/// Throws a ConstraintViolation when no recipients, or more than `MAX_RECIPIENTS`, are given,
+/// or when the threshold is zero or exceeds the number of recipients.
+pub(crate) fn seal_header<R: Rng + CryptoRng, W: Writable>(
+    threshold: u8,
+    is: &[(&PublicKey, &Recipient)],
+    public: &Metadata,
+    private: &Metadata,
+    rng: &mut R,
+    w: &mut W,
+) -> Result<SegmentSealer, Error> {
+    if is.is_empty() || is.len() > MAX_RECIPIENTS {
+        return Err(Error::ConstraintViolation);
+    }
+
+    let keys = crate::stream::util::generate_keys(rng);
+    let (aeskey, mackey) = crate::stream::util::split_keys(&keys);
+
+    let mut shares = [[0u8; WRAPPEDKEYSIZE]; MAX_RECIPIENTS];
+    {
+        let mut refs: ArrayVec<[&mut [u8]; MAX_RECIPIENTS]> = shares
+            .iter_mut()
+            .take(is.len())
+            .map(|s| &mut s[..])
+            .collect();
+        crate::shamir::split(&keys, threshold, &mut refs, rng)?;
+    }
+
+    let nonce = crate::stream::util::generate_nonce_prefix(rng);
+
+    let aead = SymCrypt::new(aeskey.into());
+    let mut hmac = Verifier::new_varkey(&mackey).unwrap();
+
+    hmac.input(&PRELUDE);
+    w.write(&PRELUDE)?;
 
-        hmac.input(&PRELUDE);
-        w.write(&PRELUDE)?;
+    hmac.input(&[FORMAT_VERSION]);
+    w.write(&[FORMAT_VERSION])?;
 
-        hmac.input(&[FORMAT_VERSION]);
-        w.write(&[FORMAT_VERSION])?;
+    // Bounded by MAX_RECIPIENTS, thus always fits.
+    let count = is.len() as u8;
+    hmac.input(&[count, threshold]);
+    w.write(&[count, threshold])?;
 
-        i.write_to(&mut hmac)?;
-        i.write_to(w)?;
+    for ((pk, r), share) in is.iter().zip(shares.iter()) {
+        let key_id = pk.key_id();
+        hmac.input(&key_id.0);
+        w.write(&key_id.0)?;
+
+        // Sealed for the full identity, but only the hint is written.
+        let (c, k) = ibe::kiltz_vahlis_one::encrypt(&pk.0, &r.identity.derive(), rng);
+        let ciphertext = c.to_bytes();
+        let wrapped = crate::stream::util::wrap_keys(&k, share);
+
+        let hint = r.hint();
+        hint.write_to(&mut hmac)?;
+        hint.write_to(w)?;
 
         hmac.input(&ciphertext);
         w.write(&ciphertext)?;
 
-        hmac.input(&iv);
-        w.write(&iv)?;
+        hmac.input(&wrapped);
+        w.write(&wrapped)?;
+    }
+
+    hmac.input(&nonce);
+    w.write(&nonce)?;
+
+    public.write_to(&mut hmac)?;
+    public.write_to(w)?;
+
+    // The private metadata is encrypted with the session key, using its own nonce.
+    let mut private = private.clone();
+    let tag = aead
+        .encrypt_in_place_detached(&metadata_nonce(&nonce), &[], private.as_bytes_mut())
+        .or(Err(Error::ConstraintViolation))?;
+
+    private.write_to(&mut hmac)?;
+    private.write_to(w)?;
+
+    hmac.input(&tag);
+    w.write(&tag)?;
+
+    w.write(&hmac.result().code())?;
+
+    Ok(SegmentSealer {
+        aead,
+        nonce,
+        counter: 0,
+    })
+}
+
+impl SegmentSealer {
+    /// Encrypt the next segment in place, yielding its authentication tag.
+    pub(crate) fn seal(&mut self, segment: &mut [u8], last: bool) -> Result<[u8; TAGSIZE], Error> {
+        let nonce = segment_nonce(&self.nonce, self.counter, last);
+
+        let tag = self
+            .aead
+            .encrypt_in_place_detached(&nonce, &[], segment)
+            .or(Err(Error::ConstraintViolation))?;
 
-        Ok(Sealer { aes, hmac, w })
+        self.counter = self
+            .counter
+            .checked_add(1)
+            .ok_or(Error::ConstraintViolation)?;
+
+        Ok(tag.into())
+    }
+}
+
+impl<W: Writable> Sealer<W> {
+    /// Start sealing a bytestream for one or more recipients.
+    ///
+    /// A single random session key is generated, which is wrapped for every recipient identity
+    /// separately. Any of these recipients can open the resulting bytestream.
+    ///
+    /// Throws a ConstraintViolation when no recipients, or more than `MAX_RECIPIENTS`, are given.
+    pub fn new<R: Rng + CryptoRng>(
+        is: &[Recipient],
+        pk: &PublicKey,
+        rng: &mut R,
+        w: W,
+    ) -> Result<Sealer<W>, Error> {
+        Sealer::with_metadata(is, pk, &Metadata::new(), &Metadata::new(), rng, w)
+    }
+
+    /// Start sealing a bytestream for one or more recipients, including metadata in the header.
+    ///
+    /// The `public` metadata is readable by anyone, for example as routing hint, but is only
+    /// authenticated once the stream is unsealed. The `private` metadata is encrypted, and thus
+    /// only readable by the recipients.
+    pub fn with_metadata<R: Rng + CryptoRng>(
+        is: &[Recipient],
+        pk: &PublicKey,
+        public: &Metadata,
+        private: &Metadata,
+        rng: &mut R,
+        w: W,
+    ) -> Result<Sealer<W>, Error> {
+        Sealer::with_pkgs(1, &single_pkg(is, pk)?, public, private, rng, w)
+    }
+
+    /// Start sealing a bytestream for recipients under possibly distinct PKGs, including metadata in the header.
+    ///
+    /// The session key is split using Shamir secret sharing, such that the user secret keys of
+    /// `threshold` recipients are required to open the resulting bytestream. Sealing the same identity
+    /// under several unrelated PKGs with a threshold of all of them avoids depending on a single PKG.
+    ///
+    /// Throws a ConstraintViolation when no recipients, or more than `MAX_RECIPIENTS`, are given,
+    /// or when the threshold is zero or exceeds the number of recipients.
+    pub fn with_pkgs<R: Rng + CryptoRng>(
+        threshold: u8,
+        is: &[(&PublicKey, &Recipient)],
+        public: &Metadata,
+        private: &Metadata,
+        rng: &mut R,
+        mut w: W,
+    ) -> Result<Sealer<W>, Error> {
+        let segments = seal_header(threshold, is, public, private, rng, &mut w)?;
+
+        Ok(Sealer {
+            segments,
+            buf: [0u8; SEGMENTSIZE],
+            len: 0,
+            w,
+        })
+    }
+
+    /// Write the final segment, completing the stream, and yield the underlying writer.
+    pub fn finish(mut self) -> Result<W, Error> {
+        self.flush_segment(true)?;
+        Ok(self.w)
+    }
+
+    /// Discard the buffered plaintext and yield the underlying writer, without completing the stream.
+    ///
+    /// Any segments already written out can not be opened, as the final segment is missing.
+    pub fn abort(self) -> W {
+        self.w
+    }
+
+    /// Seal the currently buffered plaintext as a segment and write it out.
+    fn flush_segment(&mut self, last: bool) -> Result<(), Error> {
+        let segment = &mut self.buf[..self.len];
+        let tag = self.segments.seal(segment, last)?;
+
+        self.w.write(segment)?;
+        self.w.write(&tag)?;
+        self.len = 0;
+
+        Ok(())
     }
 }
 
@@ -55,25 +246,37 @@ impl Writable for Verifier {
     }
 }
 
-impl<'a, W: Writable> Writable for Sealer<'a, W> {
-    fn write(&mut self, buf: &[u8]) -> Result<(), Error> {
-        let mut tmp = [0u8; BLOCKSIZE];
-
-        for c in buf.chunks(BLOCKSIZE) {
-            let subtmp = &mut tmp[0..c.len()];
-            subtmp.copy_from_slice(c);
-            self.aes.encrypt(subtmp);
-            self.hmac.input(subtmp);
-            self.w.write(subtmp)?;
+impl<W: Writable> Writable for Sealer<W> {
+    fn write(&mut self, mut buf: &[u8]) -> Result<(), Error> {
+        while !buf.is_empty() {
+            let n = core::cmp::min(SEGMENTSIZE - self.len, buf.len());
+            self.buf[self.len..self.len + n].copy_from_slice(&buf[..n]);
+            self.len += n;
+            buf = &buf[n..];
+
+            // Only the final segment is shorter than SEGMENTSIZE, thus a full segment can be
+            // written out as soon as it is complete.
+            if self.len == SEGMENTSIZE {
+                self.flush_segment(false)?;
+            }
         }
 
         Ok(())
     }
 }
 
-impl<'a, W: Writable> Drop for Sealer<'a, W> {
-    fn drop(&mut self) {
-        let code = self.hmac.result_reset().code();
-        self.w.write(&code).unwrap()
+/// Seal everything written to this `std::io::Write`.
+///
+/// Note that `flush` does not write out a partial segment, as only the final segment may be shorter
+/// than `SEGMENTSIZE`.
+#[cfg(feature = "std")]
+impl<W: Writable> std::io::Write for Sealer<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        Writable::write(self, buf)?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
     }
 }
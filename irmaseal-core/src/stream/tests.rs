@@ -1,3 +1,4 @@
+use crate::api::Parameters;
 use crate::stream::*;
 use crate::util::SliceReader;
 use crate::*;
@@ -9,6 +10,7 @@ type BigBuf = ArrayVec<[u8; 65536]>;
 
 struct DefaultProps {
     pub i: Identity,
+    pub j: Identity,
     pub pk: ibe::kiltz_vahlis_one::PublicKey,
     pub sk: ibe::kiltz_vahlis_one::SecretKey,
 }
@@ -22,56 +24,77 @@ impl Default for DefaultProps {
             Some("w.geraedts@sarif.nl"),
         )
         .unwrap();
+        let j = Identity::new(
+            1566722350,
+            "pbdf.gemeente.personalData.bsn",
+            Some("123456789"),
+        )
+        .unwrap();
 
         let (pk, sk) = ibe::kiltz_vahlis_one::setup(&mut rng);
 
-        DefaultProps { i, pk, sk }
+        DefaultProps { i, j, pk, sk }
     }
 }
 
-fn seal(props: &DefaultProps, content: &[u8]) -> BigBuf {
+fn seal_for(props: &DefaultProps, is: &[Identity], content: &[u8]) -> BigBuf {
     let mut rng = rand::thread_rng();
-    let DefaultProps { i, pk, sk: _ } = props;
 
     let mut buf = BigBuf::new();
-    {
-        let mut s = Sealer::new(&i, &PublicKey(pk.clone()), &mut rng, &mut buf).unwrap();
-        s.write(&content).unwrap();
-    } // Force Drop of s.
+
+    let rs: ArrayVec<[Recipient; 16]> = is.iter().cloned().map(Recipient::new).collect();
+
+    let mut s = Sealer::new(&rs, &PublicKey(props.pk), &mut rng, &mut buf).unwrap();
+    s.write(content).unwrap();
+    s.finish().unwrap();
 
     buf
 }
 
-fn unseal(props: &DefaultProps, buf: &[u8]) -> (BigBuf, bool) {
-    let mut rng = rand::thread_rng();
-    let DefaultProps { i, pk, sk } = props;
+fn seal(props: &DefaultProps, content: &[u8]) -> BigBuf {
+    seal_for(props, core::slice::from_ref(&props.i), content)
+}
 
-    let bufr = SliceReader::new(&buf);
-    let (i2, o) = OpenerSealed::new(bufr).unwrap();
+fn unseal_as(props: &DefaultProps, recipient: usize, buf: &[u8]) -> Result<BigBuf, Error> {
+    let mut rng = rand::thread_rng();
+    let DefaultProps { pk, sk, .. } = props;
 
-    assert_eq!(i, &i2);
+    let bufr = SliceReader::new(buf);
+    let (recipients, o) = OpenerSealed::new(bufr).unwrap();
 
-    let usk = ibe::kiltz_vahlis_one::extract_usk(&pk, &sk, &i2.derive(), &mut rng);
+    let usk = ibe::kiltz_vahlis_one::extract_usk(pk, sk, &recipients[recipient].derive(), &mut rng);
 
-    let mut o = o.unseal(&UserSecretKey(usk)).unwrap();
+    let mut o = o.unseal(recipient, &UserSecretKey(usk))?;
 
     let mut dst = BigBuf::new();
-    o.write_to(&mut dst).unwrap();
+    o.write_to(&mut dst)?;
+
+    Ok(dst)
+}
+
+fn unseal(props: &DefaultProps, buf: &[u8]) -> Result<BigBuf, Error> {
+    let (recipients, _) = OpenerSealed::new(SliceReader::new(buf)).unwrap();
+    assert_eq!(recipients.as_slice(), core::slice::from_ref(&props.i));
 
-    (dst, o.validate())
+    unseal_as(props, 0, buf)
 }
 
-fn seal_and_unseal(props: &DefaultProps, content: &[u8]) -> (BigBuf, bool) {
+fn seal_and_unseal(props: &DefaultProps, content: &[u8]) -> Result<BigBuf, Error> {
     let buf = seal(props, content);
     unseal(props, &buf)
 }
 
 fn do_test(props: &DefaultProps, content: &mut [u8]) {
     rand::thread_rng().fill_bytes(content);
-    let (dst, valid) = seal_and_unseal(props, content);
+    let dst = seal_and_unseal(props, content).unwrap();
 
-    assert_eq!(&content.as_ref(), &dst.as_slice());
-    assert!(valid);
+    assert_eq!(&content, &dst.as_slice());
+}
+
+/// The length of the header of a sealed bytestream with the given content length.
+fn header_len(buf: &[u8], content_len: usize) -> usize {
+    let segments = content_len / SEGMENTSIZE + 1;
+    buf.len() - content_len - segments * TAGSIZE
 }
 
 #[test]
@@ -82,8 +105,10 @@ fn reflection_sealer_opener() {
     do_test(&props, &mut [0u8; 1]);
     do_test(&props, &mut [0u8; 511]);
     do_test(&props, &mut [0u8; 512]);
-    do_test(&props, &mut [0u8; 1008]);
-    do_test(&props, &mut [0u8; 1023]);
+    do_test(&props, &mut [0u8; 4095]);
+    do_test(&props, &mut [0u8; 4096]);
+    do_test(&props, &mut [0u8; 4097]);
+    do_test(&props, &mut [0u8; 8192]);
     do_test(&props, &mut [0u8; 60000]);
 }
 
@@ -95,15 +120,16 @@ fn corrupt_body() {
     rand::thread_rng().fill_bytes(&mut content);
 
     let mut buf = seal(&props, &content);
-    buf[1000] += 0x02;
-    let (dst, valid) = unseal(&props, &buf);
+    buf[10000] ^= 0x02;
 
-    assert_ne!(&content.as_ref(), &dst.as_slice());
-    assert!(!valid);
+    assert!(matches!(
+        unseal(&props, &buf),
+        Err(Error::IntegrityViolation)
+    ));
 }
 
 #[test]
-fn corrupt_hmac() {
+fn corrupt_tag() {
     let props = DefaultProps::default();
 
     let mut content = [0u8; 60000];
@@ -111,9 +137,983 @@ fn corrupt_hmac() {
 
     let mut buf = seal(&props, &content);
     let mutation_point = buf.len() - 5;
-    buf[mutation_point] += 0x02;
-    let (dst, valid) = unseal(&props, &buf);
+    buf[mutation_point] ^= 0x02;
+
+    assert!(matches!(
+        unseal(&props, &buf),
+        Err(Error::IntegrityViolation)
+    ));
+}
+
+#[test]
+fn corrupt_header() {
+    let props = DefaultProps::default();
+
+    let mut buf = seal(&props, &[0u8; 100]);
+    let mutation_point = header_len(&buf, 100) - 1;
+    buf[mutation_point] ^= 0x02;
+
+    assert!(matches!(
+        unseal(&props, &buf),
+        Err(Error::IntegrityViolation)
+    ));
+}
+
+#[test]
+fn truncated() {
+    let props = DefaultProps::default();
+
+    let mut content = [0u8; 2 * SEGMENTSIZE];
+    rand::thread_rng().fill_bytes(&mut content);
+
+    let buf = seal(&props, &content);
+
+    // Drop the empty final segment, leaving only full segments.
+    let truncated = &buf[..buf.len() - TAGSIZE];
+    assert!(matches!(
+        unseal(&props, truncated),
+        Err(Error::IntegrityViolation)
+    ));
+
+    // Drop the final and part of the preceding segment.
+    let truncated = &buf[..buf.len() - TAGSIZE - 100];
+    assert!(matches!(
+        unseal(&props, truncated),
+        Err(Error::IntegrityViolation)
+    ));
+}
+
+#[test]
+fn reordered() {
+    let props = DefaultProps::default();
+
+    let mut content = [0u8; 2 * SEGMENTSIZE + 100];
+    rand::thread_rng().fill_bytes(&mut content);
+
+    let mut buf = seal(&props, &content);
+
+    let start = header_len(&buf, content.len());
+    let (first, second) = buf[start..].split_at_mut(SEGMENTSIZE + TAGSIZE);
+    first.swap_with_slice(&mut second[..SEGMENTSIZE + TAGSIZE]);
+
+    assert!(matches!(
+        unseal(&props, &buf),
+        Err(Error::IntegrityViolation)
+    ));
+}
+
+#[test]
+fn multiple_recipients() {
+    let props = DefaultProps::default();
+
+    let mut content = [0u8; 1000];
+    rand::thread_rng().fill_bytes(&mut content);
+
+    let is = [props.i.clone(), props.j.clone()];
+    let buf = seal_for(&props, &is, &content);
+
+    let (recipients, _) = OpenerSealed::new(SliceReader::new(&buf)).unwrap();
+    assert_eq!(recipients.as_slice(), &is);
+
+    for recipient in 0..is.len() {
+        let dst = unseal_as(&props, recipient, &buf).unwrap();
+
+        assert_eq!(&content.as_ref(), &dst.as_slice());
+    }
+}
+
+#[test]
+fn wrong_recipient_key() {
+    let props = DefaultProps::default();
+    let mut rng = rand::thread_rng();
+
+    let buf = seal_for(&props, &[props.i.clone(), props.j.clone()], &[0u8; 100]);
+    let (recipients, o) = OpenerSealed::new(SliceReader::new(&buf)).unwrap();
+
+    // The user secret key of the second recipient does not unwrap the first session key.
+    let usk =
+        ibe::kiltz_vahlis_one::extract_usk(&props.pk, &props.sk, &recipients[1].derive(), &mut rng);
+    assert!(matches!(
+        o.unseal(0, &UserSecretKey(usk)),
+        Err(Error::IntegrityViolation)
+    ));
+}
+
+#[test]
+fn hidden_recipient() {
+    let props = DefaultProps::default();
+    let mut rng = rand::thread_rng();
+
+    let mut buf = BigBuf::new();
+    let mut s = Sealer::new(
+        &[Recipient::hidden(props.j.clone())],
+        &PublicKey(props.pk),
+        &mut rng,
+        &mut buf,
+    )
+    .unwrap();
+    s.write(&[0xAAu8; 100]).unwrap();
+    s.finish().unwrap();
+
+    // Only the attribute type is disclosed.
+    let (recipients, _) = OpenerSealed::new(SliceReader::new(&buf)).unwrap();
+    assert_eq!(recipients[0].attributes[0].value, None);
+    assert_eq!(
+        recipients[0].attributes[0].atype,
+        props.j.attributes[0].atype
+    );
+
+    // The user secret key for the hint does not open the stream.
+    assert!(matches!(
+        unseal_as(&props, 0, &buf),
+        Err(Error::IntegrityViolation)
+    ));
+
+    let identity = recipients[0].reconstruct(&props.j.attributes).unwrap();
+    assert_eq!(identity, props.j);
+
+    let (_, o) = OpenerSealed::new(SliceReader::new(&buf)).unwrap();
+    let usk =
+        ibe::kiltz_vahlis_one::extract_usk(&props.pk, &props.sk, &identity.derive(), &mut rng);
+    let mut o = o.unseal(0, &UserSecretKey(usk)).unwrap();
+
+    let mut dst = BigBuf::new();
+    o.write_to(&mut dst).unwrap();
+    assert_eq!(dst.as_slice(), &[0xAAu8; 100][..]);
+}
+
+#[test]
+fn conjunction() {
+    let props = DefaultProps::default();
+    let mut rng = rand::thread_rng();
+
+    let attributes = [
+        props.i.attributes[0].clone(),
+        Attribute::new("pbdf.pbdf.big.profession", Some("Arts")).unwrap(),
+    ];
+    let policy = Identity::conjunction(props.i.timestamp, &attributes).unwrap();
 
-    assert_eq!(&content.as_ref(), &dst.as_slice());
-    assert!(!valid);
+    let buf = seal_for(&props, core::slice::from_ref(&policy), &[0xAAu8; 100]);
+
+    let (recipients, _) = OpenerSealed::new(SliceReader::new(&buf)).unwrap();
+    assert_eq!(recipients.as_slice(), core::slice::from_ref(&policy));
+
+    let dst = unseal_as(&props, 0, &buf).unwrap();
+    assert_eq!(dst.as_slice(), &[0xAAu8; 100][..]);
+
+    // The key for only one of the attributes does not open the stream.
+    let (_, o) = OpenerSealed::new(SliceReader::new(&buf)).unwrap();
+    let usk = ibe::kiltz_vahlis_one::extract_usk(&props.pk, &props.sk, &props.i.derive(), &mut rng);
+    assert!(matches!(
+        o.unseal(0, &UserSecretKey(usk)),
+        Err(Error::IntegrityViolation)
+    ));
+}
+
+#[test]
+fn timeframe() {
+    let props = DefaultProps::default();
+    let parameters = Parameters {
+        format_version: FORMAT_VERSION,
+        max_age: 300,
+        max_retention: None,
+        max_skew: 0,
+        public_key: PublicKey(props.pk),
+        keys: api::KeyGenerations::new(),
+    };
+
+    let i = Identity::aligned(&parameters, 1566722350, &props.i.attributes).unwrap();
+    let j = Identity::aligned(&parameters, 1566722399, &props.i.attributes).unwrap();
+    assert_eq!(i, j);
+    assert_eq!(i.timestamp, 1566722100);
+    assert_eq!(i.timeframe(&parameters).unwrap(), 1566722100..1566722400);
+
+    let k = Identity::aligned(&parameters, 1566722400, &props.i.attributes).unwrap();
+    assert_ne!(i, k);
+
+    let buf = seal_for(&props, &[i, k], &[0u8; 10]);
+    let (_, o) = OpenerSealed::new(SliceReader::new(&buf)).unwrap();
+    assert_eq!(o.timeframe(0, &parameters).unwrap(), 1566722100..1566722400);
+    assert_eq!(o.timeframe(1, &parameters).unwrap(), 1566722400..1566722700);
+    assert!(matches!(
+        o.timeframe(2, &parameters),
+        Err(Error::UnknownRecipient)
+    ));
+
+    let parameters = Parameters {
+        max_age: 0,
+        ..parameters
+    };
+    assert!(matches!(
+        Identity::aligned(&parameters, 1566722350, &props.i.attributes),
+        Err(Error::ConstraintViolation)
+    ));
+}
+
+#[test]
+fn threshold() {
+    let props = DefaultProps::default();
+    let mut rng = rand::thread_rng();
+
+    let buf = seal(&props, &[0xAAu8; 100]);
+    let shares = threshold::split(&props.sk, 2, 3, &mut rng).unwrap();
+
+    // Each node issues a partial key for its own share of the master secret key.
+    let partial = |share: &threshold::SecretKeyShare, rng: &mut rand::rngs::ThreadRng| {
+        let usk = ibe::kiltz_vahlis_one::extract_usk(
+            &props.pk,
+            share.secret_key(),
+            &props.i.derive(),
+            rng,
+        );
+        (share.index(), UserSecretKey(usk))
+    };
+
+    let open = |usk: &UserSecretKey| -> Result<BigBuf, Error> {
+        let (_, o) = OpenerSealed::new(SliceReader::new(&buf)).unwrap();
+        let mut o = o.unseal(0, usk)?;
+
+        let mut dst = BigBuf::new();
+        o.write_to(&mut dst)?;
+        Ok(dst)
+    };
+
+    // No single node can decrypt.
+    for share in shares.iter() {
+        let (_, usk) = partial(share, &mut rng);
+        assert!(matches!(open(&usk), Err(Error::IntegrityViolation)));
+
+        let usk = threshold::combine(&[partial(share, &mut rng)]).unwrap();
+        assert!(matches!(open(&usk), Err(Error::IntegrityViolation)));
+    }
+
+    // Any two nodes can.
+    for (a, b) in [(0, 1), (0, 2), (2, 1)].iter() {
+        let partials = [
+            partial(&shares[*a], &mut rng),
+            partial(&shares[*b], &mut rng),
+        ];
+        let usk = threshold::combine(&partials).unwrap();
+        assert_eq!(open(&usk).unwrap().as_slice(), &[0xAAu8; 100][..]);
+    }
+
+    let partials: ArrayVec<[(u8, UserSecretKey); 3]> = shares
+        .iter()
+        .map(|share| partial(share, &mut rng))
+        .collect();
+    let usk = threshold::combine(&partials).unwrap();
+    assert_eq!(open(&usk).unwrap().as_slice(), &[0xAAu8; 100][..]);
+
+    let partials = [partial(&shares[0], &mut rng), partial(&shares[0], &mut rng)];
+    assert!(matches!(
+        threshold::combine(&partials),
+        Err(Error::ConstraintViolation)
+    ));
+}
+
+#[test]
+fn multiple_pkgs() {
+    let props = DefaultProps::default();
+    let mut rng = rand::thread_rng();
+
+    let (pk2, sk2) = ibe::kiltz_vahlis_one::setup(&mut rng);
+    let (pk3, sk3) = ibe::kiltz_vahlis_one::setup(&mut rng);
+    let pkgs = [
+        (PublicKey(props.pk), props.sk),
+        (PublicKey(pk2), sk2),
+        (PublicKey(pk3), sk3),
+    ];
+
+    let r = Recipient::new(props.i.clone());
+    let seal_with = |threshold: u8| -> Result<BigBuf, Error> {
+        let is = [(&pkgs[0].0, &r), (&pkgs[1].0, &r), (&pkgs[2].0, &r)];
+        let mut buf = BigBuf::new();
+        let mut s = Sealer::with_pkgs(
+            threshold,
+            &is,
+            &Metadata::new(),
+            &Metadata::new(),
+            &mut rand::thread_rng(),
+            &mut buf,
+        )?;
+        s.write(&[0xAAu8; 100])?;
+        s.finish()?;
+        Ok(buf)
+    };
+
+    let usks: ArrayVec<[UserSecretKey; 3]> = pkgs
+        .iter()
+        .map(|(pk, sk)| {
+            UserSecretKey(ibe::kiltz_vahlis_one::extract_usk(
+                &pk.0,
+                sk,
+                &props.i.derive(),
+                &mut rng,
+            ))
+        })
+        .collect();
+
+    let open = |buf: &[u8], keys: &[(usize, &UserSecretKey)]| -> Result<(), Error> {
+        let (_, o) = OpenerSealed::new(SliceReader::new(buf)).unwrap();
+        let mut o = o.unseal_with(keys)?;
+
+        assert_eq!(o.read()?, &[0xAAu8; 100][..]);
+        Ok(())
+    };
+
+    let buf = seal_with(2).unwrap();
+    let (recipients, o) = OpenerSealed::new(SliceReader::new(&buf)).unwrap();
+    assert_eq!(recipients.len(), 3);
+    assert_eq!(o.threshold(), 2);
+    assert_eq!(
+        o.key_ids().as_slice(),
+        &[pkgs[0].0.key_id(), pkgs[1].0.key_id(), pkgs[2].0.key_id()]
+    );
+
+    // The key of a single PKG does not suffice.
+    for i in 0..3 {
+        assert!(matches!(
+            open(&buf, &[(i, &usks[i])]),
+            Err(Error::ConstraintViolation)
+        ));
+    }
+    assert!(matches!(
+        open(&buf, &[(0, &usks[0]), (0, &usks[0])]),
+        Err(Error::ConstraintViolation)
+    ));
+
+    for &(a, b) in [(0, 1), (1, 2), (2, 0)].iter() {
+        open(&buf, &[(a, &usks[a]), (b, &usks[b])]).unwrap();
+    }
+
+    // A key of one PKG is of no use for a recipient sealed under another.
+    assert!(matches!(
+        open(&buf, &[(0, &usks[0]), (1, &usks[0])]),
+        Err(Error::IntegrityViolation)
+    ));
+
+    let buf = seal_with(3).unwrap();
+    assert!(matches!(
+        open(&buf, &[(0, &usks[0]), (2, &usks[2])]),
+        Err(Error::ConstraintViolation)
+    ));
+    open(&buf, &[(2, &usks[2]), (0, &usks[0]), (1, &usks[1])]).unwrap();
+
+    assert!(matches!(seal_with(0), Err(Error::ConstraintViolation)));
+    assert!(matches!(seal_with(4), Err(Error::ConstraintViolation)));
+}
+
+#[test]
+fn recipient_bounds() {
+    let props = DefaultProps::default();
+    let mut rng = rand::thread_rng();
+    let mut buf = BigBuf::new();
+
+    assert!(matches!(
+        Sealer::new(&[], &PublicKey(props.pk), &mut rng, &mut buf),
+        Err(Error::ConstraintViolation)
+    ));
+
+    let buf = seal(&props, &[0u8; 10]);
+    let (_, o) = OpenerSealed::new(SliceReader::new(&buf)).unwrap();
+    let usk = ibe::kiltz_vahlis_one::extract_usk(&props.pk, &props.sk, &props.i.derive(), &mut rng);
+    assert!(matches!(
+        o.unseal(1, &UserSecretKey(usk)),
+        Err(Error::UnknownRecipient)
+    ));
+}
+
+#[test]
+fn unfinished() {
+    let props = DefaultProps::default();
+    let mut rng = rand::thread_rng();
+
+    let mut content = [0u8; 2 * SEGMENTSIZE + 100];
+    rand::thread_rng().fill_bytes(&mut content);
+
+    let mut buf = BigBuf::new();
+    {
+        let mut s = Sealer::new(
+            &[Recipient::new(props.i.clone())],
+            &PublicKey(props.pk),
+            &mut rng,
+            &mut buf,
+        )
+        .unwrap();
+        s.write(&content).unwrap();
+    } // Dropped without finish.
+
+    assert_eq!(
+        buf.len(),
+        header_len(&seal(&props, &content), content.len()) + 2 * (SEGMENTSIZE + TAGSIZE)
+    );
+    assert!(matches!(
+        unseal(&props, &buf),
+        Err(Error::IntegrityViolation)
+    ));
+
+    let mut buf = BigBuf::new();
+    let mut s = Sealer::new(
+        &[Recipient::new(props.i.clone())],
+        &PublicKey(props.pk),
+        &mut rng,
+        &mut buf,
+    )
+    .unwrap();
+    s.write(&content).unwrap();
+    s.abort();
+
+    assert!(matches!(
+        unseal(&props, &buf),
+        Err(Error::IntegrityViolation)
+    ));
+}
+
+#[test]
+fn finish_reports_errors() {
+    let props = DefaultProps::default();
+    let mut rng = rand::thread_rng();
+
+    // Fits the header, but not the final segment.
+    let mut buf = ArrayVec::<[u8; 512]>::new();
+    let mut s = Sealer::new(
+        &[Recipient::new(props.i.clone())],
+        &PublicKey(props.pk),
+        &mut rng,
+        &mut buf,
+    )
+    .unwrap();
+    s.write(&[0u8; 300]).unwrap();
+
+    assert!(matches!(s.finish(), Err(Error::UpstreamWritableError)));
+}
+
+fn seal_with_metadata(
+    props: &DefaultProps,
+    public: &Metadata,
+    private: &Metadata,
+    content: &[u8],
+) -> BigBuf {
+    let mut rng = rand::thread_rng();
+    let mut buf = BigBuf::new();
+
+    let mut s = Sealer::with_metadata(
+        &[Recipient::new(props.i.clone())],
+        &PublicKey(props.pk),
+        public,
+        private,
+        &mut rng,
+        &mut buf,
+    )
+    .unwrap();
+    s.write(content).unwrap();
+    s.finish().unwrap();
+
+    buf
+}
+
+#[test]
+fn metadata() {
+    let props = DefaultProps::default();
+    let mut rng = rand::thread_rng();
+
+    let mut public = Metadata::new();
+    public
+        .push(METADATA_CONTENT_TYPE, b"application/pdf")
+        .unwrap();
+
+    let mut private = Metadata::new();
+    private.push(METADATA_FILENAME, b"report.pdf").unwrap();
+    private.push_u64(METADATA_MODIFIED, 1566722350).unwrap();
+
+    let content = [0xAAu8; 1000];
+    let buf = seal_with_metadata(&props, &public, &private, &content);
+
+    // The private metadata is not readable without unsealing.
+    assert!(!buf.windows(10).any(|w| w == b"report.pdf"));
+
+    let (recipients, o) = OpenerSealed::new(SliceReader::new(&buf)).unwrap();
+    assert_eq!(o.public_metadata(), &public);
+
+    let usk =
+        ibe::kiltz_vahlis_one::extract_usk(&props.pk, &props.sk, &recipients[0].derive(), &mut rng);
+    let mut o = o.unseal(0, &UserSecretKey(usk)).unwrap();
+    assert_eq!(o.metadata(), &private);
+    assert_eq!(o.metadata().get_str(METADATA_FILENAME), Some("report.pdf"));
+
+    let mut dst = BigBuf::new();
+    o.write_to(&mut dst).unwrap();
+    assert_eq!(&content[..], dst.as_slice());
+
+    // Streams without metadata yield empty sections.
+    let buf = seal(&props, &content);
+    let (_, o) = OpenerSealed::new(SliceReader::new(&buf)).unwrap();
+    assert!(o.public_metadata().is_empty());
+}
+
+#[test]
+fn corrupt_metadata() {
+    let props = DefaultProps::default();
+
+    let mut public = Metadata::new();
+    public.push(METADATA_CONTENT_TYPE, b"text/plain").unwrap();
+
+    let mut private = Metadata::new();
+    private.push(METADATA_FILENAME, b"notes.txt").unwrap();
+
+    let buf = seal_with_metadata(&props, &public, &private, &[0u8; 100]);
+    let header_len = header_len(&buf, 100);
+
+    // Position of the public content type value, and of the private ciphertext.
+    let public_point = buf.windows(10).position(|w| w == b"text/plain").unwrap();
+    let private_point = header_len - MACSIZE - TAGSIZE - 1;
+
+    for &mutation_point in &[public_point, private_point] {
+        let mut buf = buf.clone();
+        buf[mutation_point] ^= 0x02;
+
+        assert!(matches!(
+            unseal(&props, &buf),
+            Err(Error::IntegrityViolation)
+        ));
+    }
+}
+
+#[test]
+fn armored() {
+    use crate::armor::{ArmorReader, ArmorWriter};
+
+    let props = DefaultProps::default();
+    let mut rng = rand::thread_rng();
+
+    let mut content = [0u8; SEGMENTSIZE + 100];
+    rand::thread_rng().fill_bytes(&mut content);
+
+    let w = ArmorWriter::new(BigBuf::new()).unwrap();
+    let mut s = Sealer::new(
+        &[Recipient::new(props.i.clone())],
+        &PublicKey(props.pk),
+        &mut rng,
+        w,
+    )
+    .unwrap();
+    s.write(&content).unwrap();
+    let buf = s.finish().unwrap().finish().unwrap();
+
+    let (recipients, o) = OpenerSealed::new(ArmorReader::new(SliceReader::new(&buf))).unwrap();
+    let usk =
+        ibe::kiltz_vahlis_one::extract_usk(&props.pk, &props.sk, &recipients[0].derive(), &mut rng);
+    let mut o = o.unseal(0, &UserSecretKey(usk)).unwrap();
+
+    let mut dst = BigBuf::new();
+    o.write_to(&mut dst).unwrap();
+    assert_eq!(&content[..], dst.as_slice());
+}
+
+fn unseal_range(props: &DefaultProps, buf: &[u8], offset: u64, len: u64) -> Result<BigBuf, Error> {
+    let mut rng = rand::thread_rng();
+
+    let (recipients, o) = OpenerSealed::new(SliceReader::new(buf)).unwrap();
+    let usk =
+        ibe::kiltz_vahlis_one::extract_usk(&props.pk, &props.sk, &recipients[0].derive(), &mut rng);
+    let mut o = o.unseal(0, &UserSecretKey(usk))?;
+
+    let mut dst = BigBuf::new();
+    let written = o.read_range(offset, len, &mut dst)?;
+    assert_eq!(written, dst.len() as u64);
+
+    Ok(dst)
+}
+
+#[test]
+fn random_access() {
+    let props = DefaultProps::default();
+
+    let mut content = [0u8; 3 * SEGMENTSIZE + 100];
+    rand::thread_rng().fill_bytes(&mut content);
+
+    let buf = seal(&props, &content);
+
+    for &(offset, len) in &[
+        (0, 10),
+        (10, SEGMENTSIZE),
+        (SEGMENTSIZE - 1, 2),
+        (SEGMENTSIZE, SEGMENTSIZE),
+        (2 * SEGMENTSIZE + 5, SEGMENTSIZE + 50),
+        (3 * SEGMENTSIZE + 90, 1000),
+        (0, content.len()),
+    ] {
+        let dst = unseal_range(&props, &buf, offset as u64, len as u64).unwrap();
+        let end = core::cmp::min(offset + len, content.len());
+        assert_eq!(&content[offset..end], dst.as_slice());
+    }
+
+    // Ranges beyond the end of the stream yield nothing.
+    for &offset in &[
+        3 * SEGMENTSIZE + 100,
+        3 * SEGMENTSIZE + 200,
+        10 * SEGMENTSIZE,
+    ] {
+        let dst = unseal_range(&props, &buf, offset as u64, 10).unwrap();
+        assert!(dst.is_empty());
+    }
+}
+
+#[test]
+fn random_access_authenticates_covered_segments() {
+    let props = DefaultProps::default();
+
+    let mut content = [0u8; 3 * SEGMENTSIZE + 100];
+    rand::thread_rng().fill_bytes(&mut content);
+
+    let mut buf = seal(&props, &content);
+
+    // Corrupt the second segment.
+    let mutation_point = header_len(&buf, content.len()) + SEGMENTSIZE + TAGSIZE + 10;
+    buf[mutation_point] ^= 0x02;
+
+    let dst = unseal_range(&props, &buf, 0, 100).unwrap();
+    assert_eq!(&content[..100], dst.as_slice());
+
+    let dst = unseal_range(&props, &buf, 2 * SEGMENTSIZE as u64, 100).unwrap();
+    assert_eq!(
+        &content[2 * SEGMENTSIZE..2 * SEGMENTSIZE + 100],
+        dst.as_slice()
+    );
+
+    assert!(matches!(
+        unseal_range(&props, &buf, SEGMENTSIZE as u64 - 10, 100),
+        Err(Error::IntegrityViolation)
+    ));
+}
+
+#[test]
+fn random_access_truncated() {
+    let props = DefaultProps::default();
+
+    let content = [0u8; 2 * SEGMENTSIZE];
+    let buf = seal(&props, &content);
+
+    // Without the final segment, reading beyond the last full segment is not mistaken for the end.
+    let truncated = &buf[..buf.len() - TAGSIZE];
+    assert!(matches!(
+        unseal_range(&props, truncated, 2 * SEGMENTSIZE as u64, 10),
+        Err(Error::IntegrityViolation)
+    ));
+    assert!(matches!(
+        unseal_range(
+            &props,
+            truncated,
+            SEGMENTSIZE as u64,
+            2 * SEGMENTSIZE as u64
+        ),
+        Err(Error::IntegrityViolation)
+    ));
+}
+
+#[cfg(feature = "std")]
+mod std_io {
+    use super::*;
+    use crate::io::{IoReader, IoWriter};
+    use std::io::{Cursor, ErrorKind, Read, Write};
+    use std::vec::Vec;
+
+    fn seal_io(props: &DefaultProps, content: &[u8]) -> Vec<u8> {
+        let mut rng = rand::thread_rng();
+
+        let mut s = Sealer::new(
+            &[Recipient::new(props.i.clone())],
+            &PublicKey(props.pk),
+            &mut rng,
+            IoWriter::new(Vec::new()),
+        )
+        .unwrap();
+        std::io::copy(&mut Cursor::new(content), &mut s).unwrap();
+
+        s.finish().unwrap().into_inner()
+    }
+
+    fn unseal_io(props: &DefaultProps, buf: Vec<u8>) -> OpenerUnsealed<IoReader<Cursor<Vec<u8>>>> {
+        let mut rng = rand::thread_rng();
+
+        let (recipients, o) = OpenerSealed::new(IoReader::new(Cursor::new(buf))).unwrap();
+        let usk = ibe::kiltz_vahlis_one::extract_usk(
+            &props.pk,
+            &props.sk,
+            &recipients[0].derive(),
+            &mut rng,
+        );
+
+        o.unseal(0, &UserSecretKey(usk)).unwrap()
+    }
+
+    #[test]
+    fn reflection_io() {
+        let props = DefaultProps::default();
+
+        let mut content = [0u8; 3 * SEGMENTSIZE + 100];
+        rand::thread_rng().fill_bytes(&mut content);
+
+        let buf = seal_io(&props, &content);
+
+        let mut o = unseal_io(&props, buf.clone());
+        let mut dst = Vec::new();
+        o.read_to_end(&mut dst).unwrap();
+        assert_eq!(&content[..], dst.as_slice());
+
+        // Read through a small buffer, such that segments are consumed partially.
+        let mut o = unseal_io(&props, buf.clone());
+        let mut dst = Vec::new();
+        let mut small = [0u8; 100];
+        loop {
+            let n = Read::read(&mut o, &mut small).unwrap();
+            if n == 0 {
+                break;
+            }
+            dst.extend_from_slice(&small[..n]);
+        }
+        assert_eq!(&content[..], dst.as_slice());
+
+        let mut o = unseal_io(&props, buf);
+        let mut dst = IoWriter::new(Vec::new());
+        o.read_range(SEGMENTSIZE as u64 - 10, 20, &mut dst).unwrap();
+        assert_eq!(
+            &content[SEGMENTSIZE - 10..SEGMENTSIZE + 10],
+            dst.into_inner().as_slice()
+        );
+    }
+
+    #[test]
+    fn corrupt_io() {
+        let props = DefaultProps::default();
+
+        let mut buf = seal_io(&props, &[0u8; 1000]);
+        let mutation_point = buf.len() - 5;
+        buf[mutation_point] ^= 0x02;
+
+        let mut o = unseal_io(&props, buf);
+        let err = o.read_to_end(&mut Vec::new()).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+    }
+
+    /// Yields a single byte per read, as a pipe or socket may.
+    struct Trickle(Cursor<Vec<u8>>);
+
+    impl Read for Trickle {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            let len = buf.len().min(1);
+            self.0.read(&mut buf[..len])
+        }
+    }
+
+    #[test]
+    fn short_reads_io() {
+        let props = DefaultProps::default();
+
+        let content = [7u8; SEGMENTSIZE + 100];
+        let buf = seal_io(&props, &content);
+
+        let (recipients, o) = OpenerSealed::new(IoReader::new(Trickle(Cursor::new(buf)))).unwrap();
+        let usk = ibe::kiltz_vahlis_one::extract_usk(
+            &props.pk,
+            &props.sk,
+            &recipients[0].derive(),
+            &mut rand::thread_rng(),
+        );
+        let mut o = o.unseal(0, &UserSecretKey(usk)).unwrap();
+
+        let mut dst = Vec::new();
+        o.read_to_end(&mut dst).unwrap();
+        assert_eq!(&content[..], dst.as_slice());
+
+        // The end of the stream still yields the remaining bytes.
+        let mut r = IoReader::new(Trickle(Cursor::new([1u8, 2, 3].to_vec())));
+        assert_eq!(r.read_bytes(2).unwrap(), &[1, 2]);
+        assert_eq!(r.read_bytes(4).unwrap(), &[3]);
+    }
+
+    struct Failing;
+
+    impl Read for Failing {
+        fn read(&mut self, _: &mut [u8]) -> std::io::Result<usize> {
+            Err(std::io::Error::new(ErrorKind::ConnectionReset, "failing"))
+        }
+    }
+
+    impl Write for Failing {
+        fn write(&mut self, _: &[u8]) -> std::io::Result<usize> {
+            Err(std::io::Error::new(ErrorKind::ConnectionReset, "failing"))
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn propagate_io_errors() {
+        let props = DefaultProps::default();
+        let mut rng = rand::thread_rng();
+
+        let mut w = IoWriter::new(Failing);
+        let res = Sealer::new(
+            &[Recipient::new(props.i.clone())],
+            &PublicKey(props.pk),
+            &mut rng,
+            &mut w,
+        );
+        assert!(matches!(res, Err(Error::Io(e)) if e.kind() == ErrorKind::ConnectionReset));
+
+        let res = OpenerSealed::new(IoReader::new(Failing));
+        assert!(matches!(res, Err(Error::Io(e)) if e.kind() == ErrorKind::ConnectionReset));
+    }
+}
+
+#[cfg(feature = "async")]
+mod asynchronous {
+    use super::*;
+    use crate::io::IoWriter;
+    use futures::executor::block_on;
+    use futures::io::{AsyncRead, Cursor};
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+    use std::pin::Pin;
+    use std::task::{Context, Poll};
+    use std::vec::Vec;
+
+    fn seal_sync(props: &DefaultProps, rng: &mut StdRng, content: &[u8]) -> Vec<u8> {
+        let mut s = Sealer::new(
+            &[Recipient::new(props.i.clone())],
+            &PublicKey(props.pk),
+            rng,
+            IoWriter::new(Vec::new()),
+        )
+        .unwrap();
+        s.write(content).unwrap();
+
+        s.finish().unwrap().into_inner()
+    }
+
+    fn seal_async(props: &DefaultProps, rng: &mut StdRng, content: &[u8]) -> Vec<u8> {
+        block_on(async {
+            let mut s = AsyncSealer::new(
+                &[Recipient::new(props.i.clone())],
+                &PublicKey(props.pk),
+                rng,
+                Vec::new(),
+            )
+            .await?;
+
+            // Write in uneven chunks, to cross segment boundaries.
+            for chunk in content.chunks(1000) {
+                s.write(chunk).await?;
+            }
+
+            s.finish().await
+        })
+        .unwrap()
+    }
+
+    fn unseal_async<R: AsyncRead + Unpin>(props: &DefaultProps, r: R) -> Result<Vec<u8>, Error> {
+        let mut rng = rand::thread_rng();
+
+        block_on(async {
+            let (recipients, o) = AsyncOpenerSealed::new(r).await?;
+            let usk = ibe::kiltz_vahlis_one::extract_usk(
+                &props.pk,
+                &props.sk,
+                &recipients[0].derive(),
+                &mut rng,
+            );
+
+            let mut o = o.unseal(0, &UserSecretKey(usk))?;
+            let mut dst = Vec::new();
+            o.write_to(&mut dst).await?;
+
+            Ok(dst)
+        })
+    }
+
+    /// Yields the bytestream in chunks of at most 7 bytes.
+    struct Trickle(Cursor<Vec<u8>>);
+
+    impl AsyncRead for Trickle {
+        fn poll_read(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context,
+            buf: &mut [u8],
+        ) -> Poll<std::io::Result<usize>> {
+            let n = core::cmp::min(buf.len(), 7);
+            Pin::new(&mut self.0).poll_read(cx, &mut buf[..n])
+        }
+    }
+
+    #[test]
+    fn byte_identical() {
+        let props = DefaultProps::default();
+
+        for &len in &[0, 1, SEGMENTSIZE, 2 * SEGMENTSIZE + 100] {
+            let mut content = std::vec![0u8; len];
+            rand::thread_rng().fill_bytes(&mut content);
+
+            let sync = seal_sync(&props, &mut StdRng::seed_from_u64(len as u64), &content);
+            let asynchronous = seal_async(&props, &mut StdRng::seed_from_u64(len as u64), &content);
+            assert_eq!(sync, asynchronous);
+
+            let dst = unseal_async(&props, Cursor::new(sync)).unwrap();
+            assert_eq!(content, dst);
+        }
+    }
+
+    #[test]
+    fn reflection_async_trickle() {
+        let props = DefaultProps::default();
+
+        let mut content = [0u8; SEGMENTSIZE + 100];
+        rand::thread_rng().fill_bytes(&mut content);
+
+        let buf = seal_async(&props, &mut StdRng::from_entropy(), &content);
+        let dst = unseal_async(&props, Trickle(Cursor::new(buf))).unwrap();
+        assert_eq!(&content[..], dst.as_slice());
+    }
+
+    #[test]
+    fn corrupt_async() {
+        let props = DefaultProps::default();
+
+        let buf = seal_async(&props, &mut StdRng::from_entropy(), &[0u8; 1000]);
+
+        let mut corrupted = buf.clone();
+        let mutation_point = corrupted.len() - 5;
+        corrupted[mutation_point] ^= 0x02;
+        assert!(matches!(
+            unseal_async(&props, Cursor::new(corrupted)),
+            Err(Error::IntegrityViolation)
+        ));
+
+        let truncated = buf[..buf.len() - 1000].to_vec();
+        assert!(matches!(
+            unseal_async(&props, Cursor::new(truncated)),
+            Err(Error::IntegrityViolation)
+        ));
+
+        let truncated = buf[..100].to_vec();
+        assert!(matches!(
+            unseal_async(&props, Cursor::new(truncated)),
+            Err(Error::PrematureEndError)
+        ));
+    }
+
+    #[test]
+    fn sealer_future_is_send() {
+        fn assert_send<T: Send>(_: T) {}
+
+        let props = DefaultProps::default();
+        assert_send(AsyncSealer::new(
+            &[Recipient::new(props.i.clone())],
+            &PublicKey(props.pk),
+            &mut rand::thread_rng(),
+            Vec::new(),
+        ));
+    }
 }
@@ -1,3 +1,6 @@
+use aes_gcm::aead::generic_array::typenum::U12;
+use aes_gcm::aead::generic_array::GenericArray;
+use arrayref::array_refs;
 use arrayvec::{Array, ArrayVec};
 use digest::{Digest, FixedOutput};
 use ibe::kiltz_vahlis_one::SymmetricKey;
@@ -6,27 +9,82 @@ use rand::{CryptoRng, Rng};
 use crate::stream::*;
 use crate::*;
 
-pub(crate) fn derive_keys(key: &SymmetricKey) -> ([u8; KEYSIZE], [u8; KEYSIZE]) {
+/// Derive the one-time pad for a wrapped key from the Kiltz-Vahlis IBE1 symmetric key.
+fn derive_pad(key: &SymmetricKey) -> [u8; WRAPPEDKEYSIZE] {
     let mut h = sha3::Sha3_512::new();
     h.input(key.to_bytes().as_ref());
-    let buf = h.fixed_result();
 
-    let mut aeskey = [0u8; KEYSIZE];
-    let mut mackey = [0u8; KEYSIZE];
+    let mut res = [0u8; WRAPPEDKEYSIZE];
+    res.copy_from_slice(h.fixed_result().as_slice());
+    res
+}
+
+/// Generate a random session key, consisting of an AES key followed by an HMAC key.
+pub(crate) fn generate_keys<R: Rng + CryptoRng>(r: &mut R) -> [u8; WRAPPEDKEYSIZE] {
+    let mut res = [0u8; WRAPPEDKEYSIZE];
+    r.fill_bytes(&mut res);
+    res
+}
+
+/// Split the session key into the AES key and the HMAC key.
+pub(crate) fn split_keys(keys: &[u8; WRAPPEDKEYSIZE]) -> ([u8; KEYSIZE], [u8; KEYSIZE]) {
+    let (aeskey, mackey) = array_refs![keys, KEYSIZE, KEYSIZE];
+    (*aeskey, *mackey)
+}
+
+/// Wrap the share of the session key for a single recipient, using the Kiltz-Vahlis IBE1 symmetric key
+/// generated for that recipient as a one-time pad.
+pub(crate) fn wrap_keys(key: &SymmetricKey, share: &[u8; WRAPPEDKEYSIZE]) -> [u8; WRAPPEDKEYSIZE] {
+    let mut res = [0u8; WRAPPEDKEYSIZE];
+    xor_into(&mut res, share, &derive_pad(key));
+    res
+}
 
-    let (a, b) = buf.as_slice().split_at(KEYSIZE);
-    aeskey.copy_from_slice(&a);
-    mackey.copy_from_slice(&b);
+/// Recover the share of the session key from a wrapped key, inverse of `wrap_keys`.
+pub(crate) fn unwrap_keys(
+    key: &SymmetricKey,
+    wrapped: &[u8; WRAPPEDKEYSIZE],
+) -> [u8; WRAPPEDKEYSIZE] {
+    let mut res = [0u8; WRAPPEDKEYSIZE];
+    xor_into(&mut res, wrapped, &derive_pad(key));
+    res
+}
 
-    (aeskey, mackey)
+fn xor_into(dst: &mut [u8], x: &[u8], y: &[u8]) {
+    for ((d, x), y) in dst.iter_mut().zip(x.iter()).zip(y.iter()) {
+        *d = x ^ y;
+    }
 }
 
-pub(crate) fn generate_iv<R: Rng + CryptoRng>(r: &mut R) -> [u8; IVSIZE] {
-    let mut res = [0u8; IVSIZE];
+pub(crate) fn generate_nonce_prefix<R: Rng + CryptoRng>(r: &mut R) -> [u8; NONCEPREFIXSIZE] {
+    let mut res = [0u8; NONCEPREFIXSIZE];
     r.fill_bytes(&mut res);
     res
 }
 
+/// Compose the AES-GCM nonce for a segment from the nonce prefix, the segment counter and
+/// whether the segment is the final one. This binds each segment to its position in the stream.
+pub(crate) fn segment_nonce(
+    prefix: &[u8; NONCEPREFIXSIZE],
+    counter: u32,
+    last: bool,
+) -> GenericArray<u8, U12> {
+    let mut res = GenericArray::default();
+    res[..NONCEPREFIXSIZE].copy_from_slice(prefix);
+    res[NONCEPREFIXSIZE..NONCEPREFIXSIZE + 4].copy_from_slice(&counter.to_be_bytes());
+    res[NONCEPREFIXSIZE + 4] = u8::from(last);
+    res
+}
+
+/// Compose the AES-GCM nonce for the encrypted metadata section from the nonce prefix.
+/// Its final byte differs from that of all segment nonces, thus it never collides with them.
+pub(crate) fn metadata_nonce(prefix: &[u8; NONCEPREFIXSIZE]) -> GenericArray<u8, U12> {
+    let mut res = GenericArray::default();
+    res[..NONCEPREFIXSIZE].copy_from_slice(prefix);
+    res[NONCEPREFIXSIZE + 4] = 0x02;
+    res
+}
+
 /// Nested Reader that archives all bytes passing through in buf.
 pub(crate) struct ArchiveReader<R: Readable, A: Array> {
     buf: ArrayVec<A>,
@@ -0,0 +1,221 @@
+//! Threshold distribution of the master secret key over several PKG nodes.
+//!
+//! The master secret key is a point in G1, which is split t-of-n using Shamir secret sharing in the
+//! group: each node holds the evaluation of a random polynomial with G1 coefficients, of which the
+//! constant term is the master secret key. A share is a point on this polynomial rather than the
+//! master secret key, and a user secret key extracted using a single share does not decrypt. A node
+//! however extracts its partial user secret keys using the same algorithm as a normal PKG.
+//!
+//! As the user secret key is linear in both the master secret key and the randomness used during
+//! extraction, t partial user secret keys combine into a working user secret key using Lagrange
+//! interpolation. Fewer than t partial keys reveal nothing about the master secret key.
+
+use arrayref::{array_mut_ref, array_refs};
+use arrayvec::ArrayVec;
+use irmaseal_curve::{G1Affine, G1Projective, G2Affine, G2Projective, Scalar};
+use rand::{CryptoRng, Rng};
+
+use crate::util::open_ct;
+use crate::*;
+
+/// The maximum number of nodes over which a master secret key can be split.
+pub const MAX_SHARES: usize = 16;
+
+/// The size of the byte representation of a `SecretKeyShare`.
+pub const SHARESIZE: usize = 1 + 48;
+
+/// The share of a master secret key held by a single PKG node.
+pub struct SecretKeyShare {
+    index: u8,
+    key: ibe::kiltz_vahlis_one::SecretKey,
+}
+
+/// The shares of a master secret key, as yielded by `split`.
+pub type SecretKeyShares = ArrayVec<[SecretKeyShare; MAX_SHARES]>;
+
+fn random_scalar<R: Rng + CryptoRng>(rng: &mut R) -> Scalar {
+    let mut bytes = [0u8; 64];
+    rng.fill_bytes(&mut bytes);
+    Scalar::from_bytes_wide(&bytes)
+}
+
+/// The Lagrange coefficients to interpolate the evaluations at the given indices at zero.
+fn lagrange(indices: &[u8]) -> Result<ArrayVec<[Scalar; MAX_SHARES]>, Error> {
+    if indices.is_empty() || indices.len() > MAX_SHARES {
+        return Err(Error::ConstraintViolation);
+    }
+
+    let mut res = ArrayVec::new();
+    for (i, xi) in indices.iter().enumerate() {
+        if *xi == 0 || indices[..i].contains(xi) {
+            return Err(Error::ConstraintViolation);
+        }
+
+        let mut num = Scalar::one();
+        let mut den = Scalar::one();
+        for xj in indices.iter().filter(|xj| *xj != xi) {
+            num *= Scalar::from(*xj as u64);
+            den *= Scalar::from(*xj as u64) - Scalar::from(*xi as u64);
+        }
+
+        res.push(num * open_ct(den.invert()).ok_or(Error::ConstraintViolation)?);
+    }
+
+    Ok(res)
+}
+
+/// Split the master secret key into `shares` shares, of which any `threshold` can issue user secret keys.
+///
+/// Throws a ConstraintViolation if the threshold is zero or exceeds the number of shares,
+/// or if more than `MAX_SHARES` shares are requested.
+pub fn split<R: Rng + CryptoRng>(
+    sk: &ibe::kiltz_vahlis_one::SecretKey,
+    threshold: u8,
+    shares: u8,
+    rng: &mut R,
+) -> Result<SecretKeyShares, Error> {
+    if threshold == 0 || threshold > shares || shares as usize > MAX_SHARES {
+        return Err(Error::ConstraintViolation);
+    }
+
+    let alpha = open_ct(G1Affine::from_compressed(&sk.to_bytes())).ok_or(Error::FormatViolation)?;
+
+    let coefficients: ArrayVec<[G1Projective; MAX_SHARES]> = (1..threshold)
+        .map(|_| G1Affine::generator() * random_scalar(rng))
+        .collect();
+
+    (1..=shares)
+        .map(|index| {
+            // Horner's method, from the highest degree coefficient down to the master secret key.
+            let x = Scalar::from(index as u64);
+            let y = coefficients
+                .iter()
+                .rev()
+                .fold(G1Projective::identity(), |acc, c| acc * x + c)
+                * x
+                + alpha;
+
+            let key =
+                ibe::kiltz_vahlis_one::SecretKey::from_bytes(&G1Affine::from(y).to_compressed());
+            open_ct(key)
+                .map(|key| SecretKeyShare { index, key })
+                .ok_or(Error::FormatViolation)
+        })
+        .collect()
+}
+
+/// Combine the partial user secret keys issued by distinct PKG nodes, given by the index of their share.
+///
+/// Yields a working user secret key if at least the threshold of partial keys is given.
+/// Throws a ConstraintViolation if an index is zero or occurs more than once.
+pub fn combine(partials: &[(u8, UserSecretKey)]) -> Result<UserSecretKey, Error> {
+    if partials.len() > MAX_SHARES {
+        return Err(Error::ConstraintViolation);
+    }
+
+    let indices: ArrayVec<[u8; MAX_SHARES]> = partials.iter().map(|(index, _)| *index).collect();
+    let coefficients = lagrange(&indices)?;
+
+    let mut d1 = G1Projective::identity();
+    let mut d2 = G2Projective::identity();
+    let mut d3 = G1Projective::identity();
+
+    for ((_, usk), l) in partials.iter().zip(coefficients.iter()) {
+        let bytes = usk.0.to_bytes();
+        let (b1, b2, b3) = array_refs![&bytes, 48, 96, 48];
+
+        d1 += open_ct(G1Affine::from_compressed(b1)).ok_or(Error::FormatViolation)? * l;
+        d2 += open_ct(G2Affine::from_compressed(b2)).ok_or(Error::FormatViolation)? * l;
+        d3 += open_ct(G1Affine::from_compressed(b3)).ok_or(Error::FormatViolation)? * l;
+    }
+
+    let mut bytes = [0u8; 192];
+    *array_mut_ref![bytes, 0, 48] = G1Affine::from(d1).to_compressed();
+    *array_mut_ref![bytes, 48, 96] = G2Affine::from(d2).to_compressed();
+    *array_mut_ref![bytes, 144, 48] = G1Affine::from(d3).to_compressed();
+
+    open_ct(ibe::kiltz_vahlis_one::UserSecretKey::from_bytes(&bytes))
+        .map(UserSecretKey)
+        .ok_or(Error::FormatViolation)
+}
+
+impl SecretKeyShare {
+    /// The index of this share, starting at 1.
+    pub fn index(&self) -> u8 {
+        self.index
+    }
+
+    /// The share as secret key, with which the node extracts its partial user secret keys.
+    pub fn secret_key(&self) -> &ibe::kiltz_vahlis_one::SecretKey {
+        &self.key
+    }
+
+    pub fn to_bytes(&self) -> [u8; SHARESIZE] {
+        let mut res = [0u8; SHARESIZE];
+        res[0] = self.index;
+        res[1..].copy_from_slice(&self.key.to_bytes());
+        res
+    }
+
+    /// Throws a FormatViolation if the index is zero or the key is not a valid secret key.
+    pub fn from_bytes(bytes: &[u8; SHARESIZE]) -> Result<SecretKeyShare, Error> {
+        let (index, key) = array_refs![bytes, 1, 48];
+        if index[0] == 0 {
+            return Err(Error::FormatViolation);
+        }
+
+        open_ct(ibe::kiltz_vahlis_one::SecretKey::from_bytes(key))
+            .map(|key| SecretKeyShare {
+                index: index[0],
+                key,
+            })
+            .ok_or(Error::FormatViolation)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lagrange_interpolation() {
+        // The polynomial 3 + 2x, evaluated at 1 and 3.
+        let l = lagrange(&[1, 3]).unwrap();
+        let y = l[0] * Scalar::from(5) + l[1] * Scalar::from(9);
+        assert_eq!(y, Scalar::from(3));
+    }
+
+    #[test]
+    fn invalid_parameters() {
+        let mut rng = rand::thread_rng();
+        let (_, sk) = ibe::kiltz_vahlis_one::setup(&mut rng);
+
+        assert!(split(&sk, 0, 3, &mut rng).is_err());
+        assert!(split(&sk, 4, 3, &mut rng).is_err());
+        assert!(split(&sk, 2, MAX_SHARES as u8 + 1, &mut rng).is_err());
+        assert_eq!(split(&sk, 1, 1, &mut rng).unwrap().len(), 1);
+
+        assert!(matches!(lagrange(&[]), Err(Error::ConstraintViolation)));
+        assert!(matches!(lagrange(&[0, 1]), Err(Error::ConstraintViolation)));
+        assert!(matches!(
+            lagrange(&[2, 1, 2]),
+            Err(Error::ConstraintViolation)
+        ));
+    }
+
+    #[test]
+    fn share_bytes() {
+        let mut rng = rand::thread_rng();
+        let (_, sk) = ibe::kiltz_vahlis_one::setup(&mut rng);
+
+        let shares = split(&sk, 2, 3, &mut rng).unwrap();
+        let share = SecretKeyShare::from_bytes(&shares[2].to_bytes()).unwrap();
+
+        assert_eq!(share.index(), 3);
+        assert_eq!(share.to_bytes()[..], shares[2].to_bytes()[..]);
+
+        let mut bytes = share.to_bytes();
+        bytes[0] = 0;
+        assert!(SecretKeyShare::from_bytes(&bytes).is_err());
+    }
+}
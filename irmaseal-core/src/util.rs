@@ -14,7 +14,7 @@ impl<'a, T> SliceReader<'a, T> {
 
 impl<'a> Readable for SliceReader<'a, u8> {
     fn read_byte(&mut self) -> Result<u8, Error> {
-        if self.buf.len() < self.i {
+        if self.buf.len() <= self.i {
             return Err(Error::EndOfStream);
         }
 
@@ -37,12 +37,25 @@ impl<'a> Readable for SliceReader<'a, u8> {
         }
 
         let res = &self.buf[self.i..end];
-        self.i += n;
+        self.i = end;
 
         Ok(res)
     }
 }
 
+impl<'a> Seekable for SliceReader<'a, u8> {
+    fn seek(&mut self, pos: u64) -> Result<(), Error> {
+        use core::convert::TryFrom;
+
+        self.i = usize::try_from(pos).or(Err(Error::EndOfStream))?;
+        Ok(())
+    }
+
+    fn stream_len(&mut self) -> Result<u64, Error> {
+        Ok(self.buf.len() as u64)
+    }
+}
+
 impl<A: Array<Item = u8>> Writable for ArrayVec<A> {
     fn write(&mut self, data: &[u8]) -> Result<(), Error> {
         unsafe {
@@ -52,7 +65,7 @@ impl<A: Array<Item = u8>> Writable for ArrayVec<A> {
                 return Err(Error::UpstreamWritableError);
             }
 
-            let tail = core::slice::from_raw_parts_mut(self.get_unchecked_mut(len), data.len());
+            let tail = core::slice::from_raw_parts_mut(self.as_mut_ptr().add(len), data.len());
             tail.copy_from_slice(data);
 
             self.set_len(len + data.len());
@@ -68,3 +81,18 @@ pub(crate) fn open_ct<T>(x: subtle::CtOption<T>) -> Option<T> {
         None
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn short_final_read() {
+        let mut r = SliceReader::new(&[1u8, 2, 3]);
+
+        assert_eq!(r.read_bytes(2).unwrap(), &[1, 2]);
+        assert_eq!(r.read_bytes(4).unwrap(), &[3]);
+        assert!(matches!(r.read_bytes(1), Err(Error::EndOfStream)));
+        assert!(matches!(r.read_byte(), Err(Error::EndOfStream)));
+    }
+}
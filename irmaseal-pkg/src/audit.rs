@@ -0,0 +1,266 @@
+//! Append-only audit log of every user secret key issued by the PKG.
+//!
+//! The log is a file with a JSON entry per line. Every entry commits to the previous one by including
+//! the SHA3-256 hash of its line, such that altering, removing or reordering entries breaks the chain.
+//! Truncating the log is only detected when the hash of its last entry has been recorded elsewhere,
+//! which is why it is printed by `irmaseal-pkg audit verify`.
+//!
+//! Attribute values and session tokens are recorded hashed only. Note that the hash of a value with
+//! little entropy, such as a BSN, can be brute-forced; it allows to check whether a key was issued for
+//! a given value, not to list those values.
+
+use clap::ArgMatches;
+use irmaseal_core::{Identity, KeyId};
+use serde::Serialize;
+use sha3::{Digest, Sha3_256};
+
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
+use std::sync::Mutex;
+
+use crate::util::now;
+use crate::Error;
+
+type Hash = [u8; 32];
+
+fn hash(domain: &[u8], data: &[u8]) -> Hash {
+    let mut h = Sha3_256::new();
+    h.input(domain);
+    h.input(data);
+
+    let mut res = [0u8; 32];
+    res.copy_from_slice(&h.result());
+    res
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// An attribute of the identity for which a key was issued, of which the value is hashed.
+#[derive(Serialize)]
+pub struct AuditAttribute {
+    #[serde(rename = "type")]
+    pub atype: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub value: Option<String>,
+}
+
+/// A single issued user secret key.
+#[derive(Serialize)]
+pub struct Entry {
+    /// The UNIX timestamp at which the key was issued.
+    pub time: u64,
+    /// How the key was issued, either `fetch` in a session once verified by an authenticator, or
+    /// `extract` by an administrator.
+    pub operation: &'static str,
+    /// The key ID of the master key under which the key was issued.
+    pub key_id: KeyId,
+    /// The index of the share of the master key, if this PKG is a node of a threshold PKG.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub share: Option<u8>,
+    pub attributes: Vec<AuditAttribute>,
+    /// The timestamp of the identity.
+    pub timestamp: u64,
+    /// The hash of the session token, if issued in a session.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub session: Option<String>,
+    /// The reason given by the administrator.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+}
+
+impl Entry {
+    /// An entry for a key issued now for the identity.
+    pub fn new(
+        operation: &'static str,
+        key_id: KeyId,
+        share: Option<u8>,
+        identity: &Identity,
+    ) -> Entry {
+        let attributes = identity
+            .attributes
+            .iter()
+            .map(|a| AuditAttribute {
+                atype: a.atype.to_string(),
+                value: a
+                    .value
+                    .as_ref()
+                    .map(|v| hex(&hash(b"irmaseal-pkg audit value", v.as_bytes()))),
+            })
+            .collect();
+
+        Entry {
+            time: now(),
+            operation,
+            key_id,
+            share,
+            attributes,
+            timestamp: identity.timestamp,
+            session: None,
+            reason: None,
+        }
+    }
+
+    /// Record the session in which the key was issued.
+    pub fn session(self, token: &str) -> Entry {
+        Entry {
+            session: Some(hex(&hash(b"irmaseal-pkg audit session", token.as_bytes()))),
+            ..self
+        }
+    }
+}
+
+/// The line as written to the log.
+#[derive(Serialize)]
+struct Line<'a> {
+    /// The hash of the previous line, or zeros for the first line.
+    prev: String,
+    #[serde(flatten)]
+    entry: &'a Entry,
+}
+
+/// Check the chain of the log, yielding the number of entries and the hash of the last one.
+///
+/// Throws the number of the first line that breaks the chain, counting from 1.
+pub fn verify(path: impl AsRef<Path>) -> Result<(usize, Hash), usize> {
+    let f = match File::open(path) {
+        Ok(f) => f,
+        Err(_) => return Ok((0, [0u8; 32])),
+    };
+
+    let mut head = [0u8; 32];
+    let mut count = 0;
+    for line in BufReader::new(f).split(b'\n') {
+        let line = line.or(Err(count + 1))?;
+        let value: serde_json::Value = serde_json::from_slice(&line).or(Err(count + 1))?;
+
+        if value["prev"].as_str() != Some(hex(&head).as_str()) {
+            return Err(count + 1);
+        }
+
+        head = hash(b"irmaseal-pkg audit entry", &line);
+        count += 1;
+    }
+
+    Ok((count, head))
+}
+
+/// An opened audit log, shared by all workers of the PKG.
+pub struct AuditLog {
+    state: Mutex<(File, Hash)>,
+}
+
+impl AuditLog {
+    /// Open the log, which is created readable only by the owner if it does not exist.
+    ///
+    /// Throws an AuditError if the chain of the existing log is broken.
+    pub fn open(path: impl AsRef<Path>) -> Result<AuditLog, Error> {
+        let path = path.as_ref();
+        let (_, head) = verify(path).or(Err(Error::AuditError))?;
+
+        let f = OpenOptions::new()
+            .append(true)
+            .create(true)
+            .mode(0o600)
+            .open(path)
+            .or(Err(Error::AuditError))?;
+
+        Ok(AuditLog {
+            state: Mutex::new((f, head)),
+        })
+    }
+
+    /// Append an entry to the log, which is flushed to disk before returning, such that no key is
+    /// issued without a trace.
+    ///
+    /// As this blocks on the disk, handlers should call it using `web::block`.
+    pub fn append(&self, entry: &Entry) -> Result<(), Error> {
+        let mut state = self.state.lock().unwrap();
+        let (f, head) = &mut *state;
+
+        let mut line = serde_json::to_vec(&Line {
+            prev: hex(head),
+            entry,
+        })
+        .or(Err(Error::AuditError))?;
+
+        let next = hash(b"irmaseal-pkg audit entry", &line);
+        line.push(b'\n');
+
+        f.write_all(&line).or(Err(Error::AuditError))?;
+        f.sync_all().or(Err(Error::AuditError))?;
+
+        *head = next;
+        Ok(())
+    }
+}
+
+pub fn exec(m: &ArgMatches) {
+    if let Some(m) = m.subcommand_matches("verify") {
+        let audit = m.value_of("audit").unwrap();
+
+        match verify(audit) {
+            Ok((count, head)) => {
+                println!("Verified {} entries of {}", count, audit);
+                println!("Hash of the last entry {}", hex(&head));
+            }
+            Err(line) => {
+                eprintln!("The chain of {} is broken at line {}", audit, line);
+                std::process::exit(1);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(value: &str) -> Entry {
+        let identity = Identity::new(1577836800, "pbdf.pbdf.email.email", Some(value)).unwrap();
+        Entry::new("fetch", KeyId([1u8; 8]), None, &identity).session("token")
+    }
+
+    #[test]
+    fn chain() {
+        let path = std::env::temp_dir().join(format!("irmaseal-audit-{}", std::process::id()));
+
+        {
+            let log = AuditLog::open(&path).unwrap();
+            log.append(&entry("w.geraedts@sarif.nl")).unwrap();
+            log.append(&entry("l.botros@cs.ru.nl")).unwrap();
+        }
+
+        // Reopening continues the chain.
+        AuditLog::open(&path)
+            .unwrap()
+            .append(&entry("w.geraedts@sarif.nl"))
+            .unwrap();
+        let (count, head) = verify(&path).unwrap();
+        assert_eq!(count, 3);
+        assert_ne!(head, [0u8; 32]);
+
+        let log = std::fs::read_to_string(&path).unwrap();
+        assert!(!log.contains("sarif.nl"));
+
+        let lines: Vec<&str> = log.lines().collect();
+        let value: serde_json::Value = serde_json::from_str(lines[1]).unwrap();
+        assert_eq!(value["operation"], "fetch");
+        assert_eq!(value["attributes"][0]["type"], "pbdf.pbdf.email.email");
+
+        // Altering or removing an entry breaks the chain at the entry after it.
+        let altered = log.replacen("1577836800", "1577836801", 1);
+        std::fs::write(&path, altered).unwrap();
+        assert_eq!(verify(&path), Err(2));
+
+        let removed = format!("{}\n{}\n", lines[0], lines[2]);
+        std::fs::write(&path, removed).unwrap();
+        assert_eq!(verify(&path), Err(2));
+        assert!(AuditLog::open(&path).is_err());
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
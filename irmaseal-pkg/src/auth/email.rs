@@ -0,0 +1,411 @@
+use futures::future::{err, result, Future};
+use hmac::{Hmac, Mac};
+use irmaseal_core::api::KeyStatus;
+use irmaseal_core::Identity;
+use rand::RngCore;
+use sha3::{Digest, Sha3_256};
+use subtle::ConstantTimeEq;
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use crate::auth::{is_plain_address, AuthStatus, Authenticator, Challenge, Relay};
+use crate::session::{SessionKey, MAX_SESSION_AGE};
+use crate::util::now;
+use crate::Error;
+
+/// The only attribute of which the e-mail authenticator verifies identities.
+pub const EMAIL_ATTRIBUTE: &str = "pbdf.pbdf.email.email";
+
+/// The number of wrong codes after which a session is locked.
+pub const MAX_ATTEMPTS: u8 = 5;
+
+/// The delay in seconds after the first code sent to an address, doubling with every next code.
+const SEND_DELAY: u64 = 60;
+
+/// The delay in seconds after the first wrong code for an address, doubling with every next one.
+const FAILURE_DELAY: u64 = 2;
+
+/// The longest delay in seconds between two codes sent to, or entered for, an address.
+const MAX_DELAY: u64 = 3600;
+
+/// The number of seconds after which an address without codes sent or entered is forgotten.
+const QUIET_PERIOD: u64 = 24 * 3600;
+
+const SUBJECT: &str = "Your IRMAseal code";
+
+/// Exponential backoff of repeated events for an address.
+#[derive(Default)]
+struct Backoff {
+    count: u32,
+    last: u64,
+}
+
+impl Backoff {
+    /// The UNIX timestamp from which the next event is allowed, after the given initial delay.
+    fn next(&self, delay: u64) -> u64 {
+        match self.count {
+            0 => 0,
+            n => self.last + delay.checked_shl(n - 1).unwrap_or(MAX_DELAY).min(MAX_DELAY),
+        }
+    }
+
+    /// Record an event at `now`, throwing a TooManyAttempts if it came too soon.
+    fn record(&mut self, delay: u64, now: u64) -> Result<(), Error> {
+        if now < self.next(delay) {
+            return Err(Error::TooManyAttempts);
+        }
+        self.count += 1;
+        self.last = now;
+        Ok(())
+    }
+}
+
+/// The limits on sending and entering codes.
+#[derive(Default)]
+struct Limits {
+    /// The moment of the first wrong code and the number of wrong codes, per session token.
+    sessions: HashMap<String, (u64, u8)>,
+    /// The codes sent per address.
+    sent: HashMap<String, Backoff>,
+    /// The wrong codes per address, of all sessions.
+    failures: HashMap<String, Backoff>,
+}
+
+impl Limits {
+    /// Forget expired sessions and quiet addresses.
+    fn prune(&mut self, now: u64) {
+        self.sessions
+            .retain(|_, (since, _)| *since + MAX_SESSION_AGE >= now);
+        self.sent.retain(|_, b| b.last + QUIET_PERIOD >= now);
+        self.failures.retain(|_, b| b.last + QUIET_PERIOD >= now);
+    }
+}
+
+/// Verification of an e-mail address by a code sent to it.
+///
+/// The code is derived from the token of the session and the identity, such that every instance of
+/// the PKG accepts it. A session is locked after `MAX_ATTEMPTS` wrong codes. As anyone can start
+/// sessions for an address, both the codes sent to an address and the wrong codes entered for it
+/// are delayed exponentially, up to an hour. These limits are only kept in the memory of the instance.
+pub struct EmailAuthenticator {
+    relay: Arc<Relay>,
+    from: String,
+    key: [u8; 32],
+    limits: Mutex<Limits>,
+}
+
+impl EmailAuthenticator {
+    /// Send the codes from the given address through the relay, deriving them using the session key
+    /// such that every instance of the PKG accepts the codes of the others.
+    pub fn new(relay: Relay, from: &str, session_key: &SessionKey) -> EmailAuthenticator {
+        let mut h = Sha3_256::new();
+        h.input(b"irmaseal-pkg email");
+        h.input(session_key);
+
+        let mut key = [0u8; 32];
+        key.copy_from_slice(&h.result());
+
+        EmailAuthenticator {
+            relay: Arc::new(relay),
+            from: from.to_string(),
+            key,
+            limits: Mutex::new(Limits::default()),
+        }
+    }
+
+    /// The code of eight digits for the session.
+    pub(crate) fn code(&self, token: &str, identity: &Identity) -> String {
+        let mut mac = Hmac::<Sha3_256>::new_varkey(&self.key).unwrap();
+        mac.input(token.as_bytes());
+        mac.input(&serde_json::to_vec(identity).unwrap());
+
+        let mut n = [0u8; 8];
+        n.copy_from_slice(&mac.result().code()[..8]);
+        format!("{:08}", u64::from_be_bytes(n) % 100_000_000)
+    }
+
+    /// Record a code sent to the address at `now`, throwing a TooManyAttempts if the previous code
+    /// was sent too recently.
+    fn send_allowed(&self, to: &str, now: u64) -> Result<(), Error> {
+        let mut limits = self.limits.lock().unwrap();
+        limits.prune(now);
+        limits
+            .sent
+            .entry(to.to_string())
+            .or_default()
+            .record(SEND_DELAY, now)
+    }
+
+    /// Check the code entered at `now` for the session.
+    ///
+    /// Throws a SessionLocked after `MAX_ATTEMPTS` wrong codes for the session, and a TooManyAttempts
+    /// if the previous wrong code for the address was entered too recently.
+    fn attempt(
+        &self,
+        token: &str,
+        identity: &Identity,
+        code: &str,
+        now: u64,
+    ) -> Result<AuthStatus, Error> {
+        let to = address(identity).ok_or(Error::UnsupportedMethod)?;
+
+        let mut limits = self.limits.lock().unwrap();
+        limits.prune(now);
+
+        let failures = limits.sessions.get(token).map_or(0, |(_, f)| *f);
+        if failures >= MAX_ATTEMPTS {
+            return Err(Error::SessionLocked);
+        }
+        let backoff = limits.failures.entry(to.clone()).or_default();
+        if now < backoff.next(FAILURE_DELAY) {
+            return Err(Error::TooManyAttempts);
+        }
+
+        let code: String = code.chars().filter(|c| !c.is_whitespace()).collect();
+        let expected = self.code(token, identity);
+
+        if bool::from(code.as_bytes().ct_eq(expected.as_bytes())) {
+            limits.failures.remove(&to);
+            Ok(AuthStatus::Verified(Box::new(identity.clone())))
+        } else {
+            backoff.record(FAILURE_DELAY, now)?;
+            limits
+                .sessions
+                .entry(token.to_string())
+                .or_insert((now, 0))
+                .1 += 1;
+            Ok(AuthStatus::Unverified(KeyStatus::DoneInvalid))
+        }
+    }
+}
+
+/// The address to verify, if the identity consists of a plain e-mail address only.
+fn address(identity: &Identity) -> Option<String> {
+    match &identity.attributes[..] {
+        [a] if a.atype.as_str() == EMAIL_ATTRIBUTE => a
+            .value
+            .filter(|v| is_plain_address(v))
+            .map(|v| v.to_string()),
+        _ => None,
+    }
+}
+
+impl Authenticator for EmailAuthenticator {
+    /// Throws an UnsupportedMethod if the identity does not consist of an e-mail address only.
+    fn start(&self, identity: &Identity) -> Box<dyn Future<Item = Challenge, Error = Error>> {
+        let to = match address(identity) {
+            Some(to) => to,
+            None => return Box::new(err(Error::UnsupportedMethod)),
+        };
+        if let Err(e) = self.send_allowed(&to, now()) {
+            return Box::new(err(e));
+        }
+
+        let mut nonce = [0u8; 16];
+        rand::thread_rng().fill_bytes(&mut nonce);
+        let token = base64::encode_config(nonce, base64::URL_SAFE_NO_PAD);
+
+        let body = format!(
+            "Your code to decrypt the IRMAseal messages for {} is\n\n    {}\n\n\
+             The code is valid for 10 minutes. If you did not request it, you can ignore this message.\n",
+            to,
+            self.code(&token, identity)
+        );
+
+        let relay = self.relay.clone();
+        let from = self.from.clone();
+
+        Box::new(
+            actix_web::web::block(move || relay.send(&from, &to, SUBJECT, &body))
+                .map_err(|_| Error::UpstreamError)
+                .map(|_| Challenge {
+                    qr: String::new(),
+                    token,
+                }),
+        )
+    }
+
+    fn status(
+        &self,
+        token: &str,
+        identity: &Identity,
+        response: Option<&str>,
+    ) -> Box<dyn Future<Item = AuthStatus, Error = Error>> {
+        let status = match response {
+            None => Ok(AuthStatus::Unverified(KeyStatus::Initialized)),
+            Some(code) => self.attempt(token, identity, code, now()),
+        };
+
+        Box::new(result(status))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::TcpListener;
+
+    /// Accept a single message, yielding the commands and data received.
+    fn capture(listener: TcpListener) -> std::thread::JoinHandle<String> {
+        std::thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut writer = stream.try_clone().unwrap();
+            let mut reader = BufReader::new(stream);
+            let mut received = String::new();
+
+            writer.write_all(b"220 capture\r\n").unwrap();
+            let mut data = false;
+            loop {
+                let mut line = String::new();
+                if reader.read_line(&mut line).unwrap() == 0 {
+                    break;
+                }
+                received.push_str(&line);
+
+                let reply: &[u8] = if data {
+                    if line != ".\r\n" {
+                        continue;
+                    }
+                    data = false;
+                    b"250 queued\r\n"
+                } else if line.starts_with("EHLO") {
+                    b"250-capture\r\n250 AUTH PLAIN\r\n"
+                } else if line.starts_with("AUTH") {
+                    b"235 ok\r\n"
+                } else if line.starts_with("DATA") {
+                    data = true;
+                    b"354 go ahead\r\n"
+                } else if line.starts_with("QUIT") {
+                    writer.write_all(b"221 bye\r\n").unwrap();
+                    break;
+                } else {
+                    b"250 ok\r\n"
+                };
+                writer.write_all(reply).unwrap();
+            }
+
+            received
+        })
+    }
+
+    #[test]
+    fn verification() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let relay = Relay::new(
+            &listener.local_addr().unwrap().to_string(),
+            Some(("pkg".to_string(), "secret".to_string())),
+        );
+        let received = capture(listener);
+
+        let auth = EmailAuthenticator::new(relay, "pkg@example.com", &[0x42u8; 32]);
+        let identity =
+            Identity::new(1566722100, EMAIL_ATTRIBUTE, Some("w.geraedts@sarif.nl")).unwrap();
+
+        let challenge = auth.start(&identity).wait().unwrap();
+        let received = received.join().unwrap();
+        assert!(received.contains("RCPT TO:<w.geraedts@sarif.nl>\r\n"));
+        assert!(received.contains("AUTH PLAIN AHBrZwBzZWNyZXQ=\r\n"));
+
+        // The code is only found in the message.
+        let code = auth.code(&challenge.token, &identity);
+        assert!(received.contains(&code));
+
+        assert!(matches!(
+            auth.status(&challenge.token, &identity, None).wait(),
+            Ok(AuthStatus::Unverified(KeyStatus::Initialized))
+        ));
+        let attempt = |code, now| auth.attempt(&challenge.token, &identity, code, now);
+        assert!(matches!(
+            attempt("00000000", 1566722110),
+            Ok(AuthStatus::Unverified(KeyStatus::DoneInvalid))
+        ));
+        assert!(
+            matches!(attempt(&code, 1566722112), Ok(AuthStatus::Verified(i)) if *i == identity)
+        );
+
+        // Only identities consisting of an e-mail address are verified.
+        let other =
+            Identity::new(1566722100, "pbdf.gemeente.personalData.bsn", Some("123")).unwrap();
+        assert!(matches!(
+            auth.start(&other).wait(),
+            Err(Error::UnsupportedMethod)
+        ));
+    }
+
+    #[test]
+    fn lockout() {
+        let relay = Relay::new("127.0.0.1:25", None);
+        let auth = EmailAuthenticator::new(relay, "pkg@example.com", &[0x42u8; 32]);
+        let identity =
+            Identity::new(1566722100, EMAIL_ATTRIBUTE, Some("w.geraedts@sarif.nl")).unwrap();
+        let code = auth.code("token", &identity);
+        let attempt = |token, code, now| auth.attempt(token, &identity, code, now);
+
+        // A session is locked after too many wrong codes, even once the backoff has passed.
+        let mut now = 1566722110;
+        for i in 0..MAX_ATTEMPTS {
+            assert!(matches!(
+                attempt("token", "00000000", now),
+                Ok(AuthStatus::Unverified(KeyStatus::DoneInvalid))
+            ));
+            now += FAILURE_DELAY << i;
+        }
+        assert!(matches!(
+            attempt("token", &code, now),
+            Err(Error::SessionLocked)
+        ));
+
+        // Expired sessions are forgotten.
+        assert!(attempt("token", &code, 1566722110 + MAX_SESSION_AGE + 1).is_ok());
+    }
+
+    #[test]
+    fn many_sessions() {
+        let relay = Relay::new("127.0.0.1:25", None);
+        let auth = EmailAuthenticator::new(relay, "pkg@example.com", &[0x42u8; 32]);
+        let identity =
+            Identity::new(1566722100, EMAIL_ATTRIBUTE, Some("w.geraedts@sarif.nl")).unwrap();
+        let other = Identity::new(1566722100, EMAIL_ATTRIBUTE, Some("l.botros@cs.ru.nl")).unwrap();
+
+        // Codes sent to an address are delayed exponentially, whichever session they are for.
+        let mut now = 1566722110;
+        for i in 0..8 {
+            auth.send_allowed("w.geraedts@sarif.nl", now).unwrap();
+            let delay = (SEND_DELAY << i).min(MAX_DELAY);
+            assert!(matches!(
+                auth.send_allowed("w.geraedts@sarif.nl", now + delay - 1),
+                Err(Error::TooManyAttempts)
+            ));
+            now += delay;
+        }
+        auth.send_allowed("l.botros@cs.ru.nl", 1566722110).unwrap();
+
+        // Wrong codes for an address are delayed exponentially over all sessions, which allows
+        // fewer than fifty guesses a day.
+        let mut now = 1566722110;
+        let mut guesses = 0;
+        while now < 1566722110 + 86_400 {
+            let token = format!("session-{}", guesses);
+            assert!(matches!(
+                auth.attempt(&token, &identity, "00000000", now),
+                Ok(AuthStatus::Unverified(KeyStatus::DoneInvalid))
+            ));
+            let delay = (FAILURE_DELAY << guesses.min(20)).min(MAX_DELAY);
+            assert!(matches!(
+                auth.attempt("another", &identity, "00000000", now + delay - 1),
+                Err(Error::TooManyAttempts)
+            ));
+            now += delay;
+            guesses += 1;
+        }
+        assert!(guesses < 50);
+
+        // Other addresses are unaffected, and the right code resets the backoff.
+        assert!(auth.attempt("session", &other, "00000000", now - 1).is_ok());
+        let code = auth.code("session", &identity);
+        assert!(auth.attempt("session", &identity, &code, now).is_ok());
+        assert!(auth.attempt("session", &identity, "00000000", now).is_ok());
+    }
+}
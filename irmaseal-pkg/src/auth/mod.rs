@@ -0,0 +1,103 @@
+//! Authenticators verifying that the requester of a key has the attributes of its identity.
+//!
+//! Every key request starts a session with the authenticator of the requested method, of which the
+//! token is carried in the session descriptor. The status of that session is polled until the
+//! authenticator yields the verified identity. IRMA verifies any identity by disclosure of its
+//! attributes, whereas e-mail verifies a `pbdf.pbdf.email.email` attribute by a code sent to it.
+
+mod email;
+mod irma;
+mod smtp;
+
+pub use self::irma::*;
+pub use email::*;
+pub use smtp::*;
+
+use clap::ArgMatches;
+use futures::future::Future;
+use irmaseal_core::api::{AuthMethod, KeyStatus};
+use irmaseal_core::Identity;
+use jsonwebtoken::DecodingKey;
+
+use crate::session::SessionKey;
+use crate::Error;
+
+/// The environment variable holding the password of the SMTP relay.
+pub const SMTP_PASSWORD: &str = "IRMASEAL_SMTP_PASSWORD";
+
+/// The challenge with which an authenticator started a session.
+pub struct Challenge {
+    /// The QR code to be scanned using the IRMA app, or empty if not applicable.
+    pub qr: String,
+    /// The token with which the status of the session is polled.
+    pub token: String,
+}
+
+/// The status of a session of an authenticator.
+pub enum AuthStatus {
+    /// The identity has not been verified, for the given reason as reported to the client.
+    Unverified(KeyStatus),
+    /// The requester has been verified to have the identity, of which all attribute values are filled in.
+    Verified(Box<Identity>),
+}
+
+pub trait Authenticator: Send + Sync {
+    /// Start a session in which the identity is verified.
+    fn start(&self, identity: &Identity) -> Box<dyn Future<Item = Challenge, Error = Error>>;
+
+    /// Poll the status of the session with the given token, started for the identity.
+    ///
+    /// The response is what the user entered to answer the challenge, if any.
+    fn status(
+        &self,
+        token: &str,
+        identity: &Identity,
+        response: Option<&str>,
+    ) -> Box<dyn Future<Item = AuthStatus, Error = Error>>;
+}
+
+/// The authenticators of the PKG, of which IRMA is always available.
+pub struct Authenticators {
+    irma: IrmaAuthenticator,
+    email: Option<EmailAuthenticator>,
+}
+
+impl Authenticators {
+    pub fn new(irma: IrmaAuthenticator, email: Option<EmailAuthenticator>) -> Authenticators {
+        Authenticators { irma, email }
+    }
+
+    /// The authenticators given on the command line, of which e-mail is only available if a relay is given.
+    pub fn from_args(m: &ArgMatches, session_key: &SessionKey) -> Authenticators {
+        let irma_key = std::fs::read(m.value_of("irma-key").unwrap()).unwrap();
+        let irma = IrmaAuthenticator::new(
+            m.value_of("irma").unwrap(),
+            DecodingKey::from_rsa_pem(&irma_key).unwrap().into_static(),
+        );
+
+        let email = m.value_of("smtp").map(|addr| {
+            let credentials = m
+                .value_of("smtp-user")
+                .map(|user| (user.to_string(), std::env::var(SMTP_PASSWORD).unwrap()));
+
+            EmailAuthenticator::new(
+                Relay::new(addr, credentials),
+                m.value_of("smtp-from").unwrap(),
+                session_key,
+            )
+        });
+
+        Authenticators::new(irma, email)
+    }
+
+    /// Throws an UnsupportedMethod if the authenticator of the method is not available.
+    pub fn get(&self, method: AuthMethod) -> Result<&dyn Authenticator, Error> {
+        match method {
+            AuthMethod::Irma => Ok(&self.irma),
+            AuthMethod::Email => match &self.email {
+                Some(email) => Ok(email),
+                None => Err(Error::UnsupportedMethod),
+            },
+        }
+    }
+}
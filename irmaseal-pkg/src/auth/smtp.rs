@@ -0,0 +1,118 @@
+//! A minimal SMTP client, submitting messages to a relay.
+//!
+//! The connection is not encrypted, thus the relay should run on the same host or within a trusted
+//! network, from which it delivers the messages onward.
+
+use std::io::{BufRead, BufReader, Error, ErrorKind, Result, Write};
+use std::net::TcpStream;
+use std::time::Duration;
+
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+/// An SMTP relay, optionally requiring a user and password.
+pub struct Relay {
+    addr: String,
+    credentials: Option<(String, String)>,
+}
+
+struct Connection {
+    reader: BufReader<TcpStream>,
+    writer: TcpStream,
+}
+
+impl Connection {
+    /// Read a reply, which may span multiple lines, throwing if its code is not the expected one.
+    fn expect(&mut self, code: &str) -> Result<()> {
+        loop {
+            let mut line = String::new();
+            if self.reader.read_line(&mut line)? == 0 {
+                return Err(Error::new(ErrorKind::UnexpectedEof, "connection closed"));
+            }
+            if !line.starts_with(code) {
+                return Err(Error::other(line.trim_end().to_string()));
+            }
+
+            // The last line of a reply has a space after its code.
+            if line.as_bytes().get(3) != Some(&b'-') {
+                return Ok(());
+            }
+        }
+    }
+
+    fn command(&mut self, command: &str, code: &str) -> Result<()> {
+        self.writer.write_all(command.as_bytes())?;
+        self.writer.write_all(b"\r\n")?;
+        self.expect(code)
+    }
+}
+
+/// Whether the address can be used as is in commands and headers.
+pub fn is_plain_address(address: &str) -> bool {
+    let mut parts = address.split('@');
+    let valid = match (parts.next(), parts.next(), parts.next()) {
+        (Some(local), Some(domain), None) => !local.is_empty() && !domain.is_empty(),
+        _ => false,
+    };
+
+    valid
+        && address
+            .chars()
+            .all(|c| c.is_ascii_graphic() && !"<>()[],;:\\\"".contains(c))
+}
+
+impl Relay {
+    pub fn new(addr: &str, credentials: Option<(String, String)>) -> Relay {
+        Relay {
+            addr: addr.to_string(),
+            credentials,
+        }
+    }
+
+    /// Submit a message with the given subject and plain text body.
+    ///
+    /// Throws an InvalidInput if either address is not a plain address.
+    pub fn send(&self, from: &str, to: &str, subject: &str, body: &str) -> Result<()> {
+        if !is_plain_address(from) || !is_plain_address(to) || subject.contains(&['\r', '\n'][..]) {
+            return Err(Error::new(ErrorKind::InvalidInput, "invalid address"));
+        }
+
+        let stream = TcpStream::connect(&self.addr)?;
+        stream.set_read_timeout(Some(TIMEOUT))?;
+        stream.set_write_timeout(Some(TIMEOUT))?;
+
+        let mut c = Connection {
+            reader: BufReader::new(stream.try_clone()?),
+            writer: stream,
+        };
+
+        let domain = from.rsplit('@').next().unwrap();
+        c.expect("220")?;
+        c.command(&format!("EHLO {}", domain), "250")?;
+
+        if let Some((user, password)) = &self.credentials {
+            let plain = base64::encode(format!("\0{}\0{}", user, password));
+            c.command(&format!("AUTH PLAIN {}", plain), "235")?;
+        }
+
+        c.command(&format!("MAIL FROM:<{}>", from), "250")?;
+        c.command(&format!("RCPT TO:<{}>", to), "25")?;
+        c.command("DATA", "354")?;
+
+        let mut data = format!(
+            "From: <{}>\r\nTo: <{}>\r\nSubject: {}\r\nMIME-Version: 1.0\r\nContent-Type: text/plain; charset=utf-8\r\n\r\n",
+            from, to, subject
+        );
+        // Lines starting with a dot are escaped, such that the body can not end the message.
+        for line in body.lines() {
+            if line.starts_with('.') {
+                data.push('.');
+            }
+            data.push_str(line);
+            data.push_str("\r\n");
+        }
+        data.push('.');
+
+        c.command(&data, "250")?;
+        c.command("QUIT", "221")
+    }
+}
@@ -0,0 +1,41 @@
+use ibe::kiltz_vahlis_one::{Identity, PublicKey, SecretKey};
+use irmaseal_core::UserSecretKey;
+
+use std::path::Path;
+
+use crate::backend::KeyBackend;
+use crate::keyfile::Passphrase;
+use crate::session::{derive_session_key, SessionKey};
+use crate::util::read_sk;
+use crate::Error;
+
+/// The master secret key in process memory.
+pub struct FileBackend {
+    sk: SecretKey,
+}
+
+impl FileBackend {
+    pub fn new(sk: SecretKey) -> FileBackend {
+        FileBackend { sk }
+    }
+
+    /// Read the master secret key, or a share of it together with the index of that share.
+    pub fn read(
+        path: impl AsRef<Path>,
+        passphrase: &Passphrase,
+    ) -> Result<(FileBackend, Option<u8>), Error> {
+        let (sk, share) = read_sk(path, passphrase)?;
+        Ok((FileBackend::new(sk), share))
+    }
+}
+
+impl KeyBackend for FileBackend {
+    fn extract_usk(&self, pk: &PublicKey, id: &Identity) -> Result<UserSecretKey, Error> {
+        let mut rng = rand::thread_rng();
+        Ok(ibe::kiltz_vahlis_one::extract_usk(pk, &self.sk, id, &mut rng).into())
+    }
+
+    fn session_key(&self) -> Result<SessionKey, Error> {
+        Ok(derive_session_key(&self.sk))
+    }
+}
@@ -0,0 +1,94 @@
+//! Backends holding the master secret keys of the PKG.
+//!
+//! The PKG never handles a master secret key directly, but asks its backend to extract user secret
+//! keys. The file backend keeps the key in process memory, as read from disk. The PKCS#11 backend
+//! keeps the key wrapped under a non-extractable key inside a token, such as a hardware security module.
+
+mod file;
+mod pkcs11;
+
+pub use file::*;
+pub use pkcs11::*;
+
+use clap::ArgMatches;
+use ibe::kiltz_vahlis_one::{Identity, PublicKey};
+use irmaseal_core::UserSecretKey;
+use rand::{CryptoRng, Rng};
+use zeroize::Zeroizing;
+
+use std::path::Path;
+use std::sync::Arc;
+
+use crate::keyfile::Passphrase;
+use crate::session::SessionKey;
+use crate::Error;
+
+/// The environment variable holding the PIN of the PKCS#11 token, which is prompted for if absent.
+pub const PKCS11_PIN: &str = "IRMASEAL_PKCS11_PIN";
+
+pub trait KeyBackend: Send + Sync {
+    /// Extract the user secret key for an identity, under the given public key of the master secret key.
+    fn extract_usk(&self, pk: &PublicKey, id: &Identity) -> Result<UserSecretKey, Error>;
+
+    /// Derive the key with which session descriptors are authenticated.
+    fn session_key(&self) -> Result<SessionKey, Error>;
+}
+
+/// Where the master secret keys of the PKG are kept.
+pub enum KeyStore {
+    /// Files holding the key itself, optionally protected by a passphrase.
+    File(Passphrase),
+    Pkcs11(Arc<Token>),
+}
+
+impl KeyStore {
+    /// The store given on the command line, which is the PKCS#11 token if a module is given.
+    ///
+    /// If `create` is set, the key of the token is generated, and must not exist yet.
+    pub fn from_args(m: &ArgMatches, create: bool) -> KeyStore {
+        let module = match m.value_of("pkcs11-module") {
+            Some(module) => module,
+            None => return KeyStore::File(Passphrase::from_args(m)),
+        };
+        let token = m.value_of("pkcs11-token").unwrap();
+        let key = m.value_of("pkcs11-key").unwrap();
+        let pin = match std::env::var(PKCS11_PIN) {
+            Ok(pin) => Zeroizing::new(pin),
+            Err(_) => Zeroizing::new(
+                rpassword::read_password_from_tty(Some("PIN of the PKCS#11 token: ")).unwrap(),
+            ),
+        };
+
+        let token = if create {
+            Token::create(module, token, &pin, key)
+        } else {
+            Token::open(module, token, &pin, key)
+        };
+
+        KeyStore::Pkcs11(Arc::new(token.unwrap()))
+    }
+
+    /// The contents of the file in which the given master secret key, or share of it, is kept.
+    pub fn seal<R: Rng + CryptoRng>(&self, sk: &[u8], rng: &mut R) -> Result<Vec<u8>, Error> {
+        match self {
+            KeyStore::File(_) => Ok(sk.to_vec()),
+            KeyStore::Pkcs11(token) => token.seal(sk, rng),
+        }
+    }
+
+    /// Open the master secret key, or a share of it, in the given file.
+    ///
+    /// Yields the backend together with the index of the share, if any.
+    pub fn open(&self, path: impl AsRef<Path>) -> Result<(Arc<dyn KeyBackend>, Option<u8>), Error> {
+        match self {
+            KeyStore::File(passphrase) => {
+                let (backend, share) = FileBackend::read(path, passphrase)?;
+                Ok((Arc::new(backend), share))
+            }
+            KeyStore::Pkcs11(token) => {
+                let (backend, share) = Pkcs11Backend::read(token.clone(), path)?;
+                Ok((Arc::new(backend), share))
+            }
+        }
+    }
+}
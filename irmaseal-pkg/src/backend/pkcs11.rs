@@ -0,0 +1,599 @@
+//! The master secret key kept inside a PKCS#11 token.
+//!
+//! PKCS#11 offers no operations on the BLS12-381 curve, thus a token cannot extract user secret keys
+//! itself. Instead, the master secret key is stored on disk encrypted using AES-256-GCM under a key
+//! that is generated inside the token and can never leave it. The token decrypts the master secret
+//! key for every extraction only, after which the plaintext is wiped from memory. A copy of the key
+//! file is useless without the token, and the PIN of the token. The plaintext master secret key
+//! does reside in the memory of the PKG during every extraction, however.
+//!
+//! Tested against SoftHSM, see the ignored test below.
+
+use ibe::kiltz_vahlis_one::{Identity, PublicKey};
+use irmaseal_core::UserSecretKey;
+use libloading::Library;
+use rand::{CryptoRng, Rng};
+use zeroize::Zeroizing;
+
+use std::os::raw::{c_ulong, c_void};
+use std::path::Path;
+use std::ptr;
+use std::sync::{Arc, Mutex};
+
+use crate::backend::KeyBackend;
+use crate::session::{derive_session_key, SessionKey};
+use crate::util::parse_sk;
+use crate::Error;
+
+type Ulong = c_ulong;
+type Rv = Ulong;
+type Unused = Option<unsafe extern "C" fn()>;
+
+const CKR_OK: Rv = 0x0;
+const CKR_USER_ALREADY_LOGGED_IN: Rv = 0x100;
+const CKR_CRYPTOKI_ALREADY_INITIALIZED: Rv = 0x191;
+
+const CKF_RW_SESSION: Ulong = 0x2;
+const CKF_SERIAL_SESSION: Ulong = 0x4;
+const CKU_USER: Ulong = 1;
+
+const CKA_CLASS: Ulong = 0x0;
+const CKA_TOKEN: Ulong = 0x1;
+const CKA_PRIVATE: Ulong = 0x2;
+const CKA_LABEL: Ulong = 0x3;
+const CKA_KEY_TYPE: Ulong = 0x100;
+const CKA_SENSITIVE: Ulong = 0x103;
+const CKA_ENCRYPT: Ulong = 0x104;
+const CKA_DECRYPT: Ulong = 0x105;
+const CKA_VALUE_LEN: Ulong = 0x161;
+const CKA_EXTRACTABLE: Ulong = 0x162;
+
+const CKO_SECRET_KEY: Ulong = 0x4;
+const CKK_AES: Ulong = 0x1f;
+
+const CKM_AES_KEY_GEN: Ulong = 0x1080;
+const CKM_AES_GCM: Ulong = 0x1087;
+
+const IVSIZE: usize = 12;
+const TAGSIZE: usize = 16;
+
+/// Binds the encrypted key files to their purpose.
+const AAD: &[u8] = b"irmaseal-pkg master secret key";
+
+#[repr(C)]
+struct Version {
+    major: u8,
+    minor: u8,
+}
+
+#[repr(C)]
+struct TokenInfo {
+    label: [u8; 32],
+    manufacturer_id: [u8; 32],
+    model: [u8; 16],
+    serial_number: [u8; 16],
+    flags: Ulong,
+    counters: [Ulong; 10],
+    hardware_version: Version,
+    firmware_version: Version,
+    utc_time: [u8; 16],
+}
+
+#[repr(C)]
+struct Attribute {
+    kind: Ulong,
+    value: *const c_void,
+    len: Ulong,
+}
+
+#[repr(C)]
+struct Mechanism {
+    kind: Ulong,
+    parameter: *const c_void,
+    len: Ulong,
+}
+
+#[repr(C)]
+struct GcmParams {
+    iv: *const u8,
+    iv_len: Ulong,
+    iv_bits: Ulong,
+    aad: *const u8,
+    aad_len: Ulong,
+    tag_bits: Ulong,
+}
+
+/// The start of `CK_FUNCTION_LIST`, up to the last function used.
+#[repr(C)]
+struct FunctionList {
+    version: Version,
+    initialize: Option<unsafe extern "C" fn(*const c_void) -> Rv>,
+    finalize: Option<unsafe extern "C" fn(*const c_void) -> Rv>,
+    _get_info: Unused,
+    _get_function_list: Unused,
+    get_slot_list: Option<unsafe extern "C" fn(u8, *mut Ulong, *mut Ulong) -> Rv>,
+    _get_slot_info: Unused,
+    get_token_info: Option<unsafe extern "C" fn(Ulong, *mut TokenInfo) -> Rv>,
+    _get_mechanism_list: Unused,
+    _get_mechanism_info: Unused,
+    _init_token: Unused,
+    _init_pin: Unused,
+    _set_pin: Unused,
+    open_session:
+        Option<unsafe extern "C" fn(Ulong, Ulong, *const c_void, Unused, *mut Ulong) -> Rv>,
+    close_session: Option<unsafe extern "C" fn(Ulong) -> Rv>,
+    _close_all_sessions: Unused,
+    _get_session_info: Unused,
+    _get_operation_state: Unused,
+    _set_operation_state: Unused,
+    login: Option<unsafe extern "C" fn(Ulong, Ulong, *const u8, Ulong) -> Rv>,
+    _logout: Unused,
+    _create_object: Unused,
+    _copy_object: Unused,
+    _destroy_object: Unused,
+    _get_object_size: Unused,
+    _get_attribute_value: Unused,
+    _set_attribute_value: Unused,
+    find_objects_init: Option<unsafe extern "C" fn(Ulong, *const Attribute, Ulong) -> Rv>,
+    find_objects: Option<unsafe extern "C" fn(Ulong, *mut Ulong, Ulong, *mut Ulong) -> Rv>,
+    find_objects_final: Option<unsafe extern "C" fn(Ulong) -> Rv>,
+    encrypt_init: Option<unsafe extern "C" fn(Ulong, *const Mechanism, Ulong) -> Rv>,
+    encrypt: Option<unsafe extern "C" fn(Ulong, *const u8, Ulong, *mut u8, *mut Ulong) -> Rv>,
+    _encrypt_update: Unused,
+    _encrypt_final: Unused,
+    decrypt_init: Option<unsafe extern "C" fn(Ulong, *const Mechanism, Ulong) -> Rv>,
+    decrypt: Option<unsafe extern "C" fn(Ulong, *const u8, Ulong, *mut u8, *mut Ulong) -> Rv>,
+    _decrypt_update: Unused,
+    _decrypt_final: Unused,
+    _digest_init: Unused,
+    _digest: Unused,
+    _digest_update: Unused,
+    _digest_key: Unused,
+    _digest_final: Unused,
+    _sign_init: Unused,
+    _sign: Unused,
+    _sign_update: Unused,
+    _sign_final: Unused,
+    _sign_recover_init: Unused,
+    _sign_recover: Unused,
+    _verify_init: Unused,
+    _verify: Unused,
+    _verify_update: Unused,
+    _verify_final: Unused,
+    _verify_recover_init: Unused,
+    _verify_recover: Unused,
+    _digest_encrypt_update: Unused,
+    _decrypt_digest_update: Unused,
+    _sign_encrypt_update: Unused,
+    _decrypt_verify_update: Unused,
+    generate_key: Option<
+        unsafe extern "C" fn(Ulong, *const Mechanism, *const Attribute, Ulong, *mut Ulong) -> Rv,
+    >,
+}
+
+fn check(rv: Rv) -> Result<(), Error> {
+    if rv == CKR_OK {
+        Ok(())
+    } else {
+        Err(Error::BackendError)
+    }
+}
+
+fn attribute<T>(kind: Ulong, value: &T) -> Attribute {
+    Attribute {
+        kind,
+        value: value as *const T as *const c_void,
+        len: std::mem::size_of::<T>() as Ulong,
+    }
+}
+
+fn label(kind: Ulong, label: &str) -> Attribute {
+    Attribute {
+        kind,
+        value: label.as_ptr() as *const c_void,
+        len: label.len() as Ulong,
+    }
+}
+
+/// Call a function of the module, throwing a BackendError if it is missing or fails.
+///
+/// The arguments must be valid as specified by PKCS#11 for the function: pointers point to buffers
+/// of the given lengths, which outlive the call, and handles are those obtained from the module.
+macro_rules! call {
+    ($functions:expr, $f:ident($($arg:expr),*)) => {
+        $functions
+            .$f
+            .ok_or(Error::BackendError)
+            // SAFETY: the function is taken from the function list of the module, and the caller
+            // passes arguments as required by PKCS#11.
+            .and_then(|f| check(unsafe { f($($arg),*) }))
+    };
+}
+
+/// A logged in session to a PKCS#11 token, with the key under which master secret keys are encrypted.
+pub struct Token {
+    // Declared first, such that the session is closed before the module is unloaded.
+    session: Mutex<Ulong>,
+    key: Ulong,
+    functions: *const FunctionList,
+    _library: Library,
+}
+
+// SAFETY: the function list is immutable and lives as long as the library, and all use of the
+// session is serialized by its mutex.
+unsafe impl Send for Token {}
+unsafe impl Sync for Token {}
+
+impl Token {
+    /// Log in to the token with the given label, of which the key with the given label is used.
+    ///
+    /// Throws a BackendError if the token or key cannot be found, or if logging in fails.
+    pub fn open(
+        module: impl AsRef<Path>,
+        token: &str,
+        pin: &str,
+        key: &str,
+    ) -> Result<Token, Error> {
+        let mut res = Token::login(module, token, pin)?;
+        res.key = res.find_key(key)?.ok_or(Error::BackendError)?;
+        Ok(res)
+    }
+
+    /// Like `open`, but generate the key inside the token, which must not exist yet.
+    pub fn create(
+        module: impl AsRef<Path>,
+        token: &str,
+        pin: &str,
+        key: &str,
+    ) -> Result<Token, Error> {
+        let mut res = Token::login(module, token, pin)?;
+        if res.find_key(key)?.is_some() {
+            return Err(Error::BackendError);
+        }
+        res.key = res.generate_key(key)?;
+        Ok(res)
+    }
+
+    fn login(module: impl AsRef<Path>, token: &str, pin: &str) -> Result<Token, Error> {
+        let library = Library::new(module.as_ref()).or(Err(Error::BackendError))?;
+
+        let mut functions: *const FunctionList = ptr::null();
+        // SAFETY: C_GetFunctionList has this signature in every PKCS#11 module, and only writes the
+        // pointer to the function list of the module.
+        unsafe {
+            let get_function_list = library
+                .get::<unsafe extern "C" fn(*mut *const FunctionList) -> Rv>(b"C_GetFunctionList\0")
+                .or(Err(Error::BackendError))?;
+            check(get_function_list(&mut functions))?;
+        }
+        // SAFETY: the function list is either null or owned by the module, which stays loaded as
+        // long as the library, and is never modified.
+        let f = unsafe { functions.as_ref() }.ok_or(Error::BackendError)?;
+
+        // The module is shared by all tokens opened in this process.
+        // SAFETY: C_Initialize accepts null for default arguments.
+        let rv = unsafe { (f.initialize.ok_or(Error::BackendError)?)(ptr::null()) };
+        if rv != CKR_CRYPTOKI_ALREADY_INITIALIZED {
+            check(rv)?;
+        }
+
+        let mut count: Ulong = 0;
+        call!(f, get_slot_list(1, ptr::null_mut(), &mut count))?;
+        let mut slots = vec![0; count as usize];
+        call!(f, get_slot_list(1, slots.as_mut_ptr(), &mut count))?;
+        slots.truncate(count as usize);
+
+        // Token labels are padded with spaces.
+        let slot = slots
+            .into_iter()
+            .find(|slot| {
+                // SAFETY: TokenInfo consists of integers only, for which zero is valid.
+                let mut info = unsafe { std::mem::zeroed::<TokenInfo>() };
+                call!(f, get_token_info(*slot, &mut info)).is_ok()
+                    && std::str::from_utf8(&info.label).map(str::trim_end) == Ok(token)
+            })
+            .ok_or(Error::BackendError)?;
+
+        let mut session: Ulong = 0;
+        call!(
+            f,
+            open_session(
+                slot,
+                CKF_SERIAL_SESSION | CKF_RW_SESSION,
+                ptr::null(),
+                None,
+                &mut session
+            )
+        )?;
+
+        // From here on, the session is closed when dropping the token, also if logging in fails.
+        let res = Token {
+            session: Mutex::new(session),
+            key: 0,
+            functions,
+            _library: library,
+        };
+
+        let login = res.functions().login.ok_or(Error::BackendError)?;
+        // SAFETY: the PIN is passed with its length, and is not retained by the module.
+        let rv = unsafe { login(session, CKU_USER, pin.as_ptr(), pin.len() as Ulong) };
+        if rv != CKR_USER_ALREADY_LOGGED_IN {
+            check(rv)?;
+        }
+
+        Ok(res)
+    }
+
+    fn functions(&self) -> &FunctionList {
+        // SAFETY: the function list was checked to be non-null when logging in, and lives as long
+        // as the library, which is owned by the token.
+        unsafe { &*self.functions }
+    }
+
+    fn find_key(&self, key: &str) -> Result<Option<Ulong>, Error> {
+        let f = self.functions();
+        let session = self.session.lock().unwrap();
+
+        let template = [attribute(CKA_CLASS, &CKO_SECRET_KEY), label(CKA_LABEL, key)];
+        call!(
+            f,
+            find_objects_init(*session, template.as_ptr(), template.len() as Ulong)
+        )?;
+
+        let mut handles = [0; 2];
+        let mut count: Ulong = 0;
+        let found = call!(
+            f,
+            find_objects(*session, handles.as_mut_ptr(), 2, &mut count)
+        );
+        call!(f, find_objects_final(*session))?;
+        found?;
+
+        // A label that is ambiguous is not usable.
+        match count {
+            0 => Ok(None),
+            1 => Ok(Some(handles[0])),
+            _ => Err(Error::BackendError),
+        }
+    }
+
+    fn generate_key(&self, key: &str) -> Result<Ulong, Error> {
+        let f = self.functions();
+        let session = self.session.lock().unwrap();
+
+        let (yes, no): (u8, u8) = (1, 0);
+        let length: Ulong = 32;
+        let template = [
+            attribute(CKA_CLASS, &CKO_SECRET_KEY),
+            attribute(CKA_KEY_TYPE, &CKK_AES),
+            attribute(CKA_VALUE_LEN, &length),
+            attribute(CKA_TOKEN, &yes),
+            attribute(CKA_PRIVATE, &yes),
+            attribute(CKA_SENSITIVE, &yes),
+            attribute(CKA_EXTRACTABLE, &no),
+            attribute(CKA_ENCRYPT, &yes),
+            attribute(CKA_DECRYPT, &yes),
+            label(CKA_LABEL, key),
+        ];
+        let mechanism = Mechanism {
+            kind: CKM_AES_KEY_GEN,
+            parameter: ptr::null(),
+            len: 0,
+        };
+
+        let mut handle: Ulong = 0;
+        call!(
+            f,
+            generate_key(
+                *session,
+                &mechanism,
+                template.as_ptr(),
+                template.len() as Ulong,
+                &mut handle
+            )
+        )?;
+
+        Ok(handle)
+    }
+
+    fn gcm(iv: &[u8]) -> GcmParams {
+        GcmParams {
+            iv: iv.as_ptr(),
+            iv_len: iv.len() as Ulong,
+            iv_bits: (iv.len() * 8) as Ulong,
+            aad: AAD.as_ptr(),
+            aad_len: AAD.len() as Ulong,
+            tag_bits: (TAGSIZE * 8) as Ulong,
+        }
+    }
+
+    /// Encrypt a master secret key, or a share of it, under the key of the token.
+    pub fn seal<R: Rng + CryptoRng>(&self, sk: &[u8], rng: &mut R) -> Result<Vec<u8>, Error> {
+        let f = self.functions();
+        let session = self.session.lock().unwrap();
+
+        let mut res = vec![0u8; IVSIZE + sk.len() + TAGSIZE];
+        rng.fill_bytes(&mut res[..IVSIZE]);
+
+        let params = Token::gcm(&res[..IVSIZE]);
+        let mechanism = Mechanism {
+            kind: CKM_AES_GCM,
+            parameter: &params as *const GcmParams as *const c_void,
+            len: std::mem::size_of::<GcmParams>() as Ulong,
+        };
+        call!(f, encrypt_init(*session, &mechanism, self.key))?;
+
+        let out = &mut res[IVSIZE..];
+        let mut len = out.len() as Ulong;
+        call!(
+            f,
+            encrypt(
+                *session,
+                sk.as_ptr(),
+                sk.len() as Ulong,
+                out.as_mut_ptr(),
+                &mut len
+            )
+        )?;
+
+        if len as usize != out.len() {
+            return Err(Error::BackendError);
+        }
+
+        Ok(res)
+    }
+
+    /// Decrypt a master secret key, or a share of it, as encrypted by `seal`.
+    pub fn open_sealed(&self, sealed: &[u8]) -> Result<Zeroizing<Vec<u8>>, Error> {
+        if sealed.len() < IVSIZE + TAGSIZE {
+            return Err(Error::Core(irmaseal_core::Error::FormatViolation));
+        }
+
+        let f = self.functions();
+        let session = self.session.lock().unwrap();
+
+        let (iv, ct) = sealed.split_at(IVSIZE);
+        let params = Token::gcm(iv);
+        let mechanism = Mechanism {
+            kind: CKM_AES_GCM,
+            parameter: &params as *const GcmParams as *const c_void,
+            len: std::mem::size_of::<GcmParams>() as Ulong,
+        };
+        call!(f, decrypt_init(*session, &mechanism, self.key))?;
+
+        let mut res = Zeroizing::new(vec![0u8; ct.len()]);
+        let mut len = res.len() as Ulong;
+        call!(
+            f,
+            decrypt(
+                *session,
+                ct.as_ptr(),
+                ct.len() as Ulong,
+                res.as_mut_ptr(),
+                &mut len
+            )
+        )?;
+        res.truncate(len as usize);
+
+        Ok(res)
+    }
+}
+
+impl Drop for Token {
+    fn drop(&mut self) {
+        let f = self.functions();
+        let session = *self.session.lock().unwrap();
+
+        // The module is not finalized, as other tokens may still use it.
+        if let Some(close_session) = f.close_session {
+            // SAFETY: the session was opened by this token, and is not used after this point.
+            unsafe { close_session(session) };
+        }
+    }
+}
+
+/// A master secret key, or a share of it, encrypted under the key of a PKCS#11 token.
+pub struct Pkcs11Backend {
+    token: Arc<Token>,
+    sealed: Vec<u8>,
+}
+
+impl Pkcs11Backend {
+    /// Read an encrypted master secret key, or share of it, and yield the index of the share if any.
+    ///
+    /// The key is decrypted once to verify it, such that a wrong token or key fails at startup.
+    pub fn read(
+        token: Arc<Token>,
+        path: impl AsRef<Path>,
+    ) -> Result<(Pkcs11Backend, Option<u8>), Error> {
+        let sealed = std::fs::read(path).unwrap();
+        let (_, share) = parse_sk(&token.open_sealed(&sealed)?).map_err(Error::Core)?;
+
+        Ok((Pkcs11Backend { token, sealed }, share))
+    }
+
+    fn with_sk<T>(
+        &self,
+        f: impl FnOnce(&ibe::kiltz_vahlis_one::SecretKey) -> T,
+    ) -> Result<T, Error> {
+        let bytes = self.token.open_sealed(&self.sealed)?;
+        let (sk, _) = parse_sk(&bytes).map_err(Error::Core)?;
+        Ok(f(&sk))
+    }
+}
+
+impl KeyBackend for Pkcs11Backend {
+    fn extract_usk(&self, pk: &PublicKey, id: &Identity) -> Result<UserSecretKey, Error> {
+        self.with_sk(|sk| {
+            let mut rng = rand::thread_rng();
+            ibe::kiltz_vahlis_one::extract_usk(pk, sk, id, &mut rng).into()
+        })
+    }
+
+    fn session_key(&self) -> Result<SessionKey, Error> {
+        self.with_sk(derive_session_key)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn missing_module() {
+        let module = "/nonexistent/libpkcs11.so";
+        assert!(matches!(
+            Token::open(module, "irmaseal", "1234", "irmaseal-pkg"),
+            Err(Error::BackendError)
+        ));
+    }
+
+    /// Requires an initialized SoftHSM token, thus only runs when asked for, as done in CI:
+    ///
+    /// ```sh
+    /// softhsm2-util --init-token --free --label irmaseal --pin 1234 --so-pin 1234
+    /// IRMASEAL_PKCS11_MODULE=/usr/lib/softhsm/libsofthsm2.so IRMASEAL_PKCS11_PIN=1234 \
+    ///     cargo test -- --ignored softhsm
+    /// ```
+    #[test]
+    #[ignore]
+    fn softhsm() {
+        let module = std::env::var("IRMASEAL_PKCS11_MODULE").unwrap();
+        let pin = std::env::var("IRMASEAL_PKCS11_PIN").unwrap();
+
+        // A wrong PIN fails.
+        assert!(Token::open(&module, "irmaseal", "wrong", "irmaseal-pkg").is_err());
+
+        let mut rng = rand::thread_rng();
+        let (pk, sk) = ibe::kiltz_vahlis_one::setup(&mut rng);
+
+        let key = format!("test-{}", crate::util::now());
+        let token = Arc::new(Token::create(&module, "irmaseal", &pin, &key).unwrap());
+        assert!(Token::create(&module, "irmaseal", &pin, &key).is_err());
+
+        let sealed = token.seal(&sk.to_bytes(), &mut rng).unwrap();
+        assert_eq!(token.open_sealed(&sealed).unwrap()[..], sk.to_bytes()[..]);
+
+        let mut tampered = sealed.clone();
+        tampered[IVSIZE] ^= 1;
+        assert!(token.open_sealed(&tampered).is_err());
+
+        let backend = Pkcs11Backend {
+            token: token.clone(),
+            sealed,
+        };
+        assert_eq!(backend.session_key().unwrap(), derive_session_key(&sk));
+
+        let id = Identity::derive_str("w.geraedts@sarif.nl");
+        let usk = backend.extract_usk(&pk, &id).unwrap();
+        let (c, m) = ibe::kiltz_vahlis_one::encrypt(&pk, &id, &mut rng);
+        let bytes = base64::decode(serde_json::to_value(&usk).unwrap().as_str().unwrap()).unwrap();
+        let usk =
+            ibe::kiltz_vahlis_one::UserSecretKey::from_bytes(arrayref::array_ref![bytes, 0, 192]);
+        assert_eq!(ibe::kiltz_vahlis_one::decrypt(&usk.unwrap(), &c), m);
+
+        drop(backend);
+        let reopened = Token::open(&module, "irmaseal", &pin, &key).unwrap();
+        assert!(reopened.open_sealed(&tampered).is_err());
+    }
+}
@@ -0,0 +1,265 @@
+//! Backups of the master secret key, split into shares using Shamir secret sharing.
+//!
+//! Any `threshold` of the shares reconstruct the master secret key, fewer reveal nothing about it.
+//! A share is formatted as follows:
+//!
+//! 1. The version of the format, currently `0x01`.
+//! 2. The key ID of the public key of the master secret key (8 bytes).
+//! 3. The threshold and the index of the share, each as 8 bit unsigned number.
+//! 4. The share of the master secret key (48 bytes).
+//! 5. The start of the SHA3-256 hash of the parts above, to detect typing errors (4 bytes).
+//!
+//! Shares are written either as armored text, or as words using the mnemonicode word list.
+
+use arrayref::{array_ref, array_refs};
+use ibe::kiltz_vahlis_one::{PublicKey, SecretKey};
+use irmaseal_core::{Error, KeyId, KEYIDSIZE};
+use rand::{CryptoRng, Rng};
+use sha3::{Digest, Sha3_256};
+use zeroize::Zeroizing;
+
+const VERSION: u8 = 0x01;
+const SKSIZE: usize = 48;
+const CHECKSUMSIZE: usize = 4;
+
+/// The size of the byte representation of a `Share`.
+pub const SHARESIZE: usize = 1 + KEYIDSIZE + 2 + SKSIZE + CHECKSUMSIZE;
+
+const ARMOR_BEGIN: &str = "-----BEGIN IRMASEAL PKG SHARE-----";
+const ARMOR_END: &str = "-----END IRMASEAL PKG SHARE-----";
+
+/// A share of the master secret key.
+pub struct Share {
+    pub key_id: KeyId,
+    pub threshold: u8,
+    pub index: u8,
+    value: Zeroizing<[u8; SKSIZE]>,
+}
+
+fn checksum(bytes: &[u8]) -> [u8; CHECKSUMSIZE] {
+    let mut h = Sha3_256::new();
+    h.input(bytes);
+
+    let mut res = [0u8; CHECKSUMSIZE];
+    res.copy_from_slice(&h.result()[..CHECKSUMSIZE]);
+    res
+}
+
+/// Whether the secret key belongs to the public key, by extracting a user secret key and using it
+/// to decrypt.
+pub fn verify<R: Rng + CryptoRng>(pk: &PublicKey, sk: &SecretKey, rng: &mut R) -> bool {
+    let id = ibe::kiltz_vahlis_one::Identity::derive_str("irmaseal-pkg backup");
+    let usk = ibe::kiltz_vahlis_one::extract_usk(pk, sk, &id, rng);
+    let (c, m) = ibe::kiltz_vahlis_one::encrypt(pk, &id, rng);
+
+    ibe::kiltz_vahlis_one::decrypt(&usk, &c) == m
+}
+
+/// Split the master secret key into `shares` shares, of which any `threshold` reconstruct it.
+///
+/// Throws a ConstraintViolation if the threshold is zero or exceeds the number of shares.
+pub fn split<R: Rng + CryptoRng>(
+    pk: &PublicKey,
+    sk: &SecretKey,
+    threshold: u8,
+    shares: u8,
+    rng: &mut R,
+) -> Result<Vec<Share>, Error> {
+    let key_id = irmaseal_core::PublicKey::from(*pk).key_id();
+
+    let mut values: Vec<Zeroizing<[u8; SKSIZE]>> =
+        (0..shares).map(|_| Zeroizing::new([0u8; SKSIZE])).collect();
+    {
+        let mut refs: Vec<&mut [u8]> = values.iter_mut().map(|v| &mut v[..]).collect();
+        irmaseal_core::shamir::split(&sk.to_bytes(), threshold, &mut refs, rng)?;
+    }
+
+    Ok(values
+        .into_iter()
+        .zip(1..=shares)
+        .map(|(value, index)| Share {
+            key_id,
+            threshold,
+            index,
+            value,
+        })
+        .collect())
+}
+
+/// Reconstruct the master secret key of the given public key from its shares.
+///
+/// Throws a ConstraintViolation if the shares are of different keys or thresholds, if fewer shares
+/// than the threshold are given, or if an index occurs more than once. Throws an IntegrityViolation if
+/// the reconstructed key does not belong to the public key.
+pub fn combine<R: Rng + CryptoRng>(
+    pk: &PublicKey,
+    shares: &[Share],
+    rng: &mut R,
+) -> Result<SecretKey, Error> {
+    let key_id = irmaseal_core::PublicKey::from(*pk).key_id();
+
+    let threshold = shares.first().map_or(0, |s| s.threshold);
+    if shares
+        .iter()
+        .any(|s| s.key_id != key_id || s.threshold != threshold)
+        || shares.len() < usize::from(threshold)
+    {
+        return Err(Error::ConstraintViolation);
+    }
+
+    let given: Vec<(u8, &[u8])> = shares.iter().map(|s| (s.index, &s.value[..])).collect();
+    let mut res = Zeroizing::new([0u8; SKSIZE]);
+    irmaseal_core::shamir::combine(&given, &mut res[..])?;
+
+    let sk = crate::util::open_ct(SecretKey::from_bytes(&res)).ok_or(Error::IntegrityViolation)?;
+    if !verify(pk, &sk, rng) {
+        return Err(Error::IntegrityViolation);
+    }
+
+    Ok(sk)
+}
+
+impl Share {
+    pub fn to_bytes(&self) -> Zeroizing<[u8; SHARESIZE]> {
+        let mut res = Zeroizing::new([0u8; SHARESIZE]);
+        res[0] = VERSION;
+        res[1..1 + KEYIDSIZE].copy_from_slice(&self.key_id.0);
+        res[1 + KEYIDSIZE] = self.threshold;
+        res[2 + KEYIDSIZE] = self.index;
+        res[3 + KEYIDSIZE..SHARESIZE - CHECKSUMSIZE].copy_from_slice(&self.value[..]);
+
+        let sum = checksum(&res[..SHARESIZE - CHECKSUMSIZE]);
+        res[SHARESIZE - CHECKSUMSIZE..].copy_from_slice(&sum);
+        res
+    }
+
+    /// Throws an IntegrityViolation if the checksum does not match, and a FormatViolation if the
+    /// share is malformed or of an unknown version.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Share, Error> {
+        if bytes.len() != SHARESIZE {
+            return Err(Error::FormatViolation);
+        }
+
+        let bytes = array_ref![bytes, 0, SHARESIZE];
+        let (version, key_id, threshold, index, value, sum) =
+            array_refs![bytes, 1, KEYIDSIZE, 1, 1, SKSIZE, CHECKSUMSIZE];
+
+        if checksum(&bytes[..SHARESIZE - CHECKSUMSIZE]) != *sum {
+            return Err(Error::IntegrityViolation);
+        }
+        if version[0] != VERSION || threshold[0] == 0 || index[0] == 0 {
+            return Err(Error::FormatViolation);
+        }
+
+        Ok(Share {
+            key_id: KeyId(*key_id),
+            threshold: threshold[0],
+            index: index[0],
+            value: Zeroizing::new(*value),
+        })
+    }
+
+    /// The share as armored text, with informational headers and the base64 encoded share.
+    pub fn to_armor(&self) -> String {
+        let encoded = base64::encode(&self.to_bytes()[..]);
+
+        let mut res = format!(
+            "{}\nKey-ID: {}\nShare: {}\nThreshold: {}\n\n",
+            ARMOR_BEGIN, self.key_id, self.index, self.threshold
+        );
+        for line in encoded.as_bytes().chunks(64) {
+            res.push_str(std::str::from_utf8(line).unwrap());
+            res.push('\n');
+        }
+        res.push_str(ARMOR_END);
+        res.push('\n');
+        res
+    }
+
+    /// The share as words, six per line.
+    pub fn to_mnemonic(&self) -> String {
+        let mut res = Vec::new();
+        mnemonic::encode_with_format(&self.to_bytes()[..], "x-x-x x-x-x\n", &mut res).unwrap();
+
+        let mut res = String::from_utf8(res).unwrap();
+        if !res.ends_with('\n') {
+            res.push('\n');
+        }
+        res
+    }
+
+    /// Parse a share written either as armored text or as words.
+    pub fn parse(text: &str) -> Result<Share, Error> {
+        let bytes = if text.contains(ARMOR_BEGIN) {
+            let encoded: String = text
+                .lines()
+                .skip_while(|l| l.trim() != ARMOR_BEGIN)
+                .skip(1)
+                .take_while(|l| l.trim() != ARMOR_END)
+                .filter(|l| !l.contains(':'))
+                .map(str::trim)
+                .collect();
+            base64::decode(&encoded).or(Err(Error::FormatViolation))?
+        } else {
+            let mut res = Vec::new();
+            mnemonic::decode(text, &mut res).or(Err(Error::FormatViolation))?;
+            res
+        };
+
+        Share::from_bytes(&Zeroizing::new(bytes))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reflection() {
+        let mut rng = rand::thread_rng();
+        let (pk, sk) = ibe::kiltz_vahlis_one::setup(&mut rng);
+
+        let shares = split(&pk, &sk, 2, 3, &mut rng).unwrap();
+        assert_eq!(shares.len(), 3);
+
+        let armored = Share::parse(&shares[0].to_armor()).unwrap();
+        let words = Share::parse(&shares[2].to_mnemonic()).unwrap();
+        assert_eq!((armored.index, words.index), (1, 3));
+
+        let res = combine(&pk, &[armored, words], &mut rng).unwrap();
+        assert_eq!(res.to_bytes()[..], sk.to_bytes()[..]);
+
+        // Fewer shares than the threshold, or the shares of another key, are refused.
+        let single = Share::parse(&shares[1].to_armor()).unwrap();
+        assert!(matches!(
+            combine(&pk, &[single], &mut rng),
+            Err(Error::ConstraintViolation)
+        ));
+
+        let (other, _) = ibe::kiltz_vahlis_one::setup(&mut rng);
+        let shares = split(&pk, &sk, 1, 1, &mut rng).unwrap();
+        assert!(matches!(
+            combine(&other, &shares, &mut rng),
+            Err(Error::ConstraintViolation)
+        ));
+    }
+
+    #[test]
+    fn integrity() {
+        let mut rng = rand::thread_rng();
+        let (pk, sk) = ibe::kiltz_vahlis_one::setup(&mut rng);
+
+        let share = split(&pk, &sk, 1, 1, &mut rng).unwrap().remove(0);
+        let mut bytes = share.to_bytes();
+        bytes[20] ^= 1;
+
+        assert!(matches!(
+            Share::from_bytes(&bytes[..]),
+            Err(Error::IntegrityViolation)
+        ));
+        assert!(matches!(
+            Share::parse("apollo banana"),
+            Err(Error::FormatViolation)
+        ));
+    }
+}
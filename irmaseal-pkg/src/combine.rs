@@ -0,0 +1,63 @@
+use clap::ArgMatches;
+use irmaseal_core::Error;
+
+use crate::backup::{combine, Share};
+use crate::keyfile::{self, Passphrase, DEFAULT_COST};
+use crate::util::{read_pk, write_owned};
+
+pub fn exec(m: &ArgMatches) {
+    let mut rng = rand::thread_rng();
+
+    let public = m.value_of("public").unwrap();
+    let secret = m.value_of("secret").unwrap();
+
+    let pk = read_pk(public).unwrap();
+
+    let mut shares = Vec::new();
+    for path in m.values_of("shares").unwrap() {
+        let share = std::fs::read_to_string(path)
+            .or(Err(Error::FormatViolation))
+            .and_then(|s| Share::parse(&s));
+
+        match share {
+            Ok(share) => shares.push(share),
+            Err(Error::IntegrityViolation) => {
+                eprintln!(
+                    "The checksum of {} does not match, check for typing errors",
+                    path
+                );
+                std::process::exit(1);
+            }
+            Err(_) => {
+                eprintln!("Could not read a share from {}", path);
+                std::process::exit(1);
+            }
+        }
+    }
+
+    // The reconstructed key is only written after a test extraction and decryption succeeded.
+    let sk = match combine(&pk, &shares, &mut rng) {
+        Ok(sk) => sk,
+        Err(Error::IntegrityViolation) => {
+            eprintln!("The reconstructed key is not the private key of {}", public);
+            std::process::exit(1);
+        }
+        Err(_) => {
+            eprintln!(
+                "The shares are not of {}, are not distinct or are fewer than required",
+                public
+            );
+            std::process::exit(1);
+        }
+    };
+
+    let contents = if m.is_present("passphrase") {
+        let passphrase = Passphrase::from_args(m).get(true);
+        keyfile::seal(&sk.to_bytes(), &passphrase, DEFAULT_COST, &mut rng).unwrap()
+    } else {
+        sk.to_bytes().to_vec()
+    };
+    write_owned(secret, contents);
+
+    println!("Written {}, verified against {}", secret, public);
+}
@@ -13,8 +13,20 @@ impl ResponseError for Error {
         let mut response = match self {
             Error::Core(_) => HttpResponse::InternalServerError(),
             Error::ChronologyError => HttpResponse::BadRequest(),
+            Error::AlignmentError => HttpResponse::BadRequest(),
+            Error::RetentionError => HttpResponse::Gone(),
             Error::SessionNotFound => HttpResponse::NotFound(),
+            Error::InvalidSession => HttpResponse::BadRequest(),
+            Error::SessionExpired => HttpResponse::Gone(),
+            Error::SessionRedeemed => HttpResponse::Gone(),
+            Error::SessionLocked => HttpResponse::Gone(),
+            Error::TooManyAttempts => HttpResponse::TooManyRequests(),
+            Error::UnknownKey => HttpResponse::NotFound(),
             Error::UpstreamError => HttpResponse::ServiceUnavailable(),
+            Error::BackendError => HttpResponse::InternalServerError(),
+            Error::InvalidPassphrase => HttpResponse::InternalServerError(),
+            Error::AuditError => HttpResponse::InternalServerError(),
+            Error::UnsupportedMethod => HttpResponse::BadRequest(),
             Error::Unexpected => HttpResponse::InternalServerError(),
         };
 
@@ -26,8 +38,20 @@ impl ResponseError for Error {
 pub enum Error {
     Core(irmaseal_core::Error),
     ChronologyError,
+    AlignmentError,
+    RetentionError,
     SessionNotFound,
+    InvalidSession,
+    SessionExpired,
+    SessionRedeemed,
+    SessionLocked,
+    TooManyAttempts,
+    UnknownKey,
     UpstreamError,
+    BackendError,
+    InvalidPassphrase,
+    AuditError,
+    UnsupportedMethod,
     Unexpected,
 }
 
@@ -39,8 +63,20 @@ impl Display for Error {
             match self {
                 Error::Core(_) => "core",
                 Error::ChronologyError => "chronology error",
+                Error::AlignmentError => "alignment error",
+                Error::RetentionError => "retention error",
                 Error::SessionNotFound => "session not found",
+                Error::InvalidSession => "invalid session",
+                Error::SessionExpired => "session expired",
+                Error::SessionRedeemed => "session already redeemed",
+                Error::SessionLocked => "session locked after too many wrong codes",
+                Error::TooManyAttempts => "too many attempts",
+                Error::UnknownKey => "unknown key",
                 Error::UpstreamError => "upstream error",
+                Error::BackendError => "key backend error",
+                Error::InvalidPassphrase => "invalid passphrase",
+                Error::AuditError => "audit error",
+                Error::UnsupportedMethod => "unsupported authentication method",
                 Error::Unexpected => "unexpected",
             }
         )
@@ -0,0 +1,60 @@
+use clap::ArgMatches;
+use irmaseal_core::{Attribute, Identity, UserSecretKey};
+
+use crate::audit::{AuditLog, Entry};
+use crate::keyfile::Passphrase;
+use crate::util::{read_pk, read_sk};
+
+fn parse_attribute(s: &str) -> Option<Attribute> {
+    let (atype, value) = s.split_once('=')?;
+    Attribute::new(atype, Some(value)).ok()
+}
+
+pub fn exec(m: &ArgMatches) {
+    let public = m.value_of("public").unwrap();
+    let secret = m.value_of("secret").unwrap();
+    let audit = m.value_of("audit").unwrap();
+
+    let timestamp = m.value_of("timestamp").unwrap().parse::<u64>().unwrap();
+
+    let attributes: Option<Vec<Attribute>> = m
+        .values_of("identity")
+        .unwrap()
+        .map(parse_attribute)
+        .collect();
+    let identity = match attributes.map(|a| Identity::conjunction(timestamp, &a)) {
+        Some(Ok(identity)) => identity,
+        _ => {
+            eprintln!("Expected at most 4 attributes of distinct types, as TYPE=VALUE");
+            std::process::exit(1);
+        }
+    };
+
+    let pk = read_pk(public).unwrap();
+    let (sk, share) = read_sk(secret, &Passphrase::from_args(m)).unwrap();
+    let key_id = irmaseal_core::PublicKey::from(pk).key_id();
+
+    // The key is only issued once it has been recorded.
+    let entry = Entry {
+        reason: m.value_of("reason").map(str::to_string),
+        ..Entry::new("extract", key_id, share, &identity)
+    };
+    AuditLog::open(audit).unwrap().append(&entry).unwrap();
+
+    let usk: UserSecretKey =
+        ibe::kiltz_vahlis_one::extract_usk(&pk, &sk, &identity.derive(), &mut rand::thread_rng())
+            .into();
+
+    eprintln!(
+        "Extracted the key for {:#?} under key ID {}, recorded in {}",
+        identity, key_id, audit
+    );
+    if let Some(index) = share {
+        eprintln!(
+            "This is the partial key of share {}, which must be combined with those of other nodes",
+            index
+        );
+    }
+
+    println!("{}", serde_json::to_value(&usk).unwrap().as_str().unwrap());
+}
@@ -1,34 +1,56 @@
 use clap::ArgMatches;
 use ibe::kiltz_vahlis_one::setup;
+use irmaseal_core::threshold::split;
 
-use std::fs::OpenOptions;
-use std::os::unix::fs::OpenOptionsExt;
-use std::path::Path;
-
-fn write_owned<P: AsRef<Path>, C: AsRef<[u8]>>(path: P, contents: C) {
-    fn inner(path: &Path, contents: &[u8]) {
-        use std::io::Write;
-        OpenOptions::new()
-            .write(true)
-            .create_new(true)
-            .mode(0o600)
-            .open(path)
-            .unwrap()
-            .write_all(contents)
-            .unwrap()
-    }
-    inner(path.as_ref(), contents.as_ref())
-}
+use crate::backend::KeyStore;
+use crate::keyfile::{self, Passphrase, DEFAULT_COST};
+use crate::util::write_owned;
 
 pub fn exec(m: &ArgMatches) {
     let mut rng = rand::thread_rng();
     let (pk, sk) = setup(&mut rng);
 
+    let key_id = irmaseal_core::PublicKey::from(pk).key_id();
+
     let public = m.value_of("public").unwrap();
     let secret = m.value_of("secret").unwrap();
 
+    // With a PKCS#11 token, the secret keys are written encrypted under a new key inside the token.
+    let store = KeyStore::from_args(m, true);
+    let passphrase = Passphrase::from_args(m);
+
+    // The contents of a file holding a secret key, protected by a passphrase if requested.
+    let mut contents = |sk: &[u8]| {
+        let sealed = store.seal(sk, &mut rng).unwrap();
+        if m.is_present("passphrase") {
+            keyfile::seal(&sealed, &passphrase.get(true), DEFAULT_COST, &mut rng).unwrap()
+        } else {
+            sealed
+        }
+    };
+
     write_owned(public, pk.to_bytes().as_ref());
-    write_owned(secret, sk.to_bytes().as_ref());
 
-    println!("Written {} and {}", public, secret);
+    // With shares, the master secret key itself is never written.
+    if let Some(shares) = m.value_of("shares") {
+        let shares = shares.parse::<u8>().unwrap();
+        let threshold = m.value_of("threshold").unwrap().parse::<u8>().unwrap();
+
+        for share in split(&sk, threshold, shares, &mut rand::thread_rng()).unwrap() {
+            let path = format!("{}.{}", secret, share.index());
+            write_owned(&path, contents(&share.to_bytes()));
+            println!("Written share {}", path);
+        }
+
+        println!(
+            "Written {}, {} of {} shares are required",
+            public, threshold, shares
+        );
+    } else {
+        write_owned(secret, contents(&sk.to_bytes()));
+
+        println!("Written {} and {}", public, secret);
+    }
+
+    println!("Key ID {}", key_id);
 }
@@ -1,38 +1,51 @@
 use actix_web::web::{Data, HttpResponse, Json};
-use futures::future::Future;
+use futures::future::{result, Future};
 use irmaseal_core::api::{KeyChallenge, KeyRequest};
 
-use irma::client::Client;
-use irma::request::*;
-
 use crate::server::AppState;
+use crate::session::{Session, MAX_SESSION_AGE};
+use crate::util::now;
 
 pub fn request(
     state: Data<AppState>,
     value: Json<KeyRequest>,
 ) -> impl Future<Item = HttpResponse, Error = crate::Error> {
-    let kr = value.into_inner();
-    let a = kr.attribute;
-
-    let dr = DisclosureRequest {
-        disclose: AttributeConDisCon(vec![AttributeDisCon(vec![AttributeCon(vec![
-            AttributeRequest {
-                atype: a.atype.to_string(),
-                value: a.value.map(|s| s.to_string()),
-                not_null: true,
-            },
-        ])])]),
-        labels: None,
-    };
-
-    let client = Client::new(state.irma_server_host.clone()).unwrap();
-
-    client.request(&dr).then(move |sp| {
-        let sp = sp.or(Err(crate::Error::UpstreamError))?;
-
-        let qr = &serde_json::to_string(&sp.session_ptr).or(Err(crate::Error::Unexpected))?;
-        let token: &str = (&sp.token).into();
-
-        Ok(HttpResponse::Ok().json(KeyChallenge { qr, token }))
-    })
+    let KeyRequest {
+        identity,
+        method,
+        key_id,
+        delivery_key,
+    } = value.into_inner();
+    let now = now();
+
+    let auth = state.auth.clone();
+    let session_key = state.session_key;
+
+    // Keys are issued under the master key the identity was sealed with, which must be served by this PKG.
+    let checked = state.keyring.get(key_id.as_ref()).and_then(|g| {
+        state.policy.check(identity.timestamp, now)?;
+        Ok(g.key_id)
+    });
+
+    result(checked)
+        .and_then(move |key_id| {
+            let started = auth.get(method).map(|a| a.start(&identity));
+            result(started)
+                .flatten()
+                .map(move |c| (key_id, identity, c))
+        })
+        .and_then(move |(key_id, identity, c)| {
+            // The client carries the identity bound to the session of the authenticator.
+            let session = Session {
+                method,
+                auth_token: c.token,
+                identity,
+                key_id,
+                delivery_key,
+                expires: now + MAX_SESSION_AGE,
+            };
+            let token = &session.seal(&session_key);
+
+            Ok(HttpResponse::Ok().json(KeyChallenge { qr: &c.qr, token }))
+        })
 }
@@ -1,91 +1,104 @@
-use actix_web::web::{Data, HttpResponse, Path};
-use futures::future::{ok, Future};
-use irmaseal_core::api::{KeyResponse, KeyStatus};
-use irmaseal_core::Identity;
-
-use irma::client::Client;
-use irma::session::*;
+use actix_web::error::BlockingError;
+use actix_web::web::{Data, HttpResponse, Json, Path};
+use futures::future::{ok, result, Either, Future};
+use irmaseal_core::api::{KeyResponse, KeyStatus, KeyVerification};
 
+use crate::audit::Entry;
+use crate::auth::AuthStatus;
+use crate::keyring::Generation;
 use crate::server::AppState;
-use crate::Error;
-
-/// Fetch identity iff valid, or else yield nothing.
-fn fetch_identity(
-    timestamp: u64,
-    disclosed: &Option<Vec<Vec<DisclosedAttribute>>>,
-) -> Option<Identity> {
-    let disclosed = disclosed.as_ref()?;
-    let disclosed = if disclosed.len() == 1 && disclosed[0].len() == 1 {
-        &disclosed[0][0]
-    } else {
-        return None;
-    };
-
-    if disclosed.status != AttributeProofStatus::Present {
-        return None;
-    }
-
-    let v = disclosed.rawvalue.as_ref()?;
-
-    Identity::new(timestamp, &disclosed.id, Some(&v)).ok()
-}
+use crate::session::Session;
+use crate::util::now;
 
 pub fn request_fetch(
     state: Data<AppState>,
-    path: Path<(String, u64)>,
+    path: Path<String>,
+    verification: Option<Json<KeyVerification>>,
 ) -> impl Future<Item = HttpResponse, Error = crate::Error> {
-    let (token, timestamp) = path.into_inner();
+    let token = path.into_inner();
 
     let AppState {
-        pk,
-        sk,
-        irma_server_host,
+        keyring,
+        audit,
+        auth,
+        policy,
+        session_key,
+        redeemed,
     } = state.get_ref().clone();
 
-    ok(())
-        .and_then(move |_| {
-            let now = std::time::SystemTime::now()
-                .duration_since(std::time::UNIX_EPOCH)
-                .unwrap()
-                .as_secs();
-            if timestamp > now {
-                Err(Error::ChronologyError)
-            } else {
-                Ok(())
-            }
+    // Keys are only issued for the identity bound to the session, once, until it expires.
+    let session_token = token.clone();
+    let code = verification.map(|v| v.code);
+
+    result(Session::open(&token, &session_key, now()))
+        .and_then(move |session| {
+            policy.check(session.identity.timestamp, now())?;
+            Ok(session)
         })
-        .and_then(move |_| {
-            let client = Client::new(irma_server_host).unwrap();
-            client
-                .result(&SessionToken(token))
-                .map_err(|e| match e.status() {
-                    Some(irma::client::StatusCode::BAD_REQUEST) => crate::Error::SessionNotFound,
-                    _ => crate::Error::UpstreamError,
-                })
+        .and_then(move |session| {
+            let status = auth.get(session.method).map(|a| {
+                a.status(
+                    &session.auth_token,
+                    &session.identity,
+                    code.as_ref().map(|c| c.as_str()),
+                )
+            });
+            result(status).flatten().map(move |s| (session, s))
         })
-        .and_then(move |r: SessionResult| {
-            let d = |status: KeyStatus| KeyResponse { status, key: None };
+        .and_then(
+            move |(session, status): (Session, AuthStatus)| match status {
+                AuthStatus::Unverified(status) => {
+                    Either::A(ok(HttpResponse::Ok().json(KeyResponse {
+                        status,
+                        key: None,
+                        wrapped_key: None,
+                        share: None,
+                    })))
+                }
+                // Recording the key in the audit log blocks on the disk, as may extracting it.
+                AuthStatus::Verified(i) => Either::B(
+                    actix_web::web::block(move || {
+                        let Generation {
+                            pk, backend, share, ..
+                        } = keyring.get(Some(&session.key_id))?;
 
-            let result = match r.status {
-                SessionStatus::Initialized => d(KeyStatus::Initialized),
-                SessionStatus::Connected => d(KeyStatus::Connected),
-                SessionStatus::Cancelled => d(KeyStatus::Cancelled),
-                SessionStatus::Timeout => d(KeyStatus::Timeout),
-                SessionStatus::Done => match fetch_identity(timestamp, &r.disclosed) {
-                    Some(i) => {
+                        let usk = backend.extract_usk(pk, &i.derive())?;
                         let mut rng = rand::thread_rng();
-                        let usk =
-                            ibe::kiltz_vahlis_one::extract_usk(&pk, &sk, &i.derive(), &mut rng);
 
-                        KeyResponse {
-                            status: KeyStatus::DoneValid,
-                            key: Some(usk.into()),
-                        }
-                    }
-                    None => d(KeyStatus::DoneInvalid),
-                },
-            };
+                        // Only the client that started the session can use a wrapped key.
+                        let response = match session.delivery_key {
+                            Some(dk) => KeyResponse {
+                                status: KeyStatus::DoneValid,
+                                key: None,
+                                wrapped_key: Some(
+                                    usk.wrap(&dk, &mut rng).map_err(crate::Error::Core)?,
+                                ),
+                                share: *share,
+                            },
+                            None => KeyResponse {
+                                status: KeyStatus::DoneValid,
+                                key: Some(usk),
+                                wrapped_key: None,
+                                share: *share,
+                            },
+                        };
 
-            Ok(HttpResponse::Ok().json(result))
-        })
+                        // The key is only issued once it has been recorded, and only once per
+                        // session; a failure before leaves the session to be retried.
+                        redeemed.redeem(&session_token, &session, now(), || {
+                            audit.append(
+                                &Entry::new("fetch", session.key_id, *share, &i)
+                                    .session(&session_token),
+                            )?;
+                            Ok(response)
+                        })
+                    })
+                    .map_err(|e| match e {
+                        BlockingError::Error(e) => e,
+                        BlockingError::Canceled => crate::Error::Unexpected,
+                    })
+                    .map(|result| HttpResponse::Ok().json(result)),
+                ),
+            },
+        )
 }
@@ -0,0 +1,283 @@
+//! Passphrase protected secret key files.
+//!
+//! The secret key is encrypted using AES-256-GCM, under a key derived from the passphrase using
+//! Argon2id. The file is formatted as follows, of which the header (parts 1 to 6) is authenticated:
+//!
+//! 1. The tag `ISSK` (4 bytes).
+//! 2. The version of the format, currently `0x01`.
+//! 3. The memory cost of Argon2id in KiB, the time cost and the parallelism, each as 32 bit
+//!    big endian number.
+//! 4. A random salt of 16 bytes.
+//! 5. A random nonce of 12 bytes.
+//! 6. The encrypted secret key, followed by its 16 byte authentication tag.
+
+use aes_gcm::aead::generic_array::GenericArray;
+use aes_gcm::aead::{Aead, NewAead};
+use aes_gcm::Aes256Gcm;
+use arrayref::{array_ref, array_refs};
+use clap::ArgMatches;
+use rand::{CryptoRng, Rng};
+use zeroize::Zeroizing;
+
+use std::cell::RefCell;
+use std::io::Read;
+use std::os::unix::io::{FromRawFd, RawFd};
+
+use crate::Error;
+
+/// The environment variable holding the passphrase of the secret key files.
+pub const PASSPHRASE: &str = "IRMASEAL_PKG_PASSPHRASE";
+
+const PRELUDE: [u8; 4] = *b"ISSK";
+const VERSION: u8 = 0x01;
+
+const SALTSIZE: usize = 16;
+const NONCESIZE: usize = 12;
+const TAGSIZE: usize = 16;
+const HEADERSIZE: usize = 4 + 1 + 3 * 4 + SALTSIZE + NONCESIZE;
+
+/// The cost parameters of Argon2id.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Cost {
+    /// The memory cost in KiB.
+    pub memory: u32,
+    pub time: u32,
+    pub parallelism: u32,
+}
+
+/// The cost with which new key files are written, requiring 256 MiB of memory.
+pub const DEFAULT_COST: Cost = Cost {
+    memory: 256 * 1024,
+    time: 3,
+    parallelism: 1,
+};
+
+/// The highest cost accepted when opening a file, such that a crafted file cannot exhaust the
+/// memory or time of the PKG. It allows for 4 GiB of memory.
+pub const MAX_COST: Cost = Cost {
+    memory: 4 * 1024 * 1024,
+    time: 64,
+    parallelism: 16,
+};
+
+fn derive_key(passphrase: &str, salt: &[u8], cost: Cost) -> Result<Zeroizing<Vec<u8>>, Error> {
+    let config = argon2::Config {
+        variant: argon2::Variant::Argon2id,
+        version: argon2::Version::Version13,
+        mem_cost: cost.memory,
+        time_cost: cost.time,
+        lanes: cost.parallelism,
+        hash_length: 32,
+        ..argon2::Config::default()
+    };
+
+    argon2::hash_raw(passphrase.as_bytes(), salt, &config)
+        .map(Zeroizing::new)
+        .or(Err(Error::Core(irmaseal_core::Error::FormatViolation)))
+}
+
+/// Whether the file is a passphrase protected secret key file.
+pub fn is_sealed(bytes: &[u8]) -> bool {
+    bytes.starts_with(&PRELUDE)
+}
+
+/// Encrypt a secret key under a passphrase.
+pub fn seal<R: Rng + CryptoRng>(
+    sk: &[u8],
+    passphrase: &str,
+    cost: Cost,
+    rng: &mut R,
+) -> Result<Vec<u8>, Error> {
+    let mut res = Vec::with_capacity(HEADERSIZE + sk.len() + TAGSIZE);
+    res.extend_from_slice(&PRELUDE);
+    res.push(VERSION);
+    res.extend_from_slice(&cost.memory.to_be_bytes());
+    res.extend_from_slice(&cost.time.to_be_bytes());
+    res.extend_from_slice(&cost.parallelism.to_be_bytes());
+
+    let mut salt = [0u8; SALTSIZE + NONCESIZE];
+    rng.fill_bytes(&mut salt);
+    res.extend_from_slice(&salt);
+
+    let key = derive_key(passphrase, &salt[..SALTSIZE], cost)?;
+
+    let mut ciphertext = sk.to_vec();
+    let tag = Aes256Gcm::new(*GenericArray::from_slice(&key))
+        .encrypt_in_place_detached(
+            GenericArray::from_slice(&salt[SALTSIZE..]),
+            &res,
+            &mut ciphertext,
+        )
+        .or(Err(Error::Core(irmaseal_core::Error::ConstraintViolation)))?;
+
+    res.extend_from_slice(&ciphertext);
+    res.extend_from_slice(&tag);
+
+    Ok(res)
+}
+
+/// Decrypt a secret key file.
+///
+/// Throws a FormatViolation if the file is malformed, of an unknown version or exceeds `MAX_COST`, and
+/// an InvalidPassphrase if the passphrase is wrong or the file has been tampered with.
+pub fn open(bytes: &[u8], passphrase: &str) -> Result<Zeroizing<Vec<u8>>, Error> {
+    if bytes.len() < HEADERSIZE + TAGSIZE || !is_sealed(bytes) {
+        return Err(Error::Core(irmaseal_core::Error::FormatViolation));
+    }
+
+    let (header, rest) = bytes.split_at(HEADERSIZE);
+    let (_, version, memory, time, parallelism, salt, nonce) = array_refs![
+        array_ref![header, 0, HEADERSIZE],
+        4,
+        1,
+        4,
+        4,
+        4,
+        SALTSIZE,
+        NONCESIZE
+    ];
+    if version[0] != VERSION {
+        return Err(Error::Core(irmaseal_core::Error::FormatViolation));
+    }
+
+    let cost = Cost {
+        memory: u32::from_be_bytes(*memory),
+        time: u32::from_be_bytes(*time),
+        parallelism: u32::from_be_bytes(*parallelism),
+    };
+    if cost.memory > MAX_COST.memory
+        || cost.time > MAX_COST.time
+        || cost.parallelism > MAX_COST.parallelism
+    {
+        return Err(Error::Core(irmaseal_core::Error::FormatViolation));
+    }
+
+    let key = derive_key(passphrase, salt, cost)?;
+
+    let (ciphertext, tag) = rest.split_at(rest.len() - TAGSIZE);
+    let mut res = Zeroizing::new(ciphertext.to_vec());
+    Aes256Gcm::new(*GenericArray::from_slice(&key))
+        .decrypt_in_place_detached(
+            GenericArray::from_slice(nonce),
+            header,
+            &mut res,
+            GenericArray::from_slice(tag),
+        )
+        .or(Err(Error::InvalidPassphrase))?;
+
+    Ok(res)
+}
+
+/// The passphrase of the secret key files, obtained once on first use.
+///
+/// It is read from the file descriptor given on the command line, from the environment, or else
+/// prompted for on the terminal.
+pub struct Passphrase {
+    fd: Option<RawFd>,
+    value: RefCell<Option<Zeroizing<String>>>,
+}
+
+impl Passphrase {
+    pub fn from_args(m: &ArgMatches) -> Passphrase {
+        Passphrase {
+            fd: m
+                .value_of("passphrase-fd")
+                .map(|fd| fd.parse::<RawFd>().unwrap()),
+            value: RefCell::new(None),
+        }
+    }
+
+    /// Obtain the passphrase, which is confirmed if prompted for a new passphrase.
+    pub fn get(&self, new: bool) -> Zeroizing<String> {
+        self.value
+            .borrow_mut()
+            .get_or_insert_with(|| Passphrase::obtain(self.fd, new))
+            .clone()
+    }
+
+    fn obtain(fd: Option<RawFd>, new: bool) -> Zeroizing<String> {
+        if let Some(fd) = fd {
+            // Only the first line is the passphrase.
+            let mut res = Zeroizing::new(String::new());
+            // SAFETY: the descriptor is given on the command line for this purpose only, thus it
+            // is open and not used elsewhere, and the file takes ownership of it to close it.
+            unsafe { std::fs::File::from_raw_fd(fd) }
+                .read_to_string(&mut res)
+                .unwrap();
+            let len = res.lines().next().map_or(0, str::len);
+            res.truncate(len);
+            return res;
+        }
+
+        if let Ok(passphrase) = std::env::var(PASSPHRASE) {
+            return Zeroizing::new(passphrase);
+        }
+
+        loop {
+            let res = Zeroizing::new(
+                rpassword::read_password_from_tty(Some("Passphrase of the secret key: ")).unwrap(),
+            );
+            if !new {
+                return res;
+            }
+
+            let confirmed = Zeroizing::new(
+                rpassword::read_password_from_tty(Some("Repeat the passphrase: ")).unwrap(),
+            );
+            if res.is_empty() {
+                eprintln!("The passphrase must not be empty");
+            } else if *res != *confirmed {
+                eprintln!("The passphrases do not match");
+            } else {
+                return res;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const COST: Cost = Cost {
+        memory: 64,
+        time: 1,
+        parallelism: 1,
+    };
+
+    #[test]
+    fn reflection() {
+        let mut rng = rand::thread_rng();
+        let sk = [7u8; 48];
+
+        let sealed = seal(&sk, "correct horse", COST, &mut rng).unwrap();
+        assert!(is_sealed(&sealed));
+        assert_eq!(sealed.len(), HEADERSIZE + sk.len() + TAGSIZE);
+        assert_eq!(open(&sealed, "correct horse").unwrap()[..], sk[..]);
+
+        assert!(matches!(
+            open(&sealed, "battery staple"),
+            Err(Error::InvalidPassphrase)
+        ));
+
+        // The header is authenticated.
+        let mut tampered = sealed.clone();
+        tampered[8] ^= 1;
+        assert!(open(&tampered, "correct horse").is_err());
+
+        let mut unknown = sealed.clone();
+        unknown[4] = 0x02;
+        assert!(matches!(
+            open(&unknown, "correct horse"),
+            Err(Error::Core(irmaseal_core::Error::FormatViolation))
+        ));
+
+        // An excessive cost is rejected before deriving the key.
+        let mut expensive = sealed;
+        expensive[5..9].copy_from_slice(&u32::MAX.to_be_bytes());
+        assert!(matches!(
+            open(&expensive, "correct horse"),
+            Err(Error::Core(irmaseal_core::Error::FormatViolation))
+        ));
+    }
+}
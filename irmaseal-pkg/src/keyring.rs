@@ -0,0 +1,193 @@
+//! The generations of the master key served by the PKG.
+//!
+//! Streams record the key ID of the master key under which they were sealed. Thus after rotating
+//! the master key, the PKG keeps serving the retired keys such that existing streams remain readable.
+
+use ibe::kiltz_vahlis_one::PublicKey;
+use irmaseal_core::api::{KeyGeneration, KeyGenerations, MAX_GENERATIONS};
+use irmaseal_core::KeyId;
+use serde::Deserialize;
+
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use crate::backend::{KeyBackend, KeyStore};
+use crate::util::read_pk;
+use crate::Error;
+
+/// A single generation of the master key.
+#[derive(Clone)]
+pub struct Generation {
+    pub pk: PublicKey,
+    /// The backend holding the master secret key.
+    pub backend: Arc<dyn KeyBackend>,
+    /// The index of the share of the master secret key, if this PKG is a node of a threshold PKG.
+    pub share: Option<u8>,
+    pub key_id: KeyId,
+    /// The UNIX timestamp from which the key is used for sealing.
+    pub valid_from: u64,
+    /// The UNIX timestamp from which the key is retired, or `None` for the active key.
+    pub valid_until: Option<u64>,
+}
+
+/// All generations of the master key served by the PKG, of which exactly one is active.
+#[derive(Clone)]
+pub struct Keyring {
+    generations: Vec<Generation>,
+}
+
+/// A generation as described in a keyring file.
+#[derive(Deserialize)]
+struct Entry {
+    public: PathBuf,
+    secret: PathBuf,
+    valid_from: u64,
+    #[serde(default)]
+    valid_until: Option<u64>,
+}
+
+impl Generation {
+    pub fn new(pk: PublicKey, backend: Arc<dyn KeyBackend>, share: Option<u8>) -> Generation {
+        Generation {
+            pk,
+            backend,
+            share,
+            key_id: irmaseal_core::PublicKey::from(pk).key_id(),
+            valid_from: 0,
+            valid_until: None,
+        }
+    }
+}
+
+impl Keyring {
+    /// Throws a ConstraintViolation if not exactly one generation is active, if more than
+    /// `MAX_GENERATIONS` are given, if a key ID occurs more than once, or if a period is empty.
+    pub fn new(generations: Vec<Generation>) -> Result<Keyring, irmaseal_core::Error> {
+        let active = generations
+            .iter()
+            .filter(|g| g.valid_until.is_none())
+            .count();
+        let distinct = generations
+            .iter()
+            .enumerate()
+            .all(|(i, g)| generations[..i].iter().all(|h| h.key_id != g.key_id));
+        let periods = generations
+            .iter()
+            .all(|g| g.valid_until.map_or(true, |until| g.valid_from < until));
+
+        if active != 1 || generations.len() > MAX_GENERATIONS || !distinct || !periods {
+            return Err(irmaseal_core::Error::ConstraintViolation);
+        }
+
+        Ok(Keyring { generations })
+    }
+
+    /// Read a keyring file, a JSON list of generations with the paths of their public and secret key,
+    /// relative to the keyring file, and their validity period. The secret keys are opened in the store.
+    pub fn read(path: impl AsRef<Path>, store: &KeyStore) -> Result<Keyring, Error> {
+        let path = path.as_ref();
+        let dir = path.parent().unwrap_or_else(|| Path::new("."));
+
+        let entries: Vec<Entry> = serde_json::from_slice(&std::fs::read(path).unwrap())
+            .or(Err(Error::Core(irmaseal_core::Error::FormatViolation)))?;
+
+        let generations = entries
+            .into_iter()
+            .map(|e| {
+                let (backend, share) = store.open(dir.join(&e.secret))?;
+                let pk = read_pk(dir.join(&e.public)).map_err(Error::Core)?;
+                Ok(Generation {
+                    valid_from: e.valid_from,
+                    valid_until: e.valid_until,
+                    ..Generation::new(pk, backend, share)
+                })
+            })
+            .collect::<Result<_, Error>>()?;
+
+        Keyring::new(generations).map_err(Error::Core)
+    }
+
+    /// The generation with which new streams are sealed.
+    pub fn active(&self) -> &Generation {
+        self.generations
+            .iter()
+            .find(|g| g.valid_until.is_none())
+            .unwrap()
+    }
+
+    /// The generation with the given key ID, or the active generation if none is given.
+    pub fn get(&self, key_id: Option<&KeyId>) -> Result<&Generation, Error> {
+        match key_id {
+            Some(key_id) => self
+                .generations
+                .iter()
+                .find(|g| g.key_id == *key_id)
+                .ok_or(Error::UnknownKey),
+            None => Ok(self.active()),
+        }
+    }
+
+    /// The generations as listed in the public parameters.
+    pub fn generations(&self) -> KeyGenerations {
+        self.generations
+            .iter()
+            .map(|g| KeyGeneration {
+                key_id: g.key_id,
+                valid_from: g.valid_from,
+                valid_until: g.valid_until,
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::FileBackend;
+
+    fn generation(valid_from: u64, valid_until: Option<u64>) -> Generation {
+        let (pk, sk) = ibe::kiltz_vahlis_one::setup(&mut rand::thread_rng());
+        Generation {
+            valid_from,
+            valid_until,
+            ..Generation::new(pk, Arc::new(FileBackend::new(sk)), None)
+        }
+    }
+
+    #[test]
+    fn generations() {
+        let retired = generation(1546300800, Some(1577836800));
+        let active = generation(1577836800, None);
+        let (old, new) = (retired.key_id, active.key_id);
+
+        let keyring = Keyring::new(vec![retired, active]).unwrap();
+
+        assert_eq!(keyring.active().key_id, new);
+        assert_eq!(keyring.get(None).unwrap().key_id, new);
+        assert_eq!(keyring.get(Some(&old)).unwrap().key_id, old);
+        assert!(matches!(
+            keyring.get(Some(&KeyId([0u8; 8]))),
+            Err(Error::UnknownKey)
+        ));
+
+        let listed = keyring.generations();
+        assert_eq!(listed.len(), 2);
+        assert_eq!(listed[0].valid_until, Some(1577836800));
+        assert_eq!(listed[1].valid_until, None);
+    }
+
+    #[test]
+    fn invalid() {
+        assert!(Keyring::new(vec![]).is_err());
+        assert!(Keyring::new(vec![generation(0, None), generation(0, None)]).is_err());
+        assert!(Keyring::new(vec![generation(0, Some(100))]).is_err());
+        assert!(Keyring::new(vec![generation(100, Some(100)), generation(100, None)]).is_err());
+
+        let active = generation(0, None);
+        let duplicate = Generation {
+            valid_until: Some(100),
+            ..active.clone()
+        };
+        assert!(Keyring::new(vec![duplicate, active]).is_err());
+    }
+}
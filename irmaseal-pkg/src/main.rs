@@ -1,7 +1,18 @@
+mod audit;
+mod auth;
+mod backend;
+mod backup;
+mod combine;
 mod error;
+mod extract;
 mod generate;
 mod handlers;
+mod keyfile;
+mod keyring;
+mod policy;
 mod server;
+mod session;
+mod split;
 mod util;
 
 pub use crate::error::*;
@@ -16,5 +27,13 @@ fn main() {
         crate::generate::exec(matches);
     } else if let Some(matches) = matches.subcommand_matches("server") {
         crate::server::exec(matches);
+    } else if let Some(matches) = matches.subcommand_matches("split") {
+        crate::split::exec(matches);
+    } else if let Some(matches) = matches.subcommand_matches("combine") {
+        crate::combine::exec(matches);
+    } else if let Some(matches) = matches.subcommand_matches("extract") {
+        crate::extract::exec(matches);
+    } else if let Some(matches) = matches.subcommand_matches("audit") {
+        crate::audit::exec(matches);
     }
 }
@@ -0,0 +1,87 @@
+use irmaseal_core::api::Parameters;
+use irmaseal_core::stream::FORMAT_VERSION;
+
+use crate::keyring::Keyring;
+use crate::Error;
+
+/// The policy by which the PKG issues user secret keys, depending on the timeframe of the identity.
+#[derive(Clone, Debug)]
+pub struct Policy {
+    /// The duration of a timeframe in seconds, to which the timestamps of identities must be aligned.
+    pub granularity: u64,
+    /// The maximum age in seconds of a timeframe for which keys are still issued, if limited.
+    pub retention: Option<u64>,
+    /// The number of seconds a timeframe may start in the future, to allow for clock skew.
+    pub skew: u64,
+}
+
+impl Policy {
+    /// Check whether a key may be issued for an identity with `timestamp` at the moment `now`.
+    pub fn check(&self, timestamp: u64, now: u64) -> Result<(), Error> {
+        if timestamp % self.granularity != 0 {
+            return Err(Error::AlignmentError);
+        }
+
+        if timestamp > now.saturating_add(self.skew) {
+            return Err(Error::ChronologyError);
+        }
+
+        match self.retention {
+            Some(retention) if now.saturating_sub(timestamp) > retention => {
+                Err(Error::RetentionError)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// The public parameters advertising this policy and the master keys of the keyring.
+    pub fn parameters(&self, keyring: &Keyring) -> Parameters {
+        Parameters {
+            format_version: FORMAT_VERSION,
+            max_age: self.granularity,
+            max_retention: self.retention,
+            max_skew: self.skew,
+            public_key: keyring.active().pk.into(),
+            keys: keyring.generations(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn check() {
+        let policy = Policy {
+            granularity: 300,
+            retention: Some(3600),
+            skew: 30,
+        };
+
+        assert!(policy.check(1566722100, 1566722350).is_ok());
+        assert!(matches!(
+            policy.check(1566722101, 1566722350),
+            Err(Error::AlignmentError)
+        ));
+
+        // The next timeframe only within the clock skew.
+        assert!(policy.check(1566722400, 1566722370).is_ok());
+        assert!(matches!(
+            policy.check(1566722400, 1566722350),
+            Err(Error::ChronologyError)
+        ));
+
+        assert!(policy.check(1566722100, 1566722100 + 3600).is_ok());
+        assert!(matches!(
+            policy.check(1566722100, 1566722100 + 3601),
+            Err(Error::RetentionError)
+        ));
+
+        let unlimited = Policy {
+            retention: None,
+            ..policy
+        };
+        assert!(unlimited.check(0, 1566722350).is_ok());
+    }
+}
@@ -1,14 +1,37 @@
 use actix_rt::System;
+use actix_web::web::{get, post, resource, ServiceConfig};
 use clap::ArgMatches;
 
+use crate::audit::AuditLog;
+use crate::auth::Authenticators;
+use crate::backend::KeyStore;
 use crate::handlers;
-use crate::util::{read_pk, read_sk};
+use crate::keyring::{Generation, Keyring};
+use crate::policy::Policy;
+use crate::session::{Redeemed, SessionKey};
+use crate::util::read_pk;
+use std::num::NonZeroU64;
+use std::sync::Arc;
 
 #[derive(Clone)]
 pub struct AppState {
-    pub pk: ibe::kiltz_vahlis_one::PublicKey,
-    pub sk: ibe::kiltz_vahlis_one::SecretKey,
-    pub irma_server_host: String,
+    pub keyring: Arc<Keyring>,
+    pub audit: Arc<AuditLog>,
+    pub auth: Arc<Authenticators>,
+    pub policy: Policy,
+    pub session_key: SessionKey,
+    pub redeemed: Arc<Redeemed>,
+}
+
+/// Register the resources of the HTTP API.
+pub fn routes(cfg: &mut ServiceConfig) {
+    cfg.service(resource("/v1/parameters").route(get().to_async(handlers::parameters)))
+        .service(resource("/v1/request").route(post().to_async(handlers::request)))
+        .service(
+            resource("/v1/request/{token}")
+                .route(get().to_async(handlers::request_fetch))
+                .route(post().to_async(handlers::request_fetch)),
+        );
 }
 
 pub fn exec(m: &ArgMatches) {
@@ -18,12 +41,42 @@ pub fn exec(m: &ArgMatches) {
     let public = m.value_of("public").unwrap();
     let secret = m.value_of("secret").unwrap();
 
-    let irma_server_host = m.value_of("irma").unwrap().to_string();
+    let policy = Policy {
+        granularity: m
+            .value_of("granularity")
+            .unwrap()
+            .parse::<NonZeroU64>()
+            .unwrap()
+            .get(),
+        retention: m.value_of("retention").map(|r| r.parse::<u64>().unwrap()),
+        skew: m.value_of("skew").unwrap().parse::<u64>().unwrap(),
+    };
+
+    let store = KeyStore::from_args(m, false);
+
+    // Without a keyring, the single key pair is the active generation.
+    let keyring = match m.value_of("keyring") {
+        Some(keyring) => Keyring::read(keyring, &store).unwrap(),
+        None => {
+            let (backend, share) = store.open(secret).unwrap();
+            Keyring::new(vec![Generation::new(
+                read_pk(public).unwrap(),
+                backend,
+                share,
+            )])
+            .unwrap()
+        }
+    };
+
+    let session_key = keyring.active().backend.session_key().unwrap();
 
     let state = AppState {
-        pk: read_pk(public).unwrap(),
-        sk: read_sk(secret).unwrap(),
-        irma_server_host,
+        keyring: Arc::new(keyring),
+        audit: Arc::new(AuditLog::open(m.value_of("audit").unwrap()).unwrap()),
+        auth: Arc::new(Authenticators::from_args(m, &session_key)),
+        policy,
+        session_key,
+        redeemed: Arc::new(Redeemed::default()),
     };
 
     let system = System::new("main");
@@ -32,18 +85,7 @@ pub fn exec(m: &ArgMatches) {
         actix_web::App::new()
             .data(actix_web::web::JsonConfig::default().limit(1024 * 4096))
             .data(state.clone())
-            .service(
-                actix_web::web::resource("/v1/parameters")
-                    .route(actix_web::web::get().to_async(handlers::parameters)),
-            )
-            .service(
-                actix_web::web::resource("/v1/request")
-                    .route(actix_web::web::post().to_async(handlers::request)),
-            )
-            .service(
-                actix_web::web::resource("/v1/request/{token}/{timestamp}")
-                    .route(actix_web::web::get().to_async(handlers::request_fetch)),
-            )
+            .configure(routes)
     })
     .bind(format!("{}:{}", host, port))
     .unwrap()
@@ -52,3 +94,151 @@ pub fn exec(m: &ArgMatches) {
 
     system.run().unwrap();
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test;
+    use irmaseal_core::api::{AuthMethod, KeyResponse, KeyStatus};
+    use irmaseal_core::stream::{OpenerSealed, Sealer};
+    use irmaseal_core::util::SliceReader;
+    use irmaseal_core::{threshold, Identity, Recipient, UserSecretKey, Writable};
+
+    use crate::auth::{EmailAuthenticator, IrmaAuthenticator, Relay};
+    use crate::backend::FileBackend;
+    use crate::session::{Session, MAX_SESSION_AGE};
+    use crate::util::{now, parse_sk};
+
+    struct Buf(Vec<u8>);
+
+    impl Writable for Buf {
+        fn write(&mut self, data: &[u8]) -> Result<(), irmaseal_core::Error> {
+            self.0.extend_from_slice(data);
+            Ok(())
+        }
+    }
+
+    /// Fetch the partial key of a node of a threshold PKG, holding the given share of the master
+    /// secret key, in a verified e-mail session.
+    fn fetch(
+        pk: ibe::kiltz_vahlis_one::PublicKey,
+        share: &threshold::SecretKeyShare,
+        identity: &Identity,
+    ) -> (u8, UserSecretKey) {
+        let (sk, index) = parse_sk(&share.to_bytes()).unwrap();
+        let generation = Generation::new(pk, Arc::new(FileBackend::new(sk)), index);
+        let key_id = generation.key_id;
+        let keyring = Keyring::new(vec![generation]).unwrap();
+        let session_key = keyring.active().backend.session_key().unwrap();
+
+        let audit = std::env::temp_dir().join(format!(
+            "irmaseal-node-{}-{}",
+            index.unwrap(),
+            std::process::id()
+        ));
+        let email = EmailAuthenticator::new(
+            Relay::new("127.0.0.1:25", None),
+            "pkg@example.com",
+            &session_key,
+        );
+        let code = email.code("nonce", identity);
+        let irma = IrmaAuthenticator::new(
+            "http://localhost:8088",
+            jsonwebtoken::DecodingKey::from_secret(b"unused").into_static(),
+        );
+
+        let state = AppState {
+            keyring: Arc::new(keyring),
+            audit: Arc::new(AuditLog::open(&audit).unwrap()),
+            auth: Arc::new(Authenticators::new(irma, Some(email))),
+            policy: Policy {
+                granularity: 300,
+                retention: None,
+                skew: 0,
+            },
+            session_key,
+            redeemed: Arc::new(Redeemed::default()),
+        };
+        let mut app = test::init_service(actix_web::App::new().data(state).configure(routes));
+
+        // The session as started by the e-mail authenticator.
+        let token = Session {
+            method: AuthMethod::Email,
+            auth_token: "nonce".to_string(),
+            identity: identity.clone(),
+            key_id,
+            delivery_key: None,
+            expires: now() + MAX_SESSION_AGE,
+        }
+        .seal(&session_key);
+
+        let req = test::TestRequest::post()
+            .uri(&format!("/v1/request/{}", token))
+            .set_json(&serde_json::json!({ "code": code }))
+            .to_request();
+        let response: KeyResponse = test::read_response_json(&mut app, req);
+        std::fs::remove_file(&audit).unwrap();
+
+        assert_eq!(response.status, KeyStatus::DoneValid);
+        assert_eq!(response.share, index);
+        (response.share.unwrap(), response.key.unwrap())
+    }
+
+    #[test]
+    fn threshold_nodes() {
+        let mut rng = rand::thread_rng();
+        let (pk, sk) = ibe::kiltz_vahlis_one::setup(&mut rng);
+        let identity = Identity::new(
+            1566722100,
+            "pbdf.pbdf.email.email",
+            Some("w.geraedts@sarif.nl"),
+        )
+        .unwrap();
+
+        let mut s = Sealer::new(
+            &[Recipient::new(identity.clone())],
+            &irmaseal_core::PublicKey::from(pk),
+            &mut rng,
+            Buf(Vec::new()),
+        )
+        .unwrap();
+        s.write(b"threshold").unwrap();
+        let sealed = s.finish().unwrap().0;
+
+        // Every node is a PKG holding a single share, of which two are required.
+        let partials: Vec<(u8, UserSecretKey)> = threshold::split(&sk, 2, 3, &mut rng)
+            .unwrap()
+            .iter()
+            .map(|share| fetch(pk, share, &identity))
+            .collect();
+
+        // Partial keys are not Clone, thus combined from their serialization.
+        let partials: Vec<(u8, String)> = partials
+            .iter()
+            .map(|(i, usk)| (*i, serde_json::to_string(usk).unwrap()))
+            .collect();
+        let open = |partials: Vec<&(u8, String)>| {
+            let partials: Vec<(u8, UserSecretKey)> = partials
+                .into_iter()
+                .map(|(i, usk)| (*i, serde_json::from_str(usk).unwrap()))
+                .collect();
+            let usk = threshold::combine(&partials)?;
+            let (_, o) = OpenerSealed::new(SliceReader::new(&sealed))?;
+            let mut res = Buf(Vec::new());
+            o.unseal(0, &usk)?.write_to(&mut res)?;
+            Ok::<_, irmaseal_core::Error>(res.0)
+        };
+
+        // No single node can decrypt, whereas any two can.
+        for i in 0..partials.len() {
+            assert!(open(vec![&partials[i]]).is_err());
+
+            for j in i + 1..partials.len() {
+                assert_eq!(
+                    open(vec![&partials[i], &partials[j]]).unwrap(),
+                    b"threshold"
+                );
+            }
+        }
+    }
+}
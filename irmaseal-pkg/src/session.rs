@@ -0,0 +1,224 @@
+//! Stateless descriptors of key issuance sessions.
+//!
+//! The descriptor binds a session of an authenticator, such as an IRMA session, to the exact identity for which keys are issued, and is
+//! authenticated by the PKG such that it can be carried by the client as the session token.
+
+use hmac::{Hmac, Mac};
+use irmaseal_core::api::AuthMethod;
+use irmaseal_core::{DeliveryPublicKey, Identity, KeyId};
+use serde::{Deserialize, Serialize};
+use sha3::{Digest, Sha3_256};
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use crate::Error;
+
+type Verifier = Hmac<Sha3_256>;
+
+/// The number of seconds after which a session descriptor expires.
+pub const MAX_SESSION_AGE: u64 = 600;
+
+/// The key with which session descriptors are authenticated.
+pub type SessionKey = [u8; 32];
+
+/// Derive the key for session descriptors from the active global secret key, such that every instance
+/// of the PKG accepts the descriptors of the others.
+pub fn derive_session_key(sk: &ibe::kiltz_vahlis_one::SecretKey) -> SessionKey {
+    let mut h = Sha3_256::new();
+    h.input(b"irmaseal-pkg session");
+    h.input(sk.to_bytes().as_ref());
+
+    let mut res = [0u8; 32];
+    res.copy_from_slice(&h.result());
+    res
+}
+
+/// A key issuance session for a single identity.
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct Session {
+    /// The method with which the identity is verified.
+    #[serde(default)]
+    pub method: AuthMethod,
+    /// The token of the session of the authenticator, such as the underlying IRMA session.
+    pub auth_token: String,
+    /// The identity for which keys are issued.
+    pub identity: Identity,
+    /// The key ID of the master key under which keys are issued.
+    pub key_id: KeyId,
+    /// The public key of the client to which issued keys are wrapped, if requested.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub delivery_key: Option<DeliveryPublicKey>,
+    /// The UNIX timestamp after which no more keys are issued.
+    pub expires: u64,
+}
+
+impl Session {
+    /// Serialize the descriptor followed by its MAC, both base64 encoded and separated by a dot.
+    pub fn seal(&self, key: &SessionKey) -> String {
+        let payload = serde_json::to_vec(self).unwrap();
+
+        let mut mac = Verifier::new_varkey(key).unwrap();
+        mac.input(&payload);
+
+        format!(
+            "{}.{}",
+            base64::encode_config(&payload, base64::URL_SAFE_NO_PAD),
+            base64::encode_config(mac.result().code(), base64::URL_SAFE_NO_PAD)
+        )
+    }
+
+    /// Authenticate a descriptor as serialized by `seal`, which must not have expired at `now`.
+    pub fn open(token: &str, key: &SessionKey, now: u64) -> Result<Session, Error> {
+        let mut parts = token.split('.');
+        let (payload, code) = match (parts.next(), parts.next(), parts.next()) {
+            (Some(payload), Some(code), None) => (payload, code),
+            _ => return Err(Error::InvalidSession),
+        };
+
+        let payload = base64::decode_config(payload, base64::URL_SAFE_NO_PAD)
+            .or(Err(Error::InvalidSession))?;
+        let code =
+            base64::decode_config(code, base64::URL_SAFE_NO_PAD).or(Err(Error::InvalidSession))?;
+
+        let mut mac = Verifier::new_varkey(key).unwrap();
+        mac.input(&payload);
+        mac.verify(&code).or(Err(Error::InvalidSession))?;
+
+        let session: Session = serde_json::from_slice(&payload).or(Err(Error::InvalidSession))?;
+
+        if now > session.expires {
+            return Err(Error::SessionExpired);
+        }
+
+        Ok(session)
+    }
+}
+
+/// The sessions of which a key has been issued, such that every session yields a key only once.
+///
+/// Sessions are forgotten once expired, as their descriptors are rejected from then on. The set is
+/// only kept in memory, thus it is not shared between instances of the PKG, nor kept over a restart;
+/// the audit log does record every key issued for a session.
+#[derive(Default)]
+pub struct Redeemed(Mutex<HashMap<String, u64>>);
+
+impl Redeemed {
+    /// Redeem the session with the given token by `issue`, throwing a SessionRedeemed if it already was.
+    ///
+    /// The session is only marked as redeemed if `issue` succeeds, and no other key is issued for it
+    /// in the meantime.
+    pub fn redeem<T>(
+        &self,
+        token: &str,
+        session: &Session,
+        now: u64,
+        issue: impl FnOnce() -> Result<T, Error>,
+    ) -> Result<T, Error> {
+        let mut redeemed = self.0.lock().unwrap();
+        redeemed.retain(|_, expires| *expires >= now);
+
+        if redeemed.contains_key(token) {
+            return Err(Error::SessionRedeemed);
+        }
+        let res = issue()?;
+        redeemed.insert(token.to_string(), session.expires);
+
+        Ok(res)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn session() -> Session {
+        Session {
+            method: AuthMethod::Irma,
+            auth_token: "RiAoxAPqxtxeWAaLYmAE".to_string(),
+            identity: Identity::new(
+                1566722100,
+                "pbdf.pbdf.email.email",
+                Some("w.geraedts@sarif.nl"),
+            )
+            .unwrap(),
+            key_id: KeyId([0x42u8; 8]),
+            delivery_key: None,
+            expires: 1566722700,
+        }
+    }
+
+    #[test]
+    fn reflection() {
+        let key = [0x42u8; 32];
+        let token = session().seal(&key);
+
+        assert_eq!(Session::open(&token, &key, 1566722350).unwrap(), session());
+        assert!(matches!(
+            Session::open(&token, &key, 1566722701),
+            Err(Error::SessionExpired)
+        ));
+        assert!(matches!(
+            Session::open(&token, &[0x43u8; 32], 1566722350),
+            Err(Error::InvalidSession)
+        ));
+    }
+
+    #[test]
+    fn tampered() {
+        let key = [0x42u8; 32];
+        let token = session().seal(&key);
+        let code = token.split('.').nth(1).unwrap();
+
+        // Another identity with the MAC of the original.
+        let mut other = session();
+        other.identity.timestamp += 300;
+        let forged = other.seal(&key);
+        let forged = format!("{}.{}", forged.split('.').next().unwrap(), code);
+
+        assert!(matches!(
+            Session::open(&forged, &key, 1566722350),
+            Err(Error::InvalidSession)
+        ));
+        assert!(matches!(
+            Session::open("garbage", &key, 1566722350),
+            Err(Error::InvalidSession)
+        ));
+    }
+
+    #[test]
+    fn redeemed() {
+        let key = [0x42u8; 32];
+        let token = session().seal(&key);
+        let redeemed = Redeemed::default();
+
+        let issue = || Ok(());
+
+        // A session is not redeemed if issuing fails, such that it can be retried.
+        assert!(matches!(
+            redeemed.redeem(&token, &session(), 1566722340, || Err::<(), _>(
+                Error::BackendError
+            )),
+            Err(Error::BackendError)
+        ));
+        redeemed
+            .redeem(&token, &session(), 1566722350, issue)
+            .unwrap();
+        assert!(matches!(
+            redeemed.redeem(&token, &session(), 1566722360, issue),
+            Err(Error::SessionRedeemed)
+        ));
+
+        // Other sessions are unaffected, and expired sessions are forgotten.
+        let mut other = session();
+        other.expires += 300;
+        let other_token = other.seal(&key);
+        redeemed
+            .redeem(&other_token, &other, 1566722360, issue)
+            .unwrap();
+        assert!(redeemed
+            .redeem(&other_token, &other, 1566722701, issue)
+            .is_err());
+        assert_eq!(redeemed.0.lock().unwrap().len(), 1);
+    }
+}
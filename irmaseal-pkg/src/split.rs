@@ -0,0 +1,52 @@
+use clap::ArgMatches;
+
+use crate::backup::{split, verify};
+use crate::keyfile::Passphrase;
+use crate::util::{read_pk, read_sk, write_owned};
+
+pub fn exec(m: &ArgMatches) {
+    let mut rng = rand::thread_rng();
+
+    let public = m.value_of("public").unwrap();
+    let secret = m.value_of("secret").unwrap();
+    let output = m.value_of("output").unwrap();
+
+    let shares = m.value_of("shares").unwrap().parse::<u8>().unwrap();
+    let threshold = m.value_of("threshold").unwrap().parse::<u8>().unwrap();
+
+    let pk = read_pk(public).unwrap();
+    let (sk, share) = read_sk(secret, &Passphrase::from_args(m)).unwrap();
+
+    // A share of a threshold PKG cannot be verified against the public key on its own.
+    if share.is_some() {
+        eprintln!(
+            "{} is a share of the private key, which cannot be split",
+            secret
+        );
+        std::process::exit(1);
+    }
+    if !verify(&pk, &sk, &mut rng) {
+        eprintln!("{} is not the private key of {}", secret, public);
+        std::process::exit(1);
+    }
+
+    for share in split(&pk, &sk, threshold, shares, &mut rng).unwrap() {
+        let contents = match m.value_of("format") {
+            Some("mnemonic") => share.to_mnemonic(),
+            _ => share.to_armor(),
+        };
+
+        if m.is_present("print") {
+            println!("{}", contents);
+        } else {
+            let path = format!("{}.{}", output, share.index);
+            write_owned(&path, contents);
+            println!("Written share {}", path);
+        }
+    }
+
+    println!(
+        "Split {} into {} shares, of which {} are required",
+        secret, shares, threshold
+    );
+}
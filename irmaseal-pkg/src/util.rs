@@ -1,9 +1,15 @@
 use arrayref::array_ref;
 use ibe::kiltz_vahlis_one::{PublicKey, SecretKey};
+use irmaseal_core::threshold::{SecretKeyShare, SHARESIZE};
 use irmaseal_core::Error;
+use zeroize::Zeroizing;
 
+use std::fs::OpenOptions;
+use std::os::unix::fs::OpenOptionsExt;
 use std::path::Path;
 
+use crate::keyfile::{self, Passphrase};
+
 pub fn read_pk(path: impl AsRef<Path>) -> Result<PublicKey, Error> {
     const LENGTH: usize = 25056;
 
@@ -13,19 +19,57 @@ pub fn read_pk(path: impl AsRef<Path>) -> Result<PublicKey, Error> {
     }
 
     let bytes = array_ref![&bytes, 0, LENGTH];
-    Ok(open_ct(PublicKey::from_bytes(bytes)).ok_or(Error::FormatViolation)?)
+    open_ct(PublicKey::from_bytes(bytes)).ok_or(Error::FormatViolation)
+}
+
+/// Read either the master secret key, or a share of it together with the index of that share.
+///
+/// If the file is protected by a passphrase, the passphrase is obtained to decrypt it.
+pub fn read_sk(
+    path: impl AsRef<Path>,
+    passphrase: &Passphrase,
+) -> Result<(SecretKey, Option<u8>), crate::Error> {
+    let bytes = Zeroizing::new(std::fs::read(path).unwrap());
+    let bytes = if keyfile::is_sealed(&bytes) {
+        keyfile::open(&bytes, &passphrase.get(false))?
+    } else {
+        bytes
+    };
+    parse_sk(&bytes).map_err(crate::Error::Core)
 }
 
-pub fn read_sk(path: impl AsRef<Path>) -> Result<SecretKey, Error> {
+/// Parse either the master secret key, or a share of it together with the index of that share.
+pub fn parse_sk(bytes: &[u8]) -> Result<(SecretKey, Option<u8>), Error> {
     const LENGTH: usize = 48;
 
-    let bytes = std::fs::read(path).unwrap();
-    if bytes.len() != LENGTH {
-        return Err(Error::FormatViolation);
+    match bytes.len() {
+        LENGTH => {
+            let bytes = array_ref![bytes, 0, LENGTH];
+            let sk = open_ct(SecretKey::from_bytes(bytes)).ok_or(Error::FormatViolation)?;
+            Ok((sk, None))
+        }
+        SHARESIZE => {
+            let share = SecretKeyShare::from_bytes(array_ref![bytes, 0, SHARESIZE])?;
+            Ok((*share.secret_key(), Some(share.index())))
+        }
+        _ => Err(Error::FormatViolation),
     }
+}
 
-    let bytes = array_ref![&bytes, 0, LENGTH];
-    Ok(open_ct(SecretKey::from_bytes(bytes)).ok_or(Error::FormatViolation)?)
+/// Write a new file, readable only by the owner.
+pub fn write_owned<P: AsRef<Path>, C: AsRef<[u8]>>(path: P, contents: C) {
+    fn inner(path: &Path, contents: &[u8]) {
+        use std::io::Write;
+        OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(0o600)
+            .open(path)
+            .unwrap()
+            .write_all(contents)
+            .unwrap()
+    }
+    inner(path.as_ref(), contents.as_ref())
 }
 
 pub fn open_ct<T>(x: subtle::CtOption<T>) -> Option<T> {
@@ -35,3 +79,11 @@ pub fn open_ct<T>(x: subtle::CtOption<T>) -> Option<T> {
         None
     }
 }
+
+/// The current UNIX timestamp in seconds.
+pub fn now() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
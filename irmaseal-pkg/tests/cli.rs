@@ -0,0 +1,96 @@
+//! Runs the `irmaseal-pkg` binary, checking that failures are reported by the exit status.
+
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output};
+
+fn pkg(dir: &Path, args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_irmaseal-pkg"))
+        .current_dir(dir)
+        .args(args)
+        .output()
+        .unwrap()
+}
+
+fn tempdir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("irmaseal-pkg-{}-{}", name, std::process::id()));
+    std::fs::create_dir(&dir).unwrap();
+    dir
+}
+
+#[test]
+fn combine_corrupted_share() {
+    let dir = tempdir("combine");
+
+    assert!(pkg(&dir, &["generate"]).status.success());
+    assert!(pkg(&dir, &["split", "-n", "3", "-t", "2"]).status.success());
+
+    // Alter a single character of the encoded share.
+    let share = std::fs::read_to_string(dir.join("pkg.backup.2")).unwrap();
+    let mut lines: Vec<String> = share.lines().map(str::to_string).collect();
+    let line = lines
+        .iter_mut()
+        .find(|l| !l.is_empty() && !l.contains(':') && !l.starts_with("-----"))
+        .unwrap();
+    let c = if line.starts_with('A') { "B" } else { "A" };
+    line.replace_range(..1, c);
+    std::fs::write(dir.join("pkg.backup.2"), lines.join("\n")).unwrap();
+
+    let out = pkg(
+        &dir,
+        &[
+            "combine",
+            "-S",
+            "restored.sec",
+            "pkg.backup.1",
+            "pkg.backup.2",
+        ],
+    );
+    assert!(!out.status.success());
+    assert!(!dir.join("restored.sec").exists());
+
+    // The intact shares do restore the key.
+    let out = pkg(
+        &dir,
+        &[
+            "combine",
+            "-S",
+            "restored.sec",
+            "pkg.backup.1",
+            "pkg.backup.3",
+        ],
+    );
+    assert!(out.status.success());
+    assert_eq!(
+        std::fs::read(dir.join("restored.sec")).unwrap(),
+        std::fs::read(dir.join("pkg.sec")).unwrap()
+    );
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn extract_malformed_identity() {
+    let dir = tempdir("extract");
+
+    assert!(pkg(&dir, &["generate"]).status.success());
+
+    let out = pkg(
+        &dir,
+        &["extract", "-t", "1566722100", "-i", "pbdf.pbdf.email.email"],
+    );
+    assert!(!out.status.success());
+
+    let out = pkg(
+        &dir,
+        &[
+            "extract",
+            "-t",
+            "1566722100",
+            "-i",
+            "pbdf.pbdf.email.email=w.geraedts@sarif.nl",
+        ],
+    );
+    assert!(out.status.success());
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}